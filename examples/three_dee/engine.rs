@@ -15,7 +15,7 @@ pub struct ShaderDepthBuffer {
 
 impl Shader for ShaderDepthBuffer {
     fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
-        let pixel_depth: f32 = params.p_f32[2];
+        let pixel_depth: f32 = params.varyings.depth;
 
         if (self.depth_buffer.width * self.depth_buffer.height) != width * height { 
             //println!("ERROR: ShaderDepthBuffer and Buffer have different lengths!!");
@@ -202,7 +202,7 @@ impl ThreeDeeEngine {
         // Enable depth buffer shader [0] and disable alpha clip [1]
         self.screen.buffer.shader_stack[0].active = true;
         self.screen.buffer.shader_stack[1].active = false;
-        self.screen.buffer.shader_stack[0].shader.as_mut().reset();
+        self.screen.begin_frame();
 
         self.pipeline.draw_mesh_to_screen(&mut self.screen, &self.quad, &self.patterntest, cube1_transform); 
         self.pipeline.draw_mesh_to_screen(&mut self.screen, &self.quad, &self.patterntest, cube2_transform); 
@@ -0,0 +1,820 @@
+use std::io::prelude::*;
+use std::fs::File;
+
+use crate::buffer::Buffer;
+use crate::color::*;
+use crate::sprite::*;
+
+/// Pixel format of the layers inside an Aseprite file. Every cel is converted to RGBA when loaded,
+/// indexed files keep their palette in AsepriteFile::palette.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AsepriteColorDepth {
+    Rgba,
+    Grayscale,
+    Indexed,
+}
+
+impl AsepriteColorDepth {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            AsepriteColorDepth::Rgba => 4,
+            AsepriteColorDepth::Grayscale => 2,
+            AsepriteColorDepth::Indexed => 1,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AsepriteLayerKind {
+    Normal,
+    Group,
+    Tilemap,
+}
+
+/// Aseprite blend modes. Only Normal, Multiply, Screen, Addition and Subtract are used when flattening,
+/// anything else is drawn as Normal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AsepriteBlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+    Addition,
+    Subtract,
+    Divide,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsepriteLayer {
+    pub name: String,
+    pub kind: AsepriteLayerKind,
+    pub is_visible: bool,
+    pub child_level: u16,
+    pub blend_mode: AsepriteBlendMode,
+    pub opacity: u8,
+}
+
+/// A layer's image for a single frame, placed at x and y on the canvas.
+#[derive(Clone)]
+pub struct AsepriteCel {
+    pub layer: usize,
+    pub x: i32,
+    pub y: i32,
+    pub opacity: u8,
+    pub z_index: i16,
+    pub image: Buffer,
+}
+
+#[derive(Clone)]
+pub struct AsepriteFrame {
+    /// Seconds
+    pub duration: f32,
+    pub cels: Vec<AsepriteCel>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AsepriteTagDirection {
+    Forward,
+    Reverse,
+    PingPong,
+    PingPongReverse,
+}
+
+/// A named range of frames, inclusive on both ends. 'repeat' is 0 for infinite.
+#[derive(Debug, Clone)]
+pub struct AsepriteTag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    pub direction: AsepriteTagDirection,
+    pub repeat: u16,
+}
+
+/// Slice bounds for a frame and every frame after it, until the next key.
+/// 'center' is the inner rect of a nine-slice, relative to the slice bounds.
+/// 'pivot' is relative to the slice bounds.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AsepriteSliceKey {
+    pub frame: usize,
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+    pub center: Option<(i32, i32, i32, i32)>,
+    pub pivot: Option<(i32, i32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsepriteSlice {
+    pub name: String,
+    pub keys: Vec<AsepriteSliceKey>,
+}
+
+impl AsepriteSlice {
+    /// Gets the key that applies to a frame.
+    pub fn key_at(&self, frame: usize) -> Option<&AsepriteSliceKey> {
+        self.keys.iter().rev().find(|k| k.frame <= frame)
+    }
+}
+
+/// A decoded .ase / .aseprite file.
+#[derive(Clone)]
+pub struct AsepriteFile {
+    pub width: usize,
+    pub height: usize,
+    pub color_depth: AsepriteColorDepth,
+    pub transparent_index: u8,
+    pub layers: Vec<AsepriteLayer>,
+    pub frames: Vec<AsepriteFrame>,
+    pub tags: Vec<AsepriteTag>,
+    pub slices: Vec<AsepriteSlice>,
+    pub palette: Vec<Color>,
+}
+
+impl AsepriteFile {
+
+    pub fn new_from_file(path_to: &str) -> Result<AsepriteFile, String> {
+        let mut file = match File::open(path_to) {
+            Ok(file) => file,
+            Err(reason) => { return Err(format!("ERROR - ASEPRITE: Could not open {} | {}", path_to, reason)); }
+        };
+
+        let mut bytes: Vec<u8> = Vec::new();
+        if let Err(reason) = file.read_to_end(&mut bytes) {
+            return Err(format!("ERROR - ASEPRITE: Could not read {} | {}", path_to, reason));
+        }
+
+        AsepriteFile::new_from_bytes(&bytes)
+    }
+
+    pub fn new_from_bytes(bytes: &[u8]) -> Result<AsepriteFile, String> {
+        let mut reader = AsepriteReader { bytes, position: 0 };
+
+        // Header
+        let _file_size = reader.dword()?;
+        if reader.word()? != 0xA5E0 {
+            return Err("ERROR - ASEPRITE: Not an Aseprite file, header magic number is wrong.".to_string());
+        }
+
+        let frame_count = reader.word()? as usize;
+        let width = reader.word()? as usize;
+        let height = reader.word()? as usize;
+
+        let color_depth = match reader.word()? {
+            32 => AsepriteColorDepth::Rgba,
+            16 => AsepriteColorDepth::Grayscale,
+            8 => AsepriteColorDepth::Indexed,
+            depth => { return Err(format!("ERROR - ASEPRITE: Unknown color depth {}", depth)); }
+        };
+
+        let header_flags = reader.dword()?;
+        let layer_opacity_valid = header_flags & 1 != 0;
+
+        reader.skip(2 + 4 + 4)?; // Speed (deprecated) and two zeroes
+        let transparent_index = reader.byte()?;
+        reader.seek(128)?;
+
+        let mut file = AsepriteFile {
+            width,
+            height,
+            color_depth,
+            transparent_index,
+            layers: Vec::new(),
+            frames: Vec::with_capacity(frame_count),
+            tags: Vec::new(),
+            slices: Vec::new(),
+            palette: Vec::new(),
+        };
+
+        // Cels that just point to another frame, resolved after all of the frames are read
+        let mut linked_cels: Vec<(usize, usize, i32, i32, u8, i16, usize)> = Vec::new();
+
+        for frame_index in 0..frame_count {
+            let frame_start = reader.position;
+            let frame_size = reader.dword()? as usize;
+
+            if reader.word()? != 0xF1FA {
+                return Err(format!("ERROR - ASEPRITE: Frame {} has a bad magic number.", frame_index));
+            }
+
+            let old_chunk_count = reader.word()? as usize;
+            let duration_ms = reader.word()?;
+            reader.skip(2)?;
+            let new_chunk_count = reader.dword()? as usize;
+
+            let chunk_count = if new_chunk_count == 0 { old_chunk_count } else { new_chunk_count };
+
+            let mut frame = AsepriteFrame { duration: duration_ms as f32 / 1000.0, cels: Vec::new() };
+
+            for _ in 0..chunk_count {
+                let chunk_start = reader.position;
+                let chunk_size = reader.dword()? as usize;
+                let chunk_type = reader.word()?;
+
+                let chunk_end = chunk_start + chunk_size;
+                if chunk_size < 6 || chunk_end > bytes.len() {
+                    return Err(format!("ERROR - ASEPRITE: Chunk in frame {} has an invalid size.", frame_index));
+                }
+
+                match chunk_type {
+                    // Old palettes are only used if the file has no new palette chunk
+                    0x0004 | 0x0011 if file.palette.is_empty() => {
+                        file.palette = reader.old_palette(chunk_type == 0x0011)?;
+                    },
+                    0x2004 => {
+                        let layer = reader.layer(layer_opacity_valid)?;
+                        file.layers.push(layer);
+                    },
+                    0x2005 => {
+                        let layer = reader.word()? as usize;
+                        let x = reader.short()? as i32;
+                        let y = reader.short()? as i32;
+                        let opacity = reader.byte()?;
+                        let cel_type = reader.word()?;
+                        let z_index = reader.short()?;
+                        reader.skip(5)?;
+
+                        match cel_type {
+                            0 | 2 => {
+                                let w = reader.word()? as usize;
+                                let h = reader.word()? as usize;
+
+                                let pixel_data = reader.bytes_until(chunk_end)?;
+
+                                let pixels: Vec<u8> = if cel_type == 2 {
+                                    // A cel that claims to inflate past the best deflate ratio is corrupt
+                                    let cel_size = file.cel_size(w, h)?;
+                                    if cel_size / MAX_DEFLATE_RATIO > pixel_data.len() {
+                                        return Err(format!("ERROR - ASEPRITE: Cel in frame {} is {}x{} but only has {} bytes of data.", frame_index, w, h, pixel_data.len()));
+                                    }
+                                    decompress(pixel_data, cel_size)?
+                                } else {
+                                    pixel_data.to_vec()
+                                };
+
+                                let image = file.decode_pixels(&pixels, w, h)?;
+                                frame.cels.push(AsepriteCel { layer, x, y, opacity, z_index, image });
+                            },
+                            1 => {
+                                let linked_frame = reader.word()? as usize;
+                                linked_cels.push((frame_index, layer, x, y, opacity, z_index, linked_frame));
+                            },
+                            // Tilemap cels are not supported
+                            _ => {}
+                        }
+                    },
+                    0x2018 => {
+                        let tag_count = reader.word()? as usize;
+                        reader.skip(8)?;
+
+                        for _ in 0..tag_count {
+                            let from = reader.word()? as usize;
+                            let to = reader.word()? as usize;
+                            let direction = match reader.byte()? {
+                                1 => AsepriteTagDirection::Reverse,
+                                2 => AsepriteTagDirection::PingPong,
+                                3 => AsepriteTagDirection::PingPongReverse,
+                                _ => AsepriteTagDirection::Forward,
+                            };
+                            let repeat = reader.word()?;
+                            reader.skip(6 + 3 + 1)?;
+                            let name = reader.string()?;
+
+                            file.tags.push(AsepriteTag { name, from, to, direction, repeat });
+                        }
+                    },
+                    0x2019 => {
+                        let size = reader.dword()? as usize;
+                        let first = reader.dword()? as usize;
+                        let last = reader.dword()? as usize;
+                        reader.skip(8)?;
+
+                        // Every entry takes at least 6 bytes, so the counts can be checked before anything is read
+                        let entries = last.checked_sub(first).map(|n| n + 1).unwrap_or(0);
+                        let too_big = color_depth == AsepriteColorDepth::Indexed && size > 256;
+                        if entries == 0 || last >= size || too_big || entries > reader.remaining_until(chunk_end) / 6 {
+                            return Err(format!("ERROR - ASEPRITE: Palette in frame {} has an invalid size.", frame_index));
+                        }
+
+                        for index in first..=last {
+                            let flags = reader.word()?;
+                            let color = Color::new(reader.byte()?, reader.byte()?, reader.byte()?, reader.byte()?);
+                            if flags & 1 != 0 { let _name = reader.string()?; }
+
+                            if file.palette.len() <= index {
+                                file.palette.resize(index + 1, Color::new(0, 0, 0, 0));
+                            }
+                            file.palette[index] = color;
+                        }
+                    },
+                    0x2022 => {
+                        let slice = reader.slice(chunk_end)?;
+                        file.slices.push(slice);
+                    },
+                    // Cel extras, color profiles, external files, masks, paths, user data and tilesets are skipped
+                    _ => {}
+                }
+
+                reader.seek(chunk_start + chunk_size)?;
+            }
+
+            file.frames.push(frame);
+            reader.seek(frame_start + frame_size)?;
+        }
+
+        for (frame_index, layer, x, y, opacity, z_index, linked_frame) in linked_cels {
+            let linked_image = file.frames.get(linked_frame)
+                .and_then(|f| f.cels.iter().find(|c| c.layer == layer))
+                .map(|c| c.image.clone());
+
+            if let Some(image) = linked_image {
+                file.frames[frame_index].cels.push(AsepriteCel { layer, x, y, opacity, z_index, image });
+            }
+        }
+
+        Ok(file)
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|l| l.name == name)
+    }
+
+    /// A layer is only drawn if it and all of its parent groups are visible.
+    pub fn is_layer_visible(&self, layer: usize) -> bool {
+        let mut level = match self.layers.get(layer) {
+            Some(l) => { if !l.is_visible { return false; } l.child_level },
+            None => { return false; }
+        };
+
+        // Parents are the closest layer above in the list with a lower child level
+        for parent in self.layers[..layer].iter().rev() {
+            if level == 0 { break; }
+            if parent.child_level < level {
+                if !parent.is_visible { return false; }
+                level = parent.child_level;
+            }
+        }
+
+        true
+    }
+
+    /// Every frame of a single layer as canvas sized images. Hidden layers are still returned.
+    pub fn layer_frames(&self, layer: usize) -> Vec<Buffer> {
+        self.frames.iter().map(|frame| {
+            let mut canvas = Buffer::new(self.width, self.height);
+            for cel in frame.cels.iter().filter(|c| c.layer == layer) {
+                composite_cel(&mut canvas, cel, cel.opacity, AsepriteBlendMode::Normal);
+            }
+            canvas
+        }).collect()
+    }
+
+    /// Every frame with all visible layers blended together, the same as exporting from Aseprite.
+    pub fn flattened_frames(&self) -> Vec<Buffer> {
+        (0..self.frames.len()).map(|i| self.flatten_frame(i)).collect()
+    }
+
+    pub fn flatten_frame(&self, frame: usize) -> Buffer {
+        let mut canvas = Buffer::new(self.width, self.height);
+
+        let frame = match self.frames.get(frame) {
+            Some(frame) => frame,
+            None => { return canvas; }
+        };
+
+        // Lower layers first. The z-index moves a cel up or down the stack for this frame only.
+        let mut cels: Vec<&AsepriteCel> = frame.cels.iter().filter(|c| self.is_layer_visible(c.layer)).collect();
+        cels.sort_by_key(|c| (c.layer as i32 + c.z_index as i32, c.z_index));
+
+        for cel in cels {
+            let layer = &self.layers[cel.layer];
+            if layer.kind != AsepriteLayerKind::Normal { continue; }
+
+            let opacity = ((cel.opacity as u32 * layer.opacity as u32) / 255) as u8;
+            composite_cel(&mut canvas, cel, opacity, layer.blend_mode);
+        }
+
+        canvas
+    }
+
+    /// Lays out every flattened frame left-to-right in a single image.
+    pub fn sprite_sheet(&self) -> SpriteSheet {
+        let frames = self.flattened_frames();
+        let mut image = Buffer::new(self.width * frames.len().max(1), self.height);
+
+        for (i, frame) in frames.iter().enumerate() {
+            image.blit(frame, (i * self.width) as i32, 0);
+        }
+
+        SpriteSheet::new_grid(image, self.width, self.height, 0, 0)
+    }
+
+    /// Turns each tag into an animation, using the frame durations from the file.
+    /// Files without tags get a single looping animation named "default" that plays every frame.
+    pub fn animations(&self) -> Vec<Animation> {
+        if self.tags.is_empty() {
+            let mut animation = Animation::new("default", AnimationMode::Loop);
+            for (i, frame) in self.frames.iter().enumerate() {
+                animation.add_frame(i, frame.duration);
+            }
+            return vec![animation];
+        }
+
+        self.tags.iter().map(|tag| {
+            let mode = match (tag.direction, tag.repeat) {
+                (AsepriteTagDirection::PingPong, _) | (AsepriteTagDirection::PingPongReverse, _) => AnimationMode::PingPong,
+                (_, 1) => AnimationMode::Once,
+                _ => AnimationMode::Loop,
+            };
+
+            let mut animation = Animation::new(&tag.name, mode);
+
+            let last = usize::min(tag.to, self.frames.len().saturating_sub(1));
+            let mut order: Vec<usize> = (tag.from..=last).collect();
+            if tag.direction == AsepriteTagDirection::Reverse || tag.direction == AsepriteTagDirection::PingPongReverse {
+                order.reverse();
+            }
+
+            for i in order {
+                animation.add_frame(i, self.frames[i].duration);
+            }
+
+            animation
+        }).collect()
+    }
+
+    /// An AnimationPlayer with every tag loaded, ready to use with sprite_sheet.
+    pub fn animation_player(&self) -> AnimationPlayer {
+        let mut player = AnimationPlayer::new();
+        for animation in self.animations() {
+            player.add(animation);
+        }
+        player
+    }
+
+    pub fn slice(&self, name: &str) -> Option<&AsepriteSlice> {
+        self.slices.iter().find(|s| s.name == name)
+    }
+
+    // Bytes a w x h cel takes, refusing sizes that can't be real before anything is allocated for them
+    fn cel_size(&self, w: usize, h: usize) -> Result<usize, String> {
+        match w.checked_mul(h).and_then(|count| count.checked_mul(self.color_depth.bytes_per_pixel())) {
+            Some(size) => Ok(size),
+            None => Err(format!("ERROR - ASEPRITE: Cel size {}x{} is too large.", w, h)),
+        }
+    }
+
+    fn decode_pixels(&self, pixels: &[u8], w: usize, h: usize) -> Result<Buffer, String> {
+        if pixels.len() < self.cel_size(w, h)? { return Err("ERROR - ASEPRITE: Cel has too few pixels.".to_string()); }
+
+        let mut image = Buffer::new(w, h);
+        let count = w * h;
+
+        match self.color_depth {
+            AsepriteColorDepth::Rgba => {
+                image.color.copy_from_slice(&pixels[..count * 4]);
+            },
+            AsepriteColorDepth::Grayscale => {
+                for (dst, src) in image.color.chunks_exact_mut(4).zip(pixels.chunks_exact(2)) {
+                    dst.copy_from_slice(&[src[0], src[0], src[0], src[1]]);
+                }
+            },
+            AsepriteColorDepth::Indexed => {
+                for (dst, index) in image.color.chunks_exact_mut(4).zip(pixels.iter()) {
+                    if *index == self.transparent_index { continue; }
+                    let color = self.palette.get(*index as usize).copied().unwrap_or(Color::new(0, 0, 0, 0));
+                    dst.copy_from_slice(&color.into_chunk());
+                }
+            },
+        }
+
+        Ok(image)
+    }
+}
+
+// Zlib's best case is 1032 to 1, for long runs of the same byte
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+// Stops after 'limit' bytes so a corrupt stream can't inflate past the size of its cel
+fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let mut decoder = flate2::read::ZlibDecoder::new(data).take(limit as u64);
+    let mut pixels: Vec<u8> = Vec::new();
+
+    match decoder.read_to_end(&mut pixels) {
+        Ok(_) => Ok(pixels),
+        Err(reason) => Err(format!("ERROR - ASEPRITE: Could not decompress cel | {}", reason)),
+    }
+}
+
+fn composite_cel(canvas: &mut Buffer, cel: &AsepriteCel, opacity: u8, blend_mode: AsepriteBlendMode) {
+    if opacity == 0 { return; }
+
+    for ly in 0..cel.image.height as i32 {
+        for lx in 0..cel.image.width as i32 {
+            let (px, py) = (cel.x + lx, cel.y + ly);
+            if px < 0 || py < 0 || px >= canvas.width as i32 || py >= canvas.height as i32 { continue; }
+
+            let src = cel.image.pget(lx, ly);
+            if src.a == 0 { continue; }
+
+            let dst = canvas.pget(px, py);
+            let blended = match blend_mode {
+                AsepriteBlendMode::Multiply => Color::new(src.r, src.g, src.b, src.a) * dst,
+                AsepriteBlendMode::Screen => (src.inverted() * dst.inverted()).inverted(),
+                AsepriteBlendMode::Addition => dst + Color::new(src.r, src.g, src.b, 0),
+                AsepriteBlendMode::Subtract => dst - Color::new(src.r, src.g, src.b, 0),
+                _ => src,
+            };
+
+            // Blend modes only change the color, the source alpha still decides coverage
+            let blended = if dst.a == 0 { src } else { Color::new(blended.r, blended.g, blended.b, src.a) };
+
+            canvas.pset(px, py, Color::blend_slow(blended, dst, opacity as f32 / 255.0));
+        }
+    }
+}
+
+struct AsepriteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> AsepriteReader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.position + count > self.bytes.len() {
+            return Err("ERROR - ASEPRITE: Unexpected end of file.".to_string());
+        }
+        let slice = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(slice)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), String> {
+        self.take(count).map(|_| ())
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), String> {
+        if position > self.bytes.len() {
+            return Err("ERROR - ASEPRITE: Unexpected end of file.".to_string());
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    // Bytes between the read position and 'end', never past the end of the file
+    fn remaining_until(&self, end: usize) -> usize {
+        end.min(self.bytes.len()).saturating_sub(self.position)
+    }
+
+    fn bytes_until(&mut self, end: usize) -> Result<&'a [u8], String> {
+        let count = end.saturating_sub(self.position);
+        self.take(count)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn short(&mut self) -> Result<i16, String> {
+        Ok(self.word()? as i16)
+    }
+
+    fn dword(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn long(&mut self) -> Result<i32, String> {
+        Ok(self.dword()? as i32)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.word()? as usize;
+        Ok(String::from_utf8_lossy(self.take(length)?).to_string())
+    }
+
+    fn old_palette(&mut self, six_bit: bool) -> Result<Vec<Color>, String> {
+        let mut palette: Vec<Color> = vec![Color::new(0, 0, 0, 255); 256];
+        let packet_count = self.word()?;
+        let mut index: usize = 0;
+
+        for _ in 0..packet_count {
+            index += self.byte()? as usize;
+            let mut count = self.byte()? as usize;
+            if count == 0 { count = 256; }
+
+            for _ in 0..count {
+                let (mut r, mut g, mut b) = (self.byte()?, self.byte()?, self.byte()?);
+                if six_bit { r = r.saturating_mul(4); g = g.saturating_mul(4); b = b.saturating_mul(4); }
+                if index < palette.len() { palette[index] = Color::new(r, g, b, 255); }
+                index += 1;
+            }
+        }
+
+        Ok(palette)
+    }
+
+    fn layer(&mut self, opacity_valid: bool) -> Result<AsepriteLayer, String> {
+        let flags = self.word()?;
+        let kind = match self.word()? {
+            1 => AsepriteLayerKind::Group,
+            2 => AsepriteLayerKind::Tilemap,
+            _ => AsepriteLayerKind::Normal,
+        };
+        let child_level = self.word()?;
+        self.skip(4)?; // Default width and height, ignored by Aseprite
+
+        let blend_mode = match self.word()? {
+            1 => AsepriteBlendMode::Multiply,
+            2 => AsepriteBlendMode::Screen,
+            3 => AsepriteBlendMode::Overlay,
+            4 => AsepriteBlendMode::Darken,
+            5 => AsepriteBlendMode::Lighten,
+            6 => AsepriteBlendMode::ColorDodge,
+            7 => AsepriteBlendMode::ColorBurn,
+            8 => AsepriteBlendMode::HardLight,
+            9 => AsepriteBlendMode::SoftLight,
+            10 => AsepriteBlendMode::Difference,
+            11 => AsepriteBlendMode::Exclusion,
+            12 => AsepriteBlendMode::Hue,
+            13 => AsepriteBlendMode::Saturation,
+            14 => AsepriteBlendMode::Color,
+            15 => AsepriteBlendMode::Luminosity,
+            16 => AsepriteBlendMode::Addition,
+            17 => AsepriteBlendMode::Subtract,
+            18 => AsepriteBlendMode::Divide,
+            _ => AsepriteBlendMode::Normal,
+        };
+
+        let opacity = self.byte()?;
+        self.skip(3)?;
+        let name = self.string()?;
+
+        Ok(AsepriteLayer {
+            name,
+            kind,
+            is_visible: flags & 1 != 0,
+            child_level,
+            blend_mode,
+            opacity: if opacity_valid { opacity } else { 255 },
+        })
+    }
+
+    fn slice(&mut self, chunk_end: usize) -> Result<AsepriteSlice, String> {
+        let key_count = self.dword()? as usize;
+        let flags = self.dword()?;
+        self.skip(4)?;
+        let name = self.string()?;
+
+        // Every key takes at least 20 bytes, so a count the chunk can't hold is corrupt
+        if key_count > self.remaining_until(chunk_end) / 20 {
+            return Err(format!("ERROR - ASEPRITE: Slice '{}' has more keys than its chunk can hold.", name));
+        }
+
+        let mut keys: Vec<AsepriteSliceKey> = Vec::new();
+        for _ in 0..key_count {
+            let frame = self.dword()? as usize;
+            let x = self.long()?;
+            let y = self.long()?;
+            let w = self.dword()? as i32;
+            let h = self.dword()? as i32;
+
+            let center = if flags & 1 != 0 {
+                Some((self.long()?, self.long()?, self.dword()? as i32, self.dword()? as i32))
+            } else { None };
+
+            let pivot = if flags & 2 != 0 {
+                Some((self.long()?, self.long()?))
+            } else { None };
+
+            keys.push(AsepriteSliceKey { frame, x, y, w, h, center, pivot });
+        }
+
+        Ok(AsepriteSlice { name, keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 128 byte header followed by a single frame holding 'chunks'
+    fn file_with_chunks(depth: u16, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&0xA5E0u16.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&depth.to_le_bytes());
+        bytes.resize(128, 0);
+
+        let frame_size = 16 + chunks.iter().map(|chunk| chunk.len()).sum::<usize>();
+        bytes.extend_from_slice(&(frame_size as u32).to_le_bytes());
+        bytes.extend_from_slice(&0xF1FAu16.to_le_bytes());
+        bytes.extend_from_slice(&(chunks.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&100u16.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+
+        for chunk in chunks { bytes.extend_from_slice(chunk); }
+        bytes
+    }
+
+    fn chunk(chunk_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&(data.len() as u32 + 6).to_le_bytes());
+        bytes.extend_from_slice(&chunk_type.to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn cel(cel_type: u16, w: u16, h: u16, pixels: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![0; 16];
+        data[6] = 255;
+        data[7..9].copy_from_slice(&cel_type.to_le_bytes());
+        data.extend_from_slice(&w.to_le_bytes());
+        data.extend_from_slice(&h.to_le_bytes());
+        data.extend_from_slice(pixels);
+        chunk(0x2005, &data)
+    }
+
+    #[test]
+    fn reads_a_raw_rgba_cel() {
+        let pixels: Vec<u8> = (0..16).collect();
+        let file = AsepriteFile::new_from_bytes(&file_with_chunks(32, &[cel(0, 2, 2, &pixels)])).unwrap();
+
+        let image = &file.frames[0].cels[0].image;
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.pget(1, 1), Color::new(12, 13, 14, 15));
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let pixels: Vec<u8> = (0..16).collect();
+        let bytes = file_with_chunks(32, &[cel(0, 2, 2, &pixels)]);
+
+        for length in [0, 64, 130, 150, bytes.len() - 1] {
+            assert!(AsepriteFile::new_from_bytes(&bytes[..length]).is_err(), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn rejects_slices_with_more_keys_than_the_chunk_holds() {
+        let mut data: Vec<u8> = Vec::new();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&0u16.to_le_bytes());
+
+        assert!(AsepriteFile::new_from_bytes(&file_with_chunks(32, &[chunk(0x2022, &data)])).is_err());
+    }
+
+    #[test]
+    fn reads_a_palette() {
+        let mut data: Vec<u8> = Vec::new();
+        for value in [2u32, 0, 1] { data.extend_from_slice(&value.to_le_bytes()); }
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 0, 255]);
+
+        let file = AsepriteFile::new_from_bytes(&file_with_chunks(8, &[chunk(0x2019, &data)])).unwrap();
+        assert_eq!(file.palette, vec![Color::new(255, 0, 0, 255), Color::new(0, 255, 0, 255)]);
+    }
+
+    #[test]
+    fn rejects_palettes_larger_than_their_chunk() {
+        let mut data: Vec<u8> = Vec::new();
+        for value in [u32::MAX, 0, u32::MAX - 1] { data.extend_from_slice(&value.to_le_bytes()); }
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0, 0, 255, 0, 0, 255]);
+
+        assert!(AsepriteFile::new_from_bytes(&file_with_chunks(32, &[chunk(0x2019, &data)])).is_err());
+        assert!(AsepriteFile::new_from_bytes(&file_with_chunks(8, &[chunk(0x2019, &data)])).is_err());
+    }
+
+    #[test]
+    fn rejects_compressed_cels_that_claim_too_much_data() {
+        let data = [0x78, 0x9C, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01];
+        assert!(AsepriteFile::new_from_bytes(&file_with_chunks(32, &[cel(2, 65535, 65535, &data)])).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::buffer::Buffer;
+use crate::sprite::*;
+
+/// Location of a packed image inside an Atlas, in pixels. Pass these straight into pimgrect.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AtlasRect {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+/// Many images packed into a single Buffer, looked up by name.
+#[derive(Clone)]
+pub struct Atlas {
+    pub image: Buffer,
+    pub rects: BTreeMap<String, AtlasRect>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AtlasManifest {
+    image: String,
+    width: usize,
+    height: usize,
+    rects: BTreeMap<String, AtlasRect>,
+}
+
+impl Atlas {
+    pub fn get(&self, name: &str) -> Option<AtlasRect> {
+        self.rects.get(name).copied()
+    }
+
+    /// Draws a packed image by name. Does nothing if the name isn't in the atlas.
+    pub fn draw(&self, buffer: &mut Buffer, name: &str, x: i32, y: i32) {
+        if let Some(rect) = self.rects.get(name) {
+            buffer.pimgrect(&self.image, x, y, rect.x, rect.y, rect.w, rect.h);
+        }
+    }
+
+    /// Builds a SpriteSheet out of the named rects, in the order given.
+    /// Names that aren't in the atlas are skipped.
+    pub fn sprite_sheet(&self, names: &[&str]) -> SpriteSheet {
+        let frames: Vec<SpriteFrame> = names.iter()
+            .filter_map(|name| self.rects.get(*name))
+            .map(|r| SpriteFrame::new(r.x, r.y, r.w, r.h))
+            .collect();
+
+        SpriteSheet::new_from_rects(self.image.clone(), frames)
+    }
+
+    /// Writes the atlas image as a PNG and its rects as a JSON manifest next to it.
+    pub fn save(&self, path_image: &str, path_manifest: &str) -> Result<(), String> {
+        self.image.save_png(path_image)?;
+
+        // Keep the image path relative to the manifest so both files can be moved together
+        let manifest_dir = Path::new(path_manifest).parent().unwrap_or(Path::new(""));
+        let image_relative = Path::new(path_image).strip_prefix(manifest_dir).unwrap_or(Path::new(path_image));
+
+        let manifest = AtlasManifest {
+            image: image_relative.to_string_lossy().to_string(),
+            width: self.image.width,
+            height: self.image.height,
+            rects: self.rects.clone(),
+        };
+
+        let json = match serde_json::to_string_pretty(&manifest) {
+            Ok(json) => json,
+            Err(reason) => { return Err(format!("ERROR - ATLAS: Could not write manifest {} | {}", path_manifest, reason)); }
+        };
+
+        match std::fs::write(path_manifest, json) {
+            Ok(_) => Ok(()),
+            Err(reason) => Err(format!("ERROR - ATLAS: Could not write manifest {} | {}", path_manifest, reason)),
+        }
+    }
+
+    /// Loads a manifest written by save, along with the image it points to.
+    /// The image path in the manifest is relative to the manifest's folder.
+    pub fn load(path_manifest: &str) -> Result<Atlas, String> {
+        let json = match std::fs::read_to_string(path_manifest) {
+            Ok(json) => json,
+            Err(reason) => { return Err(format!("ERROR - ATLAS: Could not read manifest {} | {}", path_manifest, reason)); }
+        };
+
+        let manifest: AtlasManifest = match serde_json::from_str(&json) {
+            Ok(manifest) => manifest,
+            Err(reason) => { return Err(format!("ERROR - ATLAS: Manifest {} is malformed | {}", path_manifest, reason)); }
+        };
+
+        let manifest_dir = Path::new(path_manifest).parent().unwrap_or(Path::new(""));
+        let path_image = manifest_dir.join(&manifest.image);
+
+        let image = Buffer::new_from_image(&path_image.to_string_lossy())?;
+        if image.width != manifest.width || image.height != manifest.height {
+            return Err(format!("ERROR - ATLAS: Image {} does not match the size in manifest {}", manifest.image, path_manifest));
+        }
+
+        Ok(Atlas { image, rects: manifest.rects })
+    }
+}
+
+/// Packs images into an Atlas using the skyline bottom-left algorithm.
+///
+/// Padding leaves empty pixels between images, extrusion repeats the edge pixels of each image outwards.
+/// Both stop neighbouring images from bleeding into each other when drawn scaled or rotated.
+#[derive(Clone)]
+pub struct AtlasBuilder {
+    pub max_width: usize,
+    pub max_height: usize,
+    pub padding: usize,
+    pub extrude: usize,
+    pub power_of_two: bool,
+
+    images: Vec<(String, Buffer)>,
+}
+
+// A horizontal segment of the top edge of everything packed so far
+#[derive(Debug, Copy, Clone)]
+struct SkylineNode {
+    x: usize,
+    y: usize,
+    width: usize,
+}
+
+impl AtlasBuilder {
+    pub fn new(max_width: usize, max_height: usize) -> AtlasBuilder {
+        AtlasBuilder {
+            max_width,
+            max_height,
+            padding: 1,
+            extrude: 0,
+            power_of_two: false,
+            images: Vec::new(),
+        }
+    }
+
+    /// Queues an image to be packed. Adding a name twice replaces the first image.
+    pub fn add(&mut self, name: &str, image: &Buffer) {
+        self.images.retain(|(n, _)| n != name);
+        self.images.push((name.to_string(), image.clone()));
+    }
+
+    pub fn add_from_image(&mut self, name: &str, path_to: &str) -> Result<(), String> {
+        let image = Buffer::new_from_image(path_to)?;
+        self.add(name, &image);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// Packs everything added so far. The atlas image is trimmed to the area actually used.
+    pub fn build(&self) -> Result<Atlas, String> {
+        let border = self.extrude * 2 + self.padding;
+
+        // Tallest first packs the tightest with a skyline
+        let mut order: Vec<usize> = (0..self.images.len()).collect();
+        order.sort_by(|a, b| {
+            let (ia, ib) = (&self.images[*a].1, &self.images[*b].1);
+            ib.height.cmp(&ia.height).then(ib.width.cmp(&ia.width))
+        });
+
+        let mut skyline: Vec<SkylineNode> = vec![SkylineNode { x: 0, y: 0, width: self.max_width }];
+        let mut placements: Vec<(usize, usize, usize)> = Vec::with_capacity(order.len());
+        let (mut used_width, mut used_height) = (0, 0);
+
+        for index in order {
+            let (name, image) = &self.images[index];
+            let cell_width = image.width + border;
+            let cell_height = image.height + border;
+
+            let (x, y) = match self.find_position(&skyline, cell_width, cell_height) {
+                Some(position) => position,
+                None => { return Err(format!("ERROR - ATLAS: {} does not fit in a {} x {} atlas", name, self.max_width, self.max_height)); }
+            };
+
+            AtlasBuilder::add_skyline_level(&mut skyline, x, y + cell_height, cell_width);
+            placements.push((index, x, y));
+
+            used_width = usize::max(used_width, x + cell_width);
+            used_height = usize::max(used_height, y + cell_height);
+        }
+
+        if self.power_of_two {
+            let (used_width_packed, used_height_packed) = (used_width, used_height);
+            used_width = used_width.next_power_of_two();
+            used_height = used_height.next_power_of_two();
+
+            // Rounding up can push a snug fit past the limit
+            if used_width > self.max_width || used_height > self.max_height {
+                return Err(format!("ERROR - ATLAS: Images fit in {} x {} but the power of two size {} x {} is larger than a {} x {} atlas",
+                    used_width_packed, used_height_packed, used_width, used_height, self.max_width, self.max_height));
+            }
+        }
+
+        let mut image = Buffer::new(used_width.max(1), used_height.max(1));
+        let mut rects: BTreeMap<String, AtlasRect> = BTreeMap::new();
+
+        for (index, x, y) in placements {
+            let (name, source) = &self.images[index];
+            let (ix, iy) = (x + self.extrude, y + self.extrude);
+
+            image.blit(source, ix as i32, iy as i32);
+            AtlasBuilder::extrude_edges(&mut image, source, ix, iy, self.extrude);
+
+            rects.insert(name.clone(), AtlasRect { x: ix as i32, y: iy as i32, w: source.width as i32, h: source.height as i32 });
+        }
+
+        Ok(Atlas { image, rects })
+    }
+
+    // Finds the lowest spot a cell can rest on the skyline, preferring the left on ties.
+    fn find_position(&self, skyline: &[SkylineNode], width: usize, height: usize) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+
+        for start in 0..skyline.len() {
+            let x = skyline[start].x;
+            if x + width > self.max_width { break; }
+
+            // The cell rests on the highest node it overlaps
+            let mut y = 0;
+            let mut width_left = width as i64;
+            let mut i = start;
+            while width_left > 0 && i < skyline.len() {
+                y = usize::max(y, skyline[i].y);
+                width_left -= skyline[i].width as i64;
+                i += 1;
+            }
+
+            if y + height > self.max_height { continue; }
+
+            let is_better = match best {
+                Some((_, best_y)) => y < best_y,
+                None => true,
+            };
+            if is_better { best = Some((x, y)); }
+        }
+
+        best
+    }
+
+    fn add_skyline_level(skyline: &mut Vec<SkylineNode>, x: usize, y: usize, width: usize) {
+        let index = skyline.iter().position(|n| n.x == x).unwrap_or(0);
+        skyline.insert(index, SkylineNode { x, y, width });
+
+        // Shrink or remove the nodes that are now covered by the new one
+        let right = x + width;
+        let i = index + 1;
+        while i < skyline.len() {
+            if skyline[i].x >= right { break; }
+
+            let node_right = skyline[i].x + skyline[i].width;
+            if node_right <= right {
+                skyline.remove(i);
+            } else {
+                skyline[i].width = node_right - right;
+                skyline[i].x = right;
+                break;
+            }
+        }
+
+        // Join neighbours at the same height
+        let mut i = 0;
+        while i + 1 < skyline.len() {
+            if skyline[i].y == skyline[i + 1].y {
+                skyline[i].width += skyline[i + 1].width;
+                skyline.remove(i + 1);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn extrude_edges(image: &mut Buffer, source: &Buffer, x: usize, y: usize, extrude: usize) {
+        if extrude == 0 || source.width == 0 || source.height == 0 { return; }
+
+        let (sw, sh) = (source.width as i32, source.height as i32);
+        let (x, y, e) = (x as i32, y as i32, extrude as i32);
+
+        for ly in -e..sh + e {
+            for lx in -e..sw + e {
+                let is_inside = lx >= 0 && ly >= 0 && lx < sw && ly < sh;
+                if is_inside { continue; }
+
+                let color = source.pget(i32::clamp(lx, 0, sw - 1), i32::clamp(ly, 0, sh - 1));
+                image.pset(x + lx, y + ly, color);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_of_two_stays_inside_the_max_size() {
+        let mut builder = AtlasBuilder::new(100, 100);
+        builder.padding = 0;
+        builder.power_of_two = true;
+        builder.add("a", &Buffer::new(70, 40));
+
+        assert!(builder.build().is_err());
+
+        builder.max_width = 128;
+        let atlas = builder.build().unwrap();
+        assert_eq!((atlas.image.width, atlas.image.height), (128, 64));
+    }
+}
@@ -1,34 +1,74 @@
 use rayon::prelude::*;
 use glam::*;
 use crate::shader::*;
+use crate::post_process::PostProcess;
+use crate::stencil::*;
+use crate::dirty_rect::*;
+use crate::simd;
+use crate::pixel_format::PixelFormat;
+use crate::command_buffer::CommandBuffer;
 
 use crate::color::*;
-use crate::math;
 use crate::partitioned_buffer::PartitionedBuffer;
 use crate::font::*;
 use crate::math::*;
 
+// Bytes each thread takes at a time when whole-buffer work is split up. Always a whole number of SIMD registers.
+const PARALLEL_CHUNK: usize = 65536;
 
 #[derive(Clone)]
 pub struct BufferShader {
-    pub shader: Box<dyn Shader>,
+    pub shader: Box<dyn SpanShader>,
     pub active: bool,
     pub order: u8,
 }
 
 impl BufferShader {
-    pub fn new(shader: Box<dyn Shader>, active: bool, order: u8) -> BufferShader {
+    pub fn new(shader: Box<dyn SpanShader>, active: bool, order: u8) -> BufferShader {
         BufferShader { shader, active, order }
     }
+
+    /// Wraps a closure as a shader. See ShaderFn.
+    pub fn new_fn<F>(function: F, active: bool, order: u8) -> BufferShader where F: Fn(&ShaderContext) -> Option<Color> + Send + Sync + 'static {
+        BufferShader { shader: Box::new(ShaderFn::new(function)), active, order }
+    }
 }
 
 
 /// Image in memory with operations to modify it. Pixel modification functions are 
+/// How Buffer::pimgrect_flip mirrors a section of an image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImageFlip {
+    pub horizontal: bool,
+    pub vertical: bool,
+    pub diagonal: bool,
+}
+
 #[derive(Clone)]
 pub struct Buffer {
+    /// Pixels stored row by row in 'format'.
     pub color: Vec<u8>,
+    /// How the pixels in 'color' are stored. Change it with convert_to so the pixels are converted too.
+    pub format: PixelFormat,
+    /// Makes the composite functions blend in linear light instead of mixing sRGB bytes. Slower, but without dark fringes.
+    pub linear_blending: bool,
     pub shader_stack: Vec<BufferShader>,
 
+    /// Handed to the shader stack by every primitive. See ShaderUniforms.
+    pub uniforms: ShaderUniforms,
+
+    /// Id given to the next primitive drawn. Counts up by one per primitive and wraps around.
+    pub primitive_id: u32,
+
+    /// One 8-bit stencil value per pixel. Empty until cleared with clear_stencil or first used by the stencil test.
+    pub stencil: Vec<u8>,
+    /// Stencil test and operations for every primitive. Blits, clears and composites ignore it.
+    pub stencil_state: StencilState,
+    /// When set, primitives only draw where the mask lets them. See BufferMask.
+    pub mask: Option<BufferMask>,
+    /// Where the buffer changed since the last begin_frame, or None when not tracking. See set_dirty_tracking.
+    pub dirty: Option<DirtyRegion>,
+
     pub width: usize,
     pub height: usize,
 
@@ -50,17 +90,33 @@ impl Buffer {
         //println!("Buffer: {} x {} x {}, Memory: {}B", width, height, 4, (width * height * 4));
         Buffer {
             shader_stack: Vec::new(),
+            uniforms: ShaderUniforms::default(),
+            primitive_id: 0,
+            stencil: Vec::new(),
+            stencil_state: StencilState::default(),
+            mask: None,
+            dirty: None,
             offset_x: 0,
             offset_y: 0,
 
             width,
             height,
             color: vec![0; width * height * 4],
+            format: PixelFormat::Rgba8,
+            linear_blending: false,
 
             is_drawing: true,
         }
     }
 
+    /// Makes a new Buffer that stores its pixels in 'format'. See PixelFormat.
+    pub fn new_with_format(width: usize, height: usize, format: PixelFormat) -> Buffer {
+        let mut buffer = Buffer::new(0, 0);
+        buffer.format = format;
+        buffer.resize(width, height);
+        buffer
+    }
+
     pub fn new_from_image(path_to: &str) -> Result<Buffer, String> {
 		match lodepng::decode32_file(path_to) {
 			Ok(image) => {
@@ -72,9 +128,17 @@ impl Buffer {
 
 				Ok(Buffer {
                     shader_stack: Vec::new(),
+                    uniforms: ShaderUniforms::default(),
+                    primitive_id: 0,
+                    stencil: Vec::new(),
+                    stencil_state: StencilState::default(),
+                    mask: None,
+                    dirty: None,
                     width: image.width,
                     height: image.height,
                     color: image.buffer.as_bytes().to_vec(),
+                    format: PixelFormat::Rgba8,
+                    linear_blending: false,
 
                     offset_x: 0,
                     offset_y: 0,
@@ -89,110 +153,249 @@ impl Buffer {
 		}
     }
 
+    /// Writes the buffer to disk as a 32-bit PNG.
+    pub fn save_png(&self, path_to: &str) -> Result<(), String> {
+        let rgba = PixelFormat::convert(&self.color, self.format, PixelFormat::Rgba8);
+        match lodepng::encode32_file(path_to, &rgba, self.width, self.height) {
+            Ok(_) => Ok(()),
+            Err(reason) => Err(format!("ERROR - IMAGE: Could not save {} | {}", path_to, reason)),
+        }
+    }
+
     /// Clears the framebuffer and changes its width and height to new values.
     pub fn resize(&mut self, width: usize, height: usize) {
         self.width = width;
         self.height = height;
-        self.color = vec![0; width * height * 4];
+        self.color = vec![0; width * height * self.format.bytes_per_pixel()];
+        if !self.stencil.is_empty() { self.stencil = vec![0; width * height]; }
+        self.mark_all_dirty();
+    }
+
+    /// Converts the pixels to another format, in place.
+    pub fn convert_to(&mut self, format: PixelFormat) {
+        if format == self.format { return; }
+        self.color = PixelFormat::convert(&self.color, self.format, format);
+        self.format = format;
+    }
+
+    /// Returns a copy of the buffer with its pixels in another format.
+    pub fn converted(&self, format: PixelFormat) -> Buffer {
+        let mut buffer = self.clone();
+        buffer.convert_to(format);
+        buffer
     }
 
     pub fn into_partitioned(&self) -> PartitionedBuffer {
         let mut pr = PartitionedBuffer::new(self.width, self.height, 0, PartitionedBuffer::PARALLEL_THRESHOLD_DEFAULT);
+        pr.convert_to(self.format);
         pr.buffer.blit(self, 0, 0);
         pr
     }
 
-    pub fn run_pixel_in_shaders(&mut self, x: i32, y: i32, color: Color, params: ShaderParams) -> (i32, i32, Color) {
+    /// Runs a pixel through every active shader in order. Returns where and what to draw, or None if a shader
+    /// discarded it. See Shader for the rules.
+    pub fn run_pixel_in_shaders(&mut self, x: i32, y: i32, color: Color, params: ShaderParams) -> Option<(i32, i32, Color)> {
         let mut params = params;
         params.x = x;
         params.y = y;
         params.color = color;
 
-        let mut results: (i32, i32, Color) = (x, y, color);
         for shader_idx in 0..self.shader_stack.len() {
             if self.shader_stack[shader_idx].active {
-                if let Some(new_results) = self.shader_stack[shader_idx].shader.shade(self.color.as_slice(), self.width, self.height, params) {
-                    results = new_results;
-                    params.x = results.0;
-                    params.y = results.1;
-                    params.color = results.2;
-                }
+                let (x, y, color) = self.shader_stack[shader_idx].shader.shade_pixel(self.color.as_slice(), self.width, self.height, params)?;
+                params.x = x;
+                params.y = y;
+                params.color = color;
             }
         }
-        results
+        Some((params.x, params.y, params.color))
+    }
+
+    /// Runs a row of pixels through every active shader, one call per shader. 'colors' is shaded in place and
+    /// pixels dropped by a shader are marked in 'discarded', which has one entry per color.
+    /// 'varyings' is either empty or has one entry per color.
+    pub fn run_span_in_shaders(&mut self, x_start: i32, y: i32, colors: &mut [Color], discarded: &mut [bool], varyings: &[ShaderVaryings], params: ShaderParams) {
+        let mut params = params;
+        params.x = x_start;
+        params.y = y;
+
+        for shader_idx in 0..self.shader_stack.len() {
+            if self.shader_stack[shader_idx].active {
+                let span = ShaderSpan { colors: &mut *colors, discarded: &mut *discarded, varyings };
+                self.shader_stack[shader_idx].shader.shade_span(self.color.as_slice(), self.width, self.height, span, params);
+            }
+        }
+    }
+
+    /// Resets every shader in the stack, clearing state they build up over a frame like depth buffers.
+    /// Call it once before drawing each frame.
+    pub fn begin_frame(&mut self) {
+        for buffer_shader in &mut self.shader_stack {
+            buffer_shader.shader.reset();
+        }
+        if let Some(dirty) = &mut self.dirty { dirty.clear(); }
+    }
+
+    /// Starts or stops recording where the buffer is drawn to. Starting marks the whole buffer, as nothing
+    /// has been presented yet. The record is cleared by begin_frame, read it with dirty_rects before then.
+    pub fn set_dirty_tracking(&mut self, enabled: bool) {
+        if enabled == self.dirty.is_some() { return; }
+        self.dirty = if enabled { Some(DirtyRegion::default()) } else { None };
+        self.mark_all_dirty();
     }
 
+    /// Rectangles that changed since the last begin_frame, merged and never overlapping.
+    /// Without dirty tracking this is always the whole buffer.
+    pub fn dirty_rects(&self) -> Vec<DirtyRect> {
+        match &self.dirty {
+            Some(dirty) => dirty.rects.clone(),
+            None => vec![DirtyRect::new(0, 0, self.width, self.height)],
+        }
+    }
+
+    /// Records a change in screen space, cut to the buffer. Only needed after writing to 'color' directly.
+    pub fn mark_dirty(&mut self, x: i32, y: i32, width: i32, height: i32) {
+        if self.dirty.is_none() { return; }
+
+        let x0 = i32::clamp(x, 0, self.width as i32);
+        let y0 = i32::clamp(y, 0, self.height as i32);
+        let x1 = i32::clamp(x.saturating_add(width), 0, self.width as i32);
+        let y1 = i32::clamp(y.saturating_add(height), 0, self.height as i32);
+        if x1 <= x0 || y1 <= y0 { return; }
+
+        self.record_dirty(x0 as usize, y0 as usize, (x1 - x0) as usize, (y1 - y0) as usize);
+    }
+
+    pub fn mark_all_dirty(&mut self) {
+        self.record_dirty(0, 0, self.width, self.height);
+    }
+
+    // Rect must already be inside the buffer
+    fn record_dirty(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        if let Some(dirty) = &mut self.dirty {
+            dirty.add(DirtyRect::new(x, y, width, height));
+        }
+    }
+
+    /// Starts a new primitive, returning params filled with the buffer's uniforms and the primitive's id.
+    /// Only needed when drawing your own primitives with pspan_with or run_pixel_in_shaders.
+    pub fn begin_primitive(&mut self) -> ShaderParams {
+        let mut params = ShaderParams::new(0, 0, Color::TRANSPARENT);
+        params.uniforms = self.uniforms;
+        params.primitive_id = self.primitive_id;
+        params.format = self.format;
+
+        self.primitive_id = self.primitive_id.wrapping_add(1);
+        params
+    }
+
+    /// Copies 'src' into the buffer with its top left corner at x, y. Images that don't fit entirely are skipped.
+    /// Images in another format are converted as they're copied.
     pub fn blit(&mut self, src: &Buffer, x: i32, y: i32) {
         let is_equal_size: bool = self.width == src.width && self.height == src.height;
-        if is_equal_size {
+        if is_equal_size && self.format == src.format {
             self.color.copy_from_slice(&src.color);
+            self.mark_all_dirty();
             return;
         }
 
-        let stride = 4;
-        
-        // The color array is a 1D row of bytes, so we have to do this in sets of rows
-        // Make sure this actually fits inside the buffer
-        let extent_width: usize = x as usize + src.width;
-        let extent_height: usize = y as usize + src.height;
-    
+        let stride = self.format.bytes_per_pixel();
+
         // If this goes out of bounds at all we should not draw it. Otherwise it WILL panic.
-        let out_of_bounds: bool = extent_width > self.width || extent_height > self.height || x < 0 || y < 0;
-        if out_of_bounds { 
+        let out_of_bounds: bool = x < 0 || y < 0 || x as usize + src.width > self.width || y as usize + src.height > self.height;
+        if out_of_bounds || src.width == 0 {
             return;
         }
-    
-        // Lets get an array of rows so we can blit them directly into the color buffer
-        let mut rows_src: Vec<&[u8]> = Vec::with_capacity(src.height);
-    
-        // Build a list of rows to blit to the screen.
-        src.color.chunks_exact(src.width * stride).enumerate().for_each(|(_, row)| {
-            rows_src.push(row);
-        });
-    
-        // Goes through each row of fbuf and split it twice into the slice that fits our rows_src.
-        self.color.chunks_exact_mut(self.width * stride).enumerate().for_each(|(i, row_dst)| {
-            // We need to cut the row into a section that we can just set equal to our row
-            // Make sure that we are actually in the bounds from our source buffer
-            if i >= y as usize as usize && i < (y as usize + src.height) {
-                // [......|#######]
-                // Split at the stride distance to get the first end
-                let rightsect = row_dst.split_at_mut(x as usize * stride).1;
-
-                // [......|####|...]
-                // Get the second half but left
-                let section = rightsect.split_at_mut((extent_width - x as usize) * stride).0;
-
-                // I HAVE YOU NOW
-                section.copy_from_slice(rows_src[i-y as usize]);
+        self.record_dirty(x as usize, y as usize, src.width, src.height);
+
+        // Rows are copied whole, which the standard library already vectorises
+        let row = src.width * stride;
+        let src_stride = src.format.bytes_per_pixel();
+        for (i, row_src) in src.color.chunks_exact(src.width * src_stride).enumerate() {
+            let to = ((y as usize + i) * self.width + x as usize) * stride;
+            if self.format == src.format {
+                self.color[to..to + row].copy_from_slice(row_src);
+            } else {
+                for (dst, pixel) in self.color[to..to + row].chunks_exact_mut(stride).zip(row_src.chunks_exact(src_stride)) {
+                    self.format.encode(src.format.decode(pixel), dst);
+                }
             }
-        });
+        }
     }
 
     /// Clears the frame memory directly, leaving a black screen.
     pub fn clear(&mut self) {
-        self.color = vec![0; self.width * self.height * 4];
+        self.color = vec![0; self.width * self.height * self.format.bytes_per_pixel()];
+        self.mark_all_dirty();
     }
 
     /// Clears the screen to a color.
     /// # Arguments
     /// * 'color' - Color the screen should be cleared too.
     pub fn clear_color(&mut self, color: Color) {
+        self.mark_all_dirty();
+
+        // Four byte formats can be filled with the color as it's stored
+        let stride = self.format.bytes_per_pixel();
+        let mut stored = vec![0; stride];
+        self.format.encode(color, &mut stored);
+        if stride != 4 {
+            self.color.par_chunks_exact_mut(stride).for_each(|c| c.copy_from_slice(&stored));
+            return;
+        }
+        let color = Color::new(stored[0], stored[1], stored[2], stored[3]);
+
         // Check if the amount of work is worth parallelizing
         if self.color.len() > 262144 {
-            self.color.par_chunks_exact_mut(4).for_each(|c| {
-                c[0] = color.r;
-                c[1] = color.g;
-                c[2] = color.b;
-                c[3] = color.a;
-            });
+            self.color.par_chunks_mut(PARALLEL_CHUNK).for_each(|c| simd::fill(c, color));
         } else {
-            self.color.chunks_exact_mut(4).for_each(|c| {
-                c[0] = color.r;
-                c[1] = color.g;
-                c[2] = color.b;
-                c[3] = color.a;
-            });
+            simd::fill(&mut self.color, color);
+        }
+    }
+
+    /// Sets every stencil value to 'value'.
+    pub fn clear_stencil(&mut self, value: u8) {
+        if self.stencil.len() != self.width * self.height {
+            self.stencil = vec![value; self.width * self.height];
+        } else {
+            self.stencil.fill(value);
+        }
+    }
+
+    /// Sets the stencil test and operations used by primitives drawn after this.
+    pub fn set_stencil_state(&mut self, stencil_state: StencilState) {
+        self.stencil_state = stencil_state;
+    }
+
+    /// Limits drawing to where 'mask' lets it, or draws everywhere again with None.
+    pub fn set_mask(&mut self, mask: Option<BufferMask>) {
+        self.mask = mask;
+    }
+
+    // True when the mask or the stencil can stop a pixel being drawn
+    fn uses_fragment_tests(&self) -> bool {
+        self.mask.is_some() || self.stencil_state.is_enabled()
+    }
+
+    // Runs the mask and stencil test for a pixel inside the buffer, updating the stencil on the way.
+    // Returns true if the color should be written.
+    fn fragment_passes(&mut self, x: i32, y: i32) -> bool {
+        if let Some(mask) = &self.mask {
+            if !mask.allows(x + self.offset_x as i32, y + self.offset_y as i32) { return false; }
+        }
+
+        let state = self.stencil_state;
+        if !state.is_enabled() { return true; }
+        if self.stencil.len() != self.width * self.height { self.clear_stencil(0); }
+
+        let idx = y as usize * self.width + x as usize;
+        let stencil = self.stencil[idx];
+        if state.passes(stencil) {
+            self.stencil[idx] = state.apply(state.pass, stencil);
+            state.write_color
+        } else {
+            self.stencil[idx] = state.apply(state.fail, stencil);
+            false
         }
     }
 
@@ -201,70 +404,84 @@ impl Buffer {
         self.shader_stack.sort_by(|a, b| a.order.cmp(&b.order));
     }
 
+    /// Adds an active closure shader to the stack. See ShaderFn.
+    pub fn add_shader_fn<F>(&mut self, function: F, order: u8) where F: Fn(&ShaderContext) -> Option<Color> + Send + Sync + 'static {
+        self.add_shader(BufferShader::new_fn(function, true, order));
+    }
+
+    /// Draws every recorded command in order. See CommandBuffer.
+    pub fn submit(&mut self, commands: &CommandBuffer) {
+        let first_id = self.primitive_id;
+        for (command, id) in commands.commands.iter().zip(commands.primitive_ids(first_id)) {
+            self.primitive_id = id;
+            command.draw(self, 0, 0);
+        }
+        self.primitive_id = first_id.wrapping_add(commands.primitive_count());
+    }
+
+    /// Runs a full screen effect over everything drawn so far. See PostProcess.
+    /// Effects work on Rgba8, buffers in other formats are converted there and back around it.
+    pub fn post_process(&mut self, effect: &dyn PostProcess) {
+        let format = self.format;
+        self.convert_to(PixelFormat::Rgba8);
+        effect.apply(self);
+        self.convert_to(format);
+        self.mark_all_dirty();
+    }
+
     pub fn clear_shaders(&mut self) {
         self.shader_stack.clear();
     }
 
     pub fn tint_buffer(&mut self, color: Color) {
+        self.mark_all_dirty();
+
+        let color = match self.format {
+            PixelFormat::Rgba8 => color,
+            PixelFormat::Bgra8 => Color::new(color.b, color.g, color.r, color.a),
+            PixelFormat::RgbaF32 => {
+                // Multiplied as floats so light above 1.0 isn't clamped, alpha is kept like Color's Mul
+                let tint = [color.r as f32 / 255.0, color.g as f32 / 255.0, color.b as f32 / 255.0];
+                self.color.par_chunks_exact_mut(16).for_each(|c| {
+                    let v = PixelFormat::RgbaF32.decode_f32(c);
+                    PixelFormat::RgbaF32.encode_f32([v[0] * tint[0], v[1] * tint[1], v[2] * tint[2], v[3]], c);
+                });
+                return;
+            },
+            format => {
+                self.color.par_chunks_exact_mut(format.bytes_per_pixel()).for_each(|c| format.encode(format.decode(c) * color, c));
+                return;
+            },
+        };
+
         // Check if the amount of work is worth parallelizing
         if self.color.len() > 262144 {
-            self.color.par_chunks_exact_mut(4).for_each(|c| {
-                let color: Color = Color { r: c[0], g: c[1], b: c[2], a: c[3] } * color;
-    
-                c[0] = color.r;
-                c[1] = color.g;
-                c[2] = color.b;
-                c[3] = color.a;
-            });
+            self.color.par_chunks_mut(PARALLEL_CHUNK).for_each(|c| simd::tint(c, color));
         } else {
-            self.color.chunks_exact_mut(4).for_each(|c| {
-                let color: Color = Color { r: c[0], g: c[1], b: c[2], a: c[3] } * color;
-    
-                c[0] = color.r;
-                c[1] = color.g;
-                c[2] = color.b;
-                c[3] = color.a;
-            });
+            simd::tint(&mut self.color, color);
         }
-
-        
     }
 
-    /// Draws a pixel to the color buffer, using the Buffers set DrawMode. DrawMode defaults to Opaque.
+    /// Draws a single pixel as its own primitive, running it through the shader stack like any other primitive.
     pub fn pset(&mut self, x: i32, y: i32, color: Color) {
-        if !self.is_drawing { return; }
-
-        let idx: usize = ((y * (self.width as i32) + x) * 4) as usize;
-
-        let out_left: bool = x < 0;
-        let out_right: bool = x > (self.width) as i32 - 1;
-        let out_top: bool = y < 0;
-        let out_bottom: bool = y > (self.height) as i32 - 1;
-        let out_of_range: bool = idx > (self.width * self.height * 4) - 1;
-
-        if out_of_range || out_left || out_right || out_top || out_bottom  { return; }
-
-        self.color[idx + 0] = color.r;
-        self.color[idx + 1] = color.g;
-        self.color[idx + 2] = color.b;
-        self.color[idx + 3] = color.a;
+        let params = self.begin_primitive();
+        self.ppixel_with(x, y, color, params);
     }
 
-    /// Draws a pixel to the color buffer, using the Buffers set DrawMode. DrawMode defaults to Opaque.
+    /// Writes a pixel straight into the color buffer, skipping the shader stack, stencil and mask.
     /// This variant of pset has no array bounds protections and will trigger a panic if a pixel is placed
     /// outside of the buffer length.
     /// This should be used once you are positive a drawing operation will not go out of bounds,
     /// as this is much more performant.
     pub fn pset_panic_oob(&mut self, x: i32, y: i32, color: Color) {
-        let idx: usize = ((y * (self.width as i32) + x) * 4) as usize;
+        let stride = self.format.bytes_per_pixel();
+        let idx: usize = (y * (self.width as i32) + x) as usize * stride;
+        self.record_dirty(x as usize, y as usize, 1, 1);
 
-        self.color[idx + 0] = color.r;
-        self.color[idx + 1] = color.g;
-        self.color[idx + 2] = color.b;
-        self.color[idx + 3] = color.a;
+        self.format.encode(color, &mut self.color[idx..idx + stride]);
     }
 
-    /// Gets a color from the color buffer.
+    /// Gets a color from the color buffer. Transparent outside the buffer.
     pub fn pget(&self, x: i32, y: i32) -> Color {
 
         let idx: usize = ((y * (self.width as i32) + x) * 4) as usize;
@@ -275,14 +492,9 @@ impl Buffer {
         let out_bottom: bool = y > (self.height) as i32 - 1;
         let out_of_range: bool = idx > (self.width * self.height * 4) - 1;
 
-        if out_of_range || out_left || out_right || out_top || out_bottom  { return Color::CLEAR; }
+        if out_of_range || out_left || out_right || out_top || out_bottom  { return Color::TRANSPARENT; }
 
-        return Color::new(
-            self.color[idx + 0],
-            self.color[idx + 1],
-            self.color[idx + 2],
-            self.color[idx + 3]
-        );
+        return self.format.read(&self.color, self.width, x, y);
     }
 
     /// Gets a color from the color buffer.
@@ -290,29 +502,122 @@ impl Buffer {
         let x = x.rem_euclid(self.width as i32);
         let y = y.rem_euclid(self.height as i32);
 
-        let idx: usize = ((y * (self.width as i32) + x) * 4) as usize;
-
-        return Color::new(
-            self.color[idx + 0],
-            self.color[idx + 1],
-            self.color[idx + 2],
-            self.color[idx + 3]
-        );
+        return self.format.read(&self.color, self.width, x, y);
     }
     
+    /// Shades a row of pixels as a single span and draws it. Pixels that land outside the buffer are cut off first.
+    pub fn pspan(&mut self, x_start: i32, y: i32, colors: &mut [Color]) {
+        let params = self.begin_primitive();
+        self.pspan_with(x_start, y, colors, &[], params);
+    }
+
+    /// Same as pspan, as part of a primitive started with begin_primitive.
+    /// 'varyings' is either empty or has one entry per color.
+    pub fn pspan_with(&mut self, x_start: i32, y: i32, colors: &mut [Color], varyings: &[ShaderVaryings], params: ShaderParams) {
+        if !self.is_drawing || y < 0 || y >= self.height as i32 { return; }
+
+        let x0 = i32::clamp(x_start, 0, self.width as i32);
+        let x1 = i32::clamp(x_start + colors.len() as i32, 0, self.width as i32);
+        if x1 <= x0 { return; }
+
+        let range = (x0 - x_start) as usize..(x1 - x_start) as usize;
+        let colors = &mut colors[range.clone()];
+        let varyings = if varyings.is_empty() { varyings } else { &varyings[range] };
+
+        let mut params = params;
+        params.x = x0;
+        params.y = y;
+        params.color = colors[0];
+
+        // Skip the discard mask entirely when there's nothing in the stack to discard with
+        if self.shader_stack.iter().any(|s| s.active) {
+            let mut discarded = vec![false; colors.len()];
+            self.run_span_in_shaders(x0, y, colors, &mut discarded, varyings, params);
+            self.write_span(x0, y, colors, &discarded);
+        } else {
+            self.write_span(x0, y, colors, &[]);
+        }
+    }
+
+    // Every primitive finishes here or in write_pixel once the shader stack has run.
+    // 'discarded' is either empty or has one entry per color. The span must already be inside the buffer.
+    fn write_span(&mut self, x_start: i32, y: i32, colors: &[Color], discarded: &[bool]) {
+        let stride = self.format.bytes_per_pixel();
+        let idx: usize = (y * (self.width as i32) + x_start) as usize * stride;
+        self.record_dirty(x_start as usize, y as usize, colors.len(), 1);
+        if self.uses_fragment_tests() || !discarded.is_empty() {
+            for (i, color) in colors.iter().enumerate() {
+                if discarded.get(i) == Some(&true) { continue; }
+                if self.uses_fragment_tests() && !self.fragment_passes(x_start + i as i32, y) { continue; }
+
+                self.format.encode(*color, &mut self.color[idx + i * stride..idx + (i + 1) * stride]);
+            }
+        } else if self.format == PixelFormat::Rgba8 {
+            for (pixel, color) in self.color[idx..idx + colors.len() * 4].chunks_exact_mut(4).zip(colors.iter()) {
+                pixel.copy_from_slice(&color.into_chunk());
+            }
+        } else {
+            let format = self.format;
+            for (pixel, color) in self.color[idx..idx + colors.len() * stride].chunks_exact_mut(stride).zip(colors.iter()) {
+                format.encode(*color, pixel);
+            }
+        }
+    }
+
+    // Single pixel version of write_span. Pixels outside the buffer are dropped.
+    fn write_pixel(&mut self, x: i32, y: i32, color: Color) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 { return; }
+        self.write_span(x, y, &[color], &[]);
+    }
+
+    /// Shades a single pixel and draws it, as part of a primitive started with begin_primitive.
+    /// Unlike spans, shaders are allowed to move the pixel.
+    pub fn ppixel_with(&mut self, x: i32, y: i32, color: Color, params: ShaderParams) {
+        if !self.is_drawing { return; }
+        if let Some((x, y, color)) = self.run_pixel_in_shaders(x, y, color, params) {
+            self.write_pixel(x, y, color);
+        }
+    }
+
+    // Images leave fully transparent pixels untouched, so they split a row into separate spans
+    fn pspan_skip_clear(&mut self, x_start: i32, y: i32, colors: &mut [Color], varyings: &[ShaderVaryings], params: ShaderParams) {
+        let mut start: usize = 0;
+        while start < colors.len() {
+            if colors[start].a == 0 { start += 1; continue; }
+
+            let mut end = start;
+            while end < colors.len() && colors[end].a != 0 { end += 1; }
+
+            self.pspan_with(x_start + start as i32, y, &mut colors[start..end], &varyings[start..end], params);
+            start = end;
+        }
+    }
+
     /// Draws a line across two points using Brensenham Line algorithm from Wikipedia
     pub fn pline(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
+        let params = self.begin_primitive();
+        self.pline_with(x0, y0, x1, y1, color, params);
+    }
+
+    // Lines that make up the outline of a shape share the shape's primitive
+    fn pline_with(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color, params: ShaderParams) {
         let (mut x0, mut y0) = (x0, y0);
+        let mut params = params;
 
         let dx = i32::abs(x1 - x0);
         let sx = if x0 < x1 {1} else {-1};
         let dy = -i32::abs(y1 - y0);
         let sy = if y0 < y1 {1} else {-1};
         let mut error = dx + dy;
+
+        let steps = i32::max(dx, -dy).max(1) as f32;
+        let mut step: i32 = 0;
         
         loop {
-            let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(x0, y0, color, ShaderParams::new(x0, y0, color));
-            self.pset(x_shade, y_shade, color_shade);
+            params.varyings.uv[0] = step as f32 / steps;
+            step += 1;
+
+            self.ppixel_with(x0, y0, color, params);
             if x0 == x1 && y0 == y1 { break; }
             let e2 = 2 * error;
             if e2 >= dy {
@@ -338,18 +643,25 @@ impl Buffer {
             let y0 = i32::clamp(y, 0, self.height as i32);
             let y1 = i32::clamp(y + h, 0, self.height as i32);
 
+            if x1 <= x0 { return; }
+
+            let params = self.begin_primitive();
+            let mut span: Vec<Color> = vec![color; (x1 - x0) as usize];
+            let mut span_varyings: Vec<ShaderVaryings> = vec![ShaderVaryings::default(); (x1 - x0) as usize];
             for py in y0..y1 {
-                for px in x0..x1 {
-                    let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(px, py, color, ShaderParams::new(px, py, color));
-                    self.pset_panic_oob(x_shade, y_shade, color_shade);
+                span.fill(color);
+                for (i, varyings) in span_varyings.iter_mut().enumerate() {
+                    varyings.uv = [(x0 + i as i32 - x) as f32 / w as f32, (py - y) as f32 / h as f32];
                 }
+                self.pspan_with(x0, py, &mut span, &span_varyings, params);
             }
         } else {
-            self.pline(x, y, x + w, y, color);
-            self.pline(x, y + h, x + w, y + h, color);
+            let params = self.begin_primitive();
+            self.pline_with(x, y, x + w, y, color, params);
+            self.pline_with(x, y + h, x + w, y + h, color, params);
 
-            self.pline(x + w, y, x + w, y + h, color);
-            self.pline(x, y, x, y + h, color);
+            self.pline_with(x + w, y, x + w, y + h, color, params);
+            self.pline_with(x, y, x, y + h, color, params);
         }
     }
 
@@ -359,31 +671,35 @@ impl Buffer {
         if filled {
             let xmin = i32::clamp(i32::min(x0, i32::min(x1, x2)), 0, self.width as i32);
             let xmax = i32::clamp(i32::max(x0, i32::max(x1, x2)), 0, self.width as i32);
-            let ymin = i32::clamp(i32::min(y0, i32::min(y1, y2)), 0, self.width as i32);
-            let ymax = i32::clamp(i32::max(y0, i32::max(y1, y2)), 0, self.width as i32);
+            let ymin = i32::clamp(i32::min(y0, i32::min(y1, y2)), 0, self.height as i32);
+            let ymax = i32::clamp(i32::max(y0, i32::max(y1, y2)), 0, self.height as i32);
 
+            let params = self.begin_primitive();
+            let mut span: Vec<Color> = Vec::with_capacity((xmax - xmin).max(0) as usize);
+            let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
             for iy in ymin..ymax {
-                for ix in xmin..xmax {
-
-                    let d1 = math::sign3i(ix, iy, x0, y0, x1, y1);
-                    let d2 = math::sign3i(ix, iy, x1, y1, x2, y2);
-                    let d3 = math::sign3i(ix, iy, x2, y2, x0, y0);
+                // Triangles are convex, so the pixels inside on each row are always in one run
+                let (start, end) = match triangle_row_extent(iy, xmin, xmax, (x0, y0), (x1, y1), (x2, y2)) {
+                    Some(extent) => extent,
+                    None => { continue; }
+                };
 
-                    let has_neg = (d1 < 0) || (d2 < 0) || (d3 < 0);
-                    let has_pos = (d1 > 0) || (d2 > 0) || (d3 > 0);
+                span.clear();
+                span.resize((end - start) as usize, color);
 
-                    let is_inside: bool = !(has_neg && has_pos);
+                span_varyings.clear();
+                span_varyings.extend((start..end).map(|ix| {
+                    let bary = barycentric((ix as f32, iy as f32), (x0 as f32, y0 as f32), (x1 as f32, y1 as f32), (x2 as f32, y2 as f32));
+                    ShaderVaryings { barycentric: [bary.0, bary.1, bary.2], ..Default::default() }
+                }));
 
-                    if is_inside {
-                        let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(ix, iy, color, ShaderParams::new(ix, iy, color));
-                        self.pset_panic_oob(x_shade, y_shade, color_shade);
-                    }
-                }
+                self.pspan_with(start, iy, &mut span, &span_varyings, params);
             }
         } else {
-            self.pline(x0, y0, x1, y1, color);
-            self.pline(x0, y0, x2, y2, color);
-            self.pline(x1, y1, x2, y2, color);
+            let params = self.begin_primitive();
+            self.pline_with(x0, y0, x1, y1, color, params);
+            self.pline_with(x0, y0, x2, y2, color, params);
+            self.pline_with(x1, y1, x2, y2, color, params);
         }
     }
     
@@ -396,27 +712,44 @@ impl Buffer {
         let maxy = i32::clamp((yc + r)+1, 0, self.height as i32);
 
         if filled {
+            let params = self.begin_primitive();
+            let size = (r * 2 + 1) as f32;
+
+            let mut span: Vec<Color> = Vec::with_capacity((maxx - minx).max(0) as usize);
+            let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
             for py in miny..maxy {
-                for px in minx..maxx {
-                    if ((px - xc) * (px - xc)) + ((py - yc) * (py - yc)) <= r * r {
-                        let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(px, py, color, ShaderParams::new(px, py, color));
-                        self.pset(x_shade, y_shade, color_shade);
-                    }
-                }
+                let is_inside = |px: i32| ((px - xc) * (px - xc)) + ((py - yc) * (py - yc)) <= r * r;
+
+                let start = match (minx..maxx).find(|px| is_inside(*px)) {
+                    Some(start) => start,
+                    None => { continue; }
+                };
+                let end = (start..maxx).rev().find(|px| is_inside(*px)).unwrap_or(start) + 1;
+
+                span.clear();
+                span.resize((end - start) as usize, color);
+
+                span_varyings.clear();
+                span_varyings.extend((start..end).map(|px| {
+                    ShaderVaryings { uv: [(px - xc + r) as f32 / size, (py - yc + r) as f32 / size], ..Default::default() }
+                }));
+
+                self.pspan_with(start, py, &mut span, &span_varyings, params);
             }
         } else {
+            let params = self.begin_primitive();
             let mut x: i32 = 0;
             let mut y: i32 = r; 
             let mut d: i32 = 3 - 2 * r;
             
-            self.pset(xc+x, yc+y, color); 
-            self.pset(xc-x, yc+y, color);
-            self.pset(xc+x, yc-y, color); 
-            self.pset(xc-x, yc-y, color); 
-            self.pset(xc+y, yc+x, color);
-            self.pset(xc-y, yc+x, color);
-            self.pset(xc+y, yc-x, color); 
-            self.pset(xc-y, yc-x, color);
+            self.ppixel_with(xc+x, yc+y, color, params); 
+            self.ppixel_with(xc-x, yc+y, color, params);
+            self.ppixel_with(xc+x, yc-y, color, params); 
+            self.ppixel_with(xc-x, yc-y, color, params); 
+            self.ppixel_with(xc+y, yc+x, color, params);
+            self.ppixel_with(xc-y, yc+x, color, params);
+            self.ppixel_with(xc+y, yc-x, color, params); 
+            self.ppixel_with(xc-y, yc-x, color, params);
     
             while y >= x
             { 
@@ -428,57 +761,118 @@ impl Buffer {
                 } else {
                     d = d + 4 * x + 6;
                 } 
-                self.pset(xc+x, yc+y, color); 
-                self.pset(xc-x, yc+y, color);
-                self.pset(xc+x, yc-y, color); 
-                self.pset(xc-x, yc-y, color); 
-                self.pset(xc+y, yc+x, color);
-                self.pset(xc-y, yc+x, color);
-                self.pset(xc+y, yc-x, color); 
-                self.pset(xc-y, yc-x, color);
+                self.ppixel_with(xc+x, yc+y, color, params); 
+                self.ppixel_with(xc-x, yc+y, color, params);
+                self.ppixel_with(xc+x, yc-y, color, params); 
+                self.ppixel_with(xc-x, yc-y, color, params); 
+                self.ppixel_with(xc+y, yc+x, color, params);
+                self.ppixel_with(xc-y, yc+x, color, params);
+                self.ppixel_with(xc+y, yc-x, color, params); 
+                self.ppixel_with(xc-y, yc-x, color, params);
             }   
         }
     }
 
     /// Draws an image directly to the screen.
     pub fn pimg(&mut self, image: &Buffer, x: i32, y: i32) {
-        for ly in 0..image.height as i32 {
-            for lx in 0..image.width as i32 {
-                let pc = image.pget(lx, ly);
-                let px = x + lx;
-                let py = y + ly;
-                
-                // Pixel out of bounds
-                if pc.a <= 0 || (px < 0 || px > self.width as i32) || (py < 0 || py > self.height as i32) { continue; }
-
-                let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(x + lx, y + ly, pc, ShaderParams::new(x + lx, y + ly, pc));
-                self.pset(x_shade, y_shade, color_shade);
-            }
+        // Only visit pixels that land inside this buffer
+        let x0 = i32::clamp(x, 0, self.width as i32) - x;
+        let x1 = i32::clamp(x + image.width as i32, 0, self.width as i32) - x;
+        let y0 = i32::clamp(y, 0, self.height as i32) - y;
+        let y1 = i32::clamp(y + image.height as i32, 0, self.height as i32) - y;
+        if x1 <= x0 { return; }
+
+        let params = self.begin_primitive();
+        let (iw, ih) = (image.width as f32, image.height as f32);
+
+        let mut span: Vec<Color> = Vec::with_capacity((x1 - x0) as usize);
+        let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
+        for ly in y0..y1 {
+            span.clear();
+            span.extend((x0..x1).map(|lx| image.pget(lx, ly)));
+
+            span_varyings.clear();
+            span_varyings.extend((x0..x1).map(|lx| ShaderVaryings { uv: [lx as f32 / iw, ly as f32 / ih], ..Default::default() }));
+
+            self.pspan_skip_clear(x + x0, y + ly, &mut span, &span_varyings, params);
         }
     }
 
-    /// Draws a section of an image directly to the screen.
+    /// Draws a section of an image directly to the screen. The section wraps around the edges of the image,
+    /// and is never drawn bigger than the image itself. Fully transparent texels are skipped, like pimg.
     pub fn pimgrect(&mut self, image: &Buffer, x: i32, y: i32, rx: i32, ry: i32, rw: i32, rh: i32) {
-        let range_x = i32::clamp(rx + rw, 0, self.width as i32);
-        let range_y = i32::clamp(ry + rh, 0, self.height as i32);
+        if image.width == 0 || image.height == 0 { return; }
+
+        // The rect wraps around the image, so it never needs to be bigger than the image itself
+        let range_x = rx + i32::clamp(rw, 0, image.width as i32);
+        let range_y = ry + i32::clamp(rh, 0, image.height as i32);
 
+        let params = self.begin_primitive();
+        let (iw, ih) = (image.width as i32, image.height as i32);
 
+        let mut span: Vec<Color> = Vec::with_capacity((range_x - rx) as usize);
+        let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
         for ly in ry..range_y {
-            for lx in rx..range_x {
-                let mlx = lx.rem_euclid(image.width as i32);
-                let mly = ly.rem_euclid(image.height as i32);
+            let py = y + (ly - ry);
+            if py < 0 || py >= self.height as i32 { continue; }
 
-                let px: i32 = (x + mlx as i32) - rx as i32;
-                let py: i32 = (y + mly as i32) - ry as i32;
-                let pc = image.pget(mlx as i32, mly as i32);
+            span.clear();
+            span.extend((rx..range_x).map(|lx| image.pget_wrap(lx, ly)));
 
-                let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(px, py, pc, ShaderParams::new(px, py, pc));
-                self.pset(x_shade, y_shade, color_shade);
+            span_varyings.clear();
+            span_varyings.extend((rx..range_x).map(|lx| {
+                ShaderVaryings { uv: [lx.rem_euclid(iw) as f32 / iw as f32, ly.rem_euclid(ih) as f32 / ih as f32], ..Default::default() }
+            }));
+
+            self.pspan_skip_clear(x, py, &mut span, &span_varyings, params);
+        }
+    }
+
+    /// Draws a section of an image directly to the screen, mirrored.
+    /// The diagonal flip swaps the x and y axis of the section first, so combined with the other flips
+    /// it rotates the section in 90 degree steps. This matches how tile editors like Tiled store rotated tiles.
+    /// 'rect' is the section of the image as (x, y, w, h).
+    pub fn pimgrect_flip(&mut self, image: &Buffer, x: i32, y: i32, rect: (i32, i32, i32, i32), flip: ImageFlip) {
+        let (rx, ry, rw, rh) = rect;
+        if image.width == 0 || image.height == 0 || rw <= 0 || rh <= 0 { return; }
+
+        // Size of the section once it's on screen
+        let (dw, dh) = if flip.diagonal { (rh, rw) } else { (rw, rh) };
+
+        // Only visit pixels that land inside this buffer
+        let x0 = i32::clamp(x, 0, self.width as i32) - x;
+        let x1 = i32::clamp(x + dw, 0, self.width as i32) - x;
+        let y0 = i32::clamp(y, 0, self.height as i32) - y;
+        let y1 = i32::clamp(y + dh, 0, self.height as i32) - y;
+
+        if x1 <= x0 { return; }
+
+        let params = self.begin_primitive();
+        let (iw, ih) = (image.width as i32, image.height as i32);
+
+        let mut span: Vec<Color> = Vec::with_capacity((x1 - x0) as usize);
+        let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
+        for dy in y0..y1 {
+            span.clear();
+            span_varyings.clear();
+
+            for dx in x0..x1 {
+                // Undo the flips in reverse order to find the source pixel
+                let u = if flip.horizontal { dw - 1 - dx } else { dx };
+                let v = if flip.vertical { dh - 1 - dy } else { dy };
+                let (sx, sy) = if flip.diagonal { (v, u) } else { (u, v) };
+
+                let (sx, sy) = ((rx + sx).rem_euclid(iw), (ry + sy).rem_euclid(ih));
+                span.push(image.pget(sx, sy));
+                span_varyings.push(ShaderVaryings { uv: [sx as f32 / iw as f32, sy as f32 / ih as f32], ..Default::default() });
             }
+
+            self.pspan_skip_clear(x + x0, y + dy, &mut span, &span_varyings, params);
         }
     }
 
     /// Draws a rotated and scaled image to the screen using matrix multiplication.
+    /// Shaders get the screen pixel being drawn, like every other primitive. Fully transparent texels are skipped, like pimg.
     pub fn pimgmtx(&mut self, image: &Buffer, position_x: f32, position_y: f32, rotation: f32, scale_x: f32, scale_y: f32, offset_x: f32, offset_y: f32) {
 
         // Early out if the image is going to be too small to draw
@@ -551,7 +945,7 @@ impl Buffer {
 
         let cmtx_inv = cmtx.inverse();
 
-        let mut shader_params: ShaderParams = ShaderParams::new(0, 0, Color::CLEAR);
+        let mut shader_params: ShaderParams = self.begin_primitive();
 
 		// We can finally draw!
         for ly in rsy..rey {
@@ -559,16 +953,18 @@ impl Buffer {
                 // We have to use the inverted compound matrix (cmtx_inv) in order to get the correct pixel data from the image.
                 let ip: Vec2 = cmtx_inv.transform_point2(Vec2::new(lx as f32, ly as f32));
 
-                let px = ip.x as i32;
-                let py = ip.y as i32;
-
                 // Ceil the transformed pixel positions to fix the colot pullingg
-                let pc = image.pget(f32::ceil(ip.x) as i32, f32::ceil(ip.y) as i32);
+                let ix = f32::ceil(ip.x) as i32;
+                let iy = f32::ceil(ip.y) as i32;
 
-                let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(px, py, pc, ShaderParams::new(px, py, pc));
-                if color_shade.a <= 0 { continue; }
-                self.pset_panic_oob(x_shade, y_shade, color_shade);
-                
+                // Corners of the bounding box land outside of the image
+                if ix < 0 || iy < 0 || ix >= image.width as i32 || iy >= image.height as i32 { continue; }
+
+                let pc = image.pget(ix, iy);
+                if pc.a == 0 { continue; }
+
+                shader_params.varyings.uv = [ix as f32 / image.width as f32, iy as f32 / image.height as f32];
+                self.ppixel_with(lx, ly, pc, shader_params);
             }
         }
     }
@@ -586,29 +982,32 @@ impl Buffer {
         let ymin = i32::clamp(i32::min(y0, i32::min(y1, y2)), 0, self.height as i32);
         let ymax = i32::clamp(i32::max(y0, i32::max(y1, y2)), 0, self.height as i32);
 
-        for iy in ymin..ymax {
-            for ix in xmin..xmax {
-
-                let d1 = math::sign3i(ix, iy, x0, y0, x1, y1);
-                let d2 = math::sign3i(ix, iy, x1, y1, x2, y2);
-                let d3 = math::sign3i(ix, iy, x2, y2, x0, y0);
-
-                let has_neg = (d1 < 0) || (d2 < 0) || (d3 < 0);
-                let has_pos = (d1 > 0) || (d2 > 0) || (d3 > 0);
-
-                let is_inside: bool = !(has_neg && has_pos);
-
-                if is_inside {
-                    // Get interpolation percent between points
-                    let uv_x = ix;
-                    let uv_y = iy;
-                    let pc = image.pget_wrap(uv_x, uv_y);
-
-                    let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(ix, iy, pc, ShaderParams::new(ix, iy, pc));
+        let params = self.begin_primitive();
+        let (iw, ih) = (image.width as i32, image.height as i32);
 
-                    self.pset_panic_oob(x_shade, y_shade, color_shade);
+        let mut span: Vec<Color> = Vec::with_capacity((xmax - xmin).max(0) as usize);
+        let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
+        for iy in ymin..ymax {
+            let (start, end) = match triangle_row_extent(iy, xmin, xmax, (x0, y0), (x1, y1), (x2, y2)) {
+                Some(extent) => extent,
+                None => { continue; }
+            };
+
+            // The texture is sampled in screen space
+            span.clear();
+            span.extend((start..end).map(|ix| image.pget_wrap(ix, iy)));
+
+            span_varyings.clear();
+            span_varyings.extend((start..end).map(|ix| {
+                let bary = barycentric((ix as f32, iy as f32), (x0 as f32, y0 as f32), (x1 as f32, y1 as f32), (x2 as f32, y2 as f32));
+                ShaderVaryings {
+                    barycentric: [bary.0, bary.1, bary.2],
+                    uv: [ix.rem_euclid(iw) as f32 / iw as f32, iy.rem_euclid(ih) as f32 / ih as f32],
+                    depth: 0.0,
                 }
-            }
+            }));
+
+            self.pspan_with(start, iy, &mut span, &span_varyings, params);
         }
     }
 
@@ -632,41 +1031,41 @@ impl Buffer {
         let uv1: Vec2 = Vec2::new(u1, v1);
         let uv2: Vec2 = Vec2::new(u2, v2); 
 
-        for iy in ymin..ymax {
-            for ix in xmin..xmax {
-
-                let d1 = math::sign3i(ix, iy, x0, y0, x1, y1);
-                let d2 = math::sign3i(ix, iy, x1, y1, x2, y2);
-                let d3 = math::sign3i(ix, iy, x2, y2, x0, y0);
-
-                let has_neg = (d1 < 0) || (d2 < 0) || (d3 < 0);
-                let has_pos = (d1 > 0) || (d2 > 0) || (d3 > 0);
-
-                let is_inside: bool = !(has_neg && has_pos);
+        let params = self.begin_primitive();
 
-                if is_inside {
-                    // Get weights of this point from the triangle verticies using barycentric coordinates.
-                    let bary: (f32, f32, f32) = barycentric(
-                        (ix as f32, iy as f32), 
-                        (x0 as f32, y0 as f32), 
-                        (x1 as f32, y1 as f32), 
-                        (x2 as f32, y2 as f32)
-                    );
-
-                    // Weigh the UV triangle by the barycentric calculations. This will map our screen triangle to our UV triangle.
-                    let uv0_weighted = uv0 * bary.0;
-                    let uv1_weighted = uv1 * bary.1;
-                    let uv2_weighted = uv2 * bary.2;
-
-                    // Sum the weighted uv coords together to get the texel of the image inside the UV triangle.
-                    let texel: Vec2 = (uv0_weighted + uv1_weighted + uv2_weighted) * Vec2::new(image.width as f32, image.height as f32);
-
-                    let pc = image.pget_wrap(texel.x as i32, texel.y as i32);
-
-                    let (x_shade, y_shade, color_shade) = self.run_pixel_in_shaders(ix, iy, pc, ShaderParams::new(ix, iy, pc));
-                    self.pset_panic_oob(x_shade, y_shade, color_shade);
-                }
+        let mut span: Vec<Color> = Vec::with_capacity((xmax - xmin).max(0) as usize);
+        let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
+        for iy in ymin..ymax {
+            let (start, end) = match triangle_row_extent(iy, xmin, xmax, (x0, y0), (x1, y1), (x2, y2)) {
+                Some(extent) => extent,
+                None => { continue; }
+            };
+
+            span.clear();
+            span_varyings.clear();
+            for ix in start..end {
+                // Get weights of this point from the triangle verticies using barycentric coordinates.
+                let bary: (f32, f32, f32) = barycentric(
+                    (ix as f32, iy as f32), 
+                    (x0 as f32, y0 as f32), 
+                    (x1 as f32, y1 as f32), 
+                    (x2 as f32, y2 as f32)
+                );
+
+                // Weigh the UV triangle by the barycentric calculations. This will map our screen triangle to our UV triangle.
+                let uv0_weighted = uv0 * bary.0;
+                let uv1_weighted = uv1 * bary.1;
+                let uv2_weighted = uv2 * bary.2;
+
+                // Sum the weighted uv coords together to get the texel of the image inside the UV triangle.
+                let uv: Vec2 = uv0_weighted + uv1_weighted + uv2_weighted;
+                let texel: Vec2 = uv * Vec2::new(image.width as f32, image.height as f32);
+
+                span.push(image.pget_wrap(texel.x as i32, texel.y as i32));
+                span_varyings.push(ShaderVaryings { barycentric: [bary.0, bary.1, bary.2], uv: [uv.x, uv.y], depth: 0.0 });
             }
+
+            self.pspan_with(start, iy, &mut span, &span_varyings, params);
         }
     }
 
@@ -694,60 +1093,52 @@ impl Buffer {
         let uv1: Vec2 = Vec2::new(u1, v1) * vz1;
         let uv2: Vec2 = Vec2::new(u2, v2) * vz2;
 
-        let mut shader_params: ShaderParams = ShaderParams::new(0, 0, Color::CLEAR);
-
-        // Draw triangle
-        // Check 8x8 box corners to see if the
-        for iy in (ymin..ymax) {
-            for ix in (xmin..xmax) {
-                if point_in_triangle(ix, iy, x0, y0, x1, y1, x2, y2) {
-                    // Get weights of this point from the triangle verticies using barycentric coordinates.
-                    // Note: Inlining constants does not improve performance, the compiler might already be doing it
-                    let bary: (f32, f32, f32) = barycentric(
-                        (ix as f32, iy as f32), 
-                        (x0 as f32, y0 as f32), 
-                        (x1 as f32, y1 as f32), 
-                        (x2 as f32, y2 as f32)
-                    ); 
-
-                    // Weigh the UV triangle by the barycentric calculations. This will map our screen triangle to our UV triangle.
-                    let uv0_weighted = uv0 * bary.0;
-                    let uv1_weighted = uv1 * bary.1;
-                    let uv2_weighted = uv2 * bary.2;
-
-                    let vz0_weighted = vz0 * bary.0;
-                    let vz1_weighted = vz1 * bary.1;
-                    let vz2_weighted = vz2 * bary.2;
-
-                    let vz_weighted = vz0_weighted + vz1_weighted + vz2_weighted;
-
-                    // Sum the weighted uv coords together to get the texel of the image inside the UV triangle.
-                    let texel: Vec2 = ((uv0_weighted + uv1_weighted + uv2_weighted) / vz_weighted) * Vec2::new(image.width as f32, image.height as f32);
-                    let mut fc: Color = image.pget(texel.x as i32, texel.y as i32);
-
-
-                    // Required for use in depth buffers
-                    let real_depth: f32 = (w0 * bary.0) + (w1 * bary.1) + (w2 * bary.2);
-
-                    shader_params.x = ix;
-                    shader_params.y = iy;
-                    shader_params.p_f32[0] = texel.x;
-                    shader_params.p_f32[1] = texel.y;
-                    shader_params.p_f32[2] = real_depth;
-                    shader_params.color = fc;
-
-                    for shader_idx in 0..self.shader_stack.len() {
-                        if self.shader_stack[shader_idx].active {
-                            if let Some(result) = self.shader_stack[shader_idx].shader.shade(self.color.as_slice(), self.width, self.height, shader_params) {
-                                fc = result.2;
-                                self.pset_panic_oob(ix, iy, fc);
-                            }
-                        }
-                    }
-                }
+        let params = self.begin_primitive();
+
+        let mut span: Vec<Color> = Vec::with_capacity((xmax - xmin).max(0) as usize);
+        let mut span_varyings: Vec<ShaderVaryings> = Vec::with_capacity(span.capacity());
+        for iy in ymin..ymax {
+            let (start, end) = match triangle_row_extent(iy, xmin, xmax, (x0, y0), (x1, y1), (x2, y2)) {
+                Some(extent) => extent,
+                None => { continue; }
+            };
+
+            span.clear();
+            span_varyings.clear();
+            for ix in start..end {
+                // Get weights of this point from the triangle verticies using barycentric coordinates.
+                // Note: Inlining constants does not improve performance, the compiler might already be doing it
+                let bary: (f32, f32, f32) = barycentric(
+                    (ix as f32, iy as f32), 
+                    (x0 as f32, y0 as f32), 
+                    (x1 as f32, y1 as f32), 
+                    (x2 as f32, y2 as f32)
+                ); 
+
+                // Weigh the UV triangle by the barycentric calculations. This will map our screen triangle to our UV triangle.
+                let uv0_weighted = uv0 * bary.0;
+                let uv1_weighted = uv1 * bary.1;
+                let uv2_weighted = uv2 * bary.2;
+
+                let vz0_weighted = vz0 * bary.0;
+                let vz1_weighted = vz1 * bary.1;
+                let vz2_weighted = vz2 * bary.2;
+
+                let vz_weighted = vz0_weighted + vz1_weighted + vz2_weighted;
+
+                // Sum the weighted uv coords together to get the texel of the image inside the UV triangle.
+                let uv: Vec2 = (uv0_weighted + uv1_weighted + uv2_weighted) / vz_weighted;
+                let texel: Vec2 = uv * Vec2::new(image.width as f32, image.height as f32);
+
+                // Required for use in depth buffers
+                let real_depth: f32 = (w0 * bary.0) + (w1 * bary.1) + (w2 * bary.2);
+
+                span.push(image.pget(texel.x as i32, texel.y as i32));
+                span_varyings.push(ShaderVaryings { barycentric: [bary.0, bary.1, bary.2], uv: [uv.x, uv.y], depth: real_depth });
             }
+
+            self.pspan_with(start, iy, &mut span, &span_varyings, params);
         }
-        
     }
 
     /// Draws text directly to the screen using a provided font.
@@ -788,6 +1179,7 @@ impl Buffer {
         let stride_c2 = self.cline(mx, my, x1, y1) as f32;
 
         let stride: f32 = (1.0 / (stride_c1 + stride_c2)) * 0.5;
+        let mut params = self.begin_primitive();
 
         let x0 = x0 as f32;
         let y0 = x0 as f32;
@@ -805,57 +1197,87 @@ impl Buffer {
             let px1 = f32::clamp(lerpf(px0, x1, step), 0.0, self.width as f32);
             let py1 = f32::clamp(lerpf(py0, y1, step), 0.0, self.height as f32);
 
-            self.pset(px1 as i32, py1 as i32, color);
+            params.varyings.uv[0] = step;
+            self.ppixel_with(px1 as i32, py1 as i32, color, params);
             step += stride;
         }
     }
 
     pub fn pcomposite_opaque(&mut self, buffer: &Buffer) {
-        if self.color.len() != buffer.color.len() { return; }
-
-        self.color.par_chunks_exact_mut(4).zip(buffer.color.par_chunks_exact(4)).for_each(|(c1, c2)| {
-            let dst = Color::new(c2[0], c2[1], c2[2], c2[3]);
-
-            if c2[3] >= 255 { 
-                c1[0] = dst.r;
-                c1[1] = dst.g;
-                c1[2] = dst.b;
-                c1[3] = 255;
-            }
+        if self.width * self.height != buffer.width * buffer.height { return; }
+        self.mark_all_dirty();
 
-            
-        });
+        if self.format == PixelFormat::RgbaF32 {
+            self.composite_hdr_with(buffer, |_, src| if src[3] >= 1.0 { Some(src) } else { None });
+        } else {
+            self.composite_with(buffer, |_, src| if src.a == 255 { Some(src) } else { None });
+        }
     }
 
     pub fn pcomposite_alpha(&mut self, buffer: &Buffer, opacity: u8) {
-        if self.color.len() != buffer.color.len() { return; }
-
-        self.color.par_chunks_exact_mut(4).zip(buffer.color.par_chunks_exact(4)).for_each(|(c1, c2)| {
-            let src = Color::new(c2[0], c2[1], c2[2], c2[3]);
-            let dst = Color::new(c1[0], c1[1], c1[2], c1[3]);
-
-            let fc = Color::blend_fast(dst, src, 255 - opacity);
-
-            c1[0] = fc.r;
-            c1[1] = fc.g;
-            c1[2] = fc.b;
-            c1[3] = fc.a;
-        });
+        if self.width * self.height != buffer.width * buffer.height { return; }
+        self.mark_all_dirty();
+
+        if self.format == PixelFormat::RgbaF32 {
+            let opacity = opacity as f32 / 255.0;
+            self.composite_hdr_with(buffer, |dst, src| {
+                let alpha = (dst[3].min(1.0) - opacity).max(0.0);
+                Some([dst[0] * alpha + src[0] * (1.0 - alpha), dst[1] * alpha + src[1] * (1.0 - alpha), dst[2] * alpha + src[2] * (1.0 - alpha), 1.0])
+            });
+        } else if self.linear_blending {
+            self.composite_with(buffer, |dst, src| {
+                let alpha = dst.a.saturating_sub(opacity) as f32 / 255.0;
+                Some(Color { a: 255, ..Color::lerp_linear(src, dst, alpha) })
+            });
+        } else if self.format == PixelFormat::Rgba8 && buffer.format == PixelFormat::Rgba8 {
+            self.color.par_chunks_mut(PARALLEL_CHUNK).zip(buffer.color.par_chunks(PARALLEL_CHUNK)).for_each(|(c1, c2)| {
+                simd::blend_alpha(c1, c2, opacity);
+            });
+        } else {
+            // Alpha below the opacity stops at 0, the same as simd::blend_alpha
+            self.composite_with(buffer, |dst, src| Some(Color::blend_fast(Color { a: dst.a.max(opacity), ..dst }, src, 255 - opacity)));
+        }
     }
 
     pub fn pcomposite_multiply(&mut self, buffer: &Buffer) {
-        if self.color.len() != buffer.color.len() { return; }
-
-        self.color.par_chunks_exact_mut(4).zip(buffer.color.par_chunks_exact(4)).for_each(|(c1, c2)| {
-            let src = Color::new(c2[0], c2[1], c2[2], c2[3]);
-            let dst = Color::new(c1[0], c1[1], c1[2], c1[3]);
+        if self.width * self.height != buffer.width * buffer.height { return; }
+        self.mark_all_dirty();
+
+        if self.format == PixelFormat::RgbaF32 {
+            self.composite_hdr_with(buffer, |dst, src| {
+                let alpha = dst[3].clamp(0.0, 1.0);
+                let mixed = |i: usize| dst[i] * alpha + src[i] * (1.0 - alpha);
+                Some([mixed(0) * src[0], mixed(1) * src[1], mixed(2) * src[2], 1.0])
+            });
+        } else if self.linear_blending {
+            self.composite_with(buffer, |dst, src| {
+                let mixed = Color::lerp_linear(src, dst, dst.a as f32 / 255.0).to_linear();
+                let src = src.to_linear();
+                Some(Color::from_linear([mixed[0] * src[0], mixed[1] * src[1], mixed[2] * src[2], 1.0]))
+            });
+        } else {
+            self.composite_with(buffer, |dst, src| Some(Color::blend_fast(dst, src, 255) * src));
+        }
+    }
 
-            let fc = Color::blend_fast(dst, src, 255) * src;
+    // Runs 'blend' over every pixel and the pixel in the same place in 'buffer', in whatever formats they're stored in.
+    // None leaves the pixel alone.
+    fn composite_with<F>(&mut self, buffer: &Buffer, blend: F) where F: Fn(Color, Color) -> Option<Color> + Send + Sync {
+        let (format, src_format) = (self.format, buffer.format);
+        self.color.par_chunks_exact_mut(format.bytes_per_pixel()).zip(buffer.color.par_chunks_exact(src_format.bytes_per_pixel())).for_each(|(c1, c2)| {
+            if let Some(color) = blend(format.decode(c1), src_format.decode(c2)) {
+                format.encode(color, c1);
+            }
+        });
+    }
 
-            c1[0] = fc.r;
-            c1[1] = fc.g;
-            c1[2] = fc.b;
-            c1[3] = fc.a;
+    // Same as composite_with, but reads and writes floats so HDR values above 1.0 aren't clamped.
+    fn composite_hdr_with<F>(&mut self, buffer: &Buffer, blend: F) where F: Fn([f32; 4], [f32; 4]) -> Option<[f32; 4]> + Send + Sync {
+        let (format, src_format) = (self.format, buffer.format);
+        self.color.par_chunks_exact_mut(format.bytes_per_pixel()).zip(buffer.color.par_chunks_exact(src_format.bytes_per_pixel())).for_each(|(c1, c2)| {
+            if let Some(value) = blend(format.decode_f32(c1), src_format.decode_f32(c2)) {
+                format.encode_f32(value, c1);
+            }
         });
     }
 
@@ -921,4 +1343,84 @@ impl Default for Buffer {
 
         missing
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 4x4 image with a fully transparent left column and a different color in every other texel
+    fn test_image() -> Buffer {
+        let mut image = Buffer::new(4, 4);
+        for y in 0..4 {
+            for x in 0..4 {
+                let alpha = if x == 0 { 0 } else { 255 };
+                image.pset_panic_oob(x, y, Color::new(x as u8 * 60, y as u8 * 60, 200, alpha));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn pset_runs_the_shader_stack() {
+        let mut screen = Buffer::new(4, 4);
+        screen.add_shader_fn(|ctx| if ctx.x() == 3 { None } else { Some(Color::GREEN) }, 0);
+
+        screen.pset(1, 1, Color::RED);
+        screen.pset(3, 1, Color::RED);
+        screen.pset(-1, 9, Color::RED);
+        screen.pset_panic_oob(2, 2, Color::RED);
+
+        assert_eq!(screen.pget(1, 1), Color::GREEN);
+        assert_eq!(screen.pget(3, 1), Color::TRANSPARENT);
+        assert_eq!(screen.pget(2, 2), Color::RED);
+    }
+
+    #[test]
+    fn pimgrect_skips_transparent_texels() {
+        let mut screen = Buffer::new(8, 8);
+        screen.clear_color(Color::RED);
+        screen.pimgrect(&test_image(), 2, 2, 0, 0, 4, 4);
+
+        assert_eq!(screen.pget(2, 3), Color::RED);
+        assert_eq!(screen.pget(3, 3), Color::new(60, 60, 200, 255));
+    }
+
+    #[test]
+    fn pimgrect_wraps_and_never_draws_past_the_image_size() {
+        let mut screen = Buffer::new(16, 8);
+        screen.clear_color(Color::RED);
+        screen.pimgrect(&test_image(), 1, 1, 3, 0, 12, 2);
+
+        // Starts at texel 3, wraps to texel 1 two pixels later, and stops after 4 pixels
+        assert_eq!(screen.pget(1, 1), Color::new(180, 0, 200, 255));
+        assert_eq!(screen.pget(2, 1), Color::RED);
+        assert_eq!(screen.pget(3, 1), Color::new(60, 0, 200, 255));
+        assert_eq!(screen.pget(4, 1), Color::new(120, 0, 200, 255));
+        assert_eq!(screen.pget(5, 1), Color::RED);
+        assert_eq!(screen.pget(4, 3), Color::RED);
+    }
+
+    #[test]
+    fn pimgmtx_shades_in_screen_space() {
+        let mut screen = Buffer::new(32, 32);
+        screen.clear_color(Color::BLACK);
+        screen.add_shader_fn(|ctx| Some(if ctx.x() >= 10 && ctx.y() >= 20 { Color::GREEN } else { Color::RED }), 0);
+        screen.pimgmtx(&test_image(), 10.0, 20.0, 0.0, 1.0, 1.0, 0.0, 0.0);
+
+        let drawn: Vec<Color> = screen.color.chunks_exact(4).map(|c| Color::new(c[0], c[1], c[2], c[3])).filter(|c| *c != Color::BLACK).collect();
+        assert!(!drawn.is_empty());
+        assert!(drawn.iter().all(|c| *c == Color::GREEN));
+    }
+
+    #[test]
+    fn pimgmtx_skips_transparent_texels() {
+        let mut screen = Buffer::new(16, 16);
+        screen.clear_color(Color::RED);
+        screen.pimgmtx(&test_image(), 4.0, 4.0, 0.0, 1.0, 1.0, 0.0, 0.0);
+
+        let opaque = test_image().color.chunks_exact(4).filter(|c| c[3] == 255).count();
+        let changed = screen.color.chunks_exact(4).filter(|c| *c != Color::RED.into_chunk()).count();
+        assert!(changed > 0 && changed <= opaque);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::buffer::*;
+use crate::color::Color;
+use crate::command_buffer::CommandBuffer;
+use crate::font::Font;
+use crate::partitioned_buffer::PartitionedBuffer;
+use crate::post_process::PostProcess;
+use crate::dirty_rect::DirtyRect;
+use crate::shader::{ShaderParams, ShaderUniforms, ShaderVaryings};
+use crate::sprite_effect::SpriteEffect;
+use crate::stencil::{BufferMask, StencilState};
+
+/// Everything that can be drawn to, so game code can be written once and handed either a Buffer or a
+/// PartitionedBuffer. Calls behave the same as the ones of the same name on Buffer.
+// Takes the same arguments as Buffer's drawing functions, however many those are
+#[allow(clippy::too_many_arguments)]
+pub trait Canvas {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+
+    fn begin_frame(&mut self);
+    fn clear(&mut self);
+    fn clear_color(&mut self, color: Color);
+    fn add_shader(&mut self, buffer_shader: BufferShader);
+    fn clear_shaders(&mut self);
+    fn set_uniforms(&mut self, uniforms: ShaderUniforms);
+    fn set_stencil_state(&mut self, stencil_state: StencilState);
+    fn set_mask(&mut self, mask: Option<BufferMask>);
+    fn clear_stencil(&mut self, value: u8);
+    fn set_dirty_tracking(&mut self, enabled: bool);
+    fn dirty_rects(&self) -> Vec<DirtyRect>;
+
+    fn tint_buffer(&mut self, color: Color);
+    fn blit(&mut self, src: &Buffer, x: i32, y: i32);
+    fn post_process(&mut self, effect: &dyn PostProcess);
+    fn submit(&mut self, commands: &CommandBuffer);
+
+    fn begin_primitive(&mut self) -> ShaderParams;
+    fn pspan(&mut self, x_start: i32, y: i32, colors: &mut [Color]);
+    fn pspan_with(&mut self, x_start: i32, y: i32, colors: &mut [Color], varyings: &[ShaderVaryings], params: ShaderParams);
+    fn ppixel_with(&mut self, x: i32, y: i32, color: Color, params: ShaderParams);
+
+    fn pset(&mut self, x: i32, y: i32, color: Color);
+    fn pget(&self, x: i32, y: i32) -> Color;
+    fn pget_wrap(&self, x: i32, y: i32) -> Color;
+    fn pline(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color);
+    fn prectangle(&mut self, filled: bool, x: i32, y: i32, w: i32, h: i32, color: Color);
+    fn ptriangle(&mut self, filled: bool, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, color: Color);
+    fn pcircle(&mut self, filled: bool, xc: i32, yc: i32, r: i32, color: Color);
+    fn pimg(&mut self, image: &Buffer, x: i32, y: i32);
+    fn pimg_effects(&mut self, image: &Buffer, x: i32, y: i32, effects: &[SpriteEffect]);
+    fn pimgrect(&mut self, image: &Buffer, x: i32, y: i32, rx: i32, ry: i32, rw: i32, rh: i32);
+    fn pimgrect_flip(&mut self, image: &Buffer, x: i32, y: i32, rect: (i32, i32, i32, i32), flip: ImageFlip);
+    fn pimgmtx(&mut self, image: &Buffer, position_x: f32, position_y: f32, rotation: f32, scale_x: f32, scale_y: f32, offset_x: f32, offset_y: f32);
+    fn ptritex(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, image: &Buffer);
+    fn ptritex_uv(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, u0: f32, v0: f32, u1: f32, v1: f32, u2: f32, v2: f32, image: &Buffer);
+    fn ptritex_uvw(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, u0: f32, v0: f32, w0: f32, u1: f32, v1: f32, w1: f32, u2: f32, v2: f32, w2: f32, image: &Buffer);
+    fn pprint(&mut self, font: &Font, text: String, x: i32, y: i32, newline_space: i32, wrap_width: Option<u32>);
+    fn pbeizer(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, mx: i32, my: i32, color: Color);
+    fn pcomposite_opaque(&mut self, buffer: &Buffer);
+    fn pcomposite_alpha(&mut self, buffer: &Buffer, opacity: u8);
+    fn pcomposite_multiply(&mut self, buffer: &Buffer);
+}
+
+impl Canvas for Buffer {
+    fn width(&self) -> usize { self.width }
+    fn height(&self) -> usize { self.height }
+
+    fn begin_frame(&mut self) { Buffer::begin_frame(self) }
+    fn clear(&mut self) { Buffer::clear(self) }
+    fn clear_color(&mut self, color: Color) { Buffer::clear_color(self, color) }
+    fn add_shader(&mut self, buffer_shader: BufferShader) { Buffer::add_shader(self, buffer_shader) }
+    fn clear_shaders(&mut self) { Buffer::clear_shaders(self) }
+    fn set_uniforms(&mut self, uniforms: ShaderUniforms) { self.uniforms = uniforms; }
+    fn set_stencil_state(&mut self, stencil_state: StencilState) { Buffer::set_stencil_state(self, stencil_state) }
+    fn set_mask(&mut self, mask: Option<BufferMask>) { Buffer::set_mask(self, mask) }
+    fn clear_stencil(&mut self, value: u8) { Buffer::clear_stencil(self, value) }
+    fn set_dirty_tracking(&mut self, enabled: bool) { Buffer::set_dirty_tracking(self, enabled) }
+    fn dirty_rects(&self) -> Vec<DirtyRect> { Buffer::dirty_rects(self) }
+
+    fn tint_buffer(&mut self, color: Color) { Buffer::tint_buffer(self, color) }
+    fn blit(&mut self, src: &Buffer, x: i32, y: i32) { Buffer::blit(self, src, x, y) }
+    fn post_process(&mut self, effect: &dyn PostProcess) { Buffer::post_process(self, effect) }
+    fn submit(&mut self, commands: &CommandBuffer) { Buffer::submit(self, commands) }
+
+    fn begin_primitive(&mut self) -> ShaderParams { Buffer::begin_primitive(self) }
+    fn pspan(&mut self, x_start: i32, y: i32, colors: &mut [Color]) { Buffer::pspan(self, x_start, y, colors) }
+    fn pspan_with(&mut self, x_start: i32, y: i32, colors: &mut [Color], varyings: &[ShaderVaryings], params: ShaderParams) {
+        Buffer::pspan_with(self, x_start, y, colors, varyings, params)
+    }
+    fn ppixel_with(&mut self, x: i32, y: i32, color: Color, params: ShaderParams) { Buffer::ppixel_with(self, x, y, color, params) }
+
+    fn pset(&mut self, x: i32, y: i32, color: Color) { Buffer::pset(self, x, y, color) }
+    fn pget(&self, x: i32, y: i32) -> Color { Buffer::pget(self, x, y) }
+    fn pget_wrap(&self, x: i32, y: i32) -> Color { Buffer::pget_wrap(self, x, y) }
+    fn pline(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) { Buffer::pline(self, x0, y0, x1, y1, color) }
+    fn prectangle(&mut self, filled: bool, x: i32, y: i32, w: i32, h: i32, color: Color) { Buffer::prectangle(self, filled, x, y, w, h, color) }
+    fn ptriangle(&mut self, filled: bool, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
+        Buffer::ptriangle(self, filled, x0, y0, x1, y1, x2, y2, color)
+    }
+    fn pcircle(&mut self, filled: bool, xc: i32, yc: i32, r: i32, color: Color) { Buffer::pcircle(self, filled, xc, yc, r, color) }
+    fn pimg(&mut self, image: &Buffer, x: i32, y: i32) { Buffer::pimg(self, image, x, y) }
+    fn pimg_effects(&mut self, image: &Buffer, x: i32, y: i32, effects: &[SpriteEffect]) { Buffer::pimg_effects(self, image, x, y, effects) }
+    fn pimgrect(&mut self, image: &Buffer, x: i32, y: i32, rx: i32, ry: i32, rw: i32, rh: i32) { Buffer::pimgrect(self, image, x, y, rx, ry, rw, rh) }
+    fn pimgrect_flip(&mut self, image: &Buffer, x: i32, y: i32, rect: (i32, i32, i32, i32), flip: ImageFlip) {
+        Buffer::pimgrect_flip(self, image, x, y, rect, flip)
+    }
+    fn pimgmtx(&mut self, image: &Buffer, position_x: f32, position_y: f32, rotation: f32, scale_x: f32, scale_y: f32, offset_x: f32, offset_y: f32) {
+        Buffer::pimgmtx(self, image, position_x, position_y, rotation, scale_x, scale_y, offset_x, offset_y)
+    }
+    fn ptritex(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, image: &Buffer) {
+        Buffer::ptritex(self, x0, y0, x1, y1, x2, y2, image)
+    }
+    fn ptritex_uv(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, u0: f32, v0: f32, u1: f32, v1: f32, u2: f32, v2: f32, image: &Buffer) {
+        Buffer::ptritex_uv(self, x0, y0, x1, y1, x2, y2, u0, v0, u1, v1, u2, v2, image)
+    }
+    fn ptritex_uvw(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, u0: f32, v0: f32, w0: f32, u1: f32, v1: f32, w1: f32, u2: f32, v2: f32, w2: f32, image: &Buffer) {
+        Buffer::ptritex_uvw(self, x0, y0, x1, y1, x2, y2, u0, v0, w0, u1, v1, w1, u2, v2, w2, image)
+    }
+    fn pprint(&mut self, font: &Font, text: String, x: i32, y: i32, newline_space: i32, wrap_width: Option<u32>) {
+        Buffer::pprint(self, font, text, x, y, newline_space, wrap_width)
+    }
+    fn pbeizer(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, mx: i32, my: i32, color: Color) { Buffer::pbeizer(self, x0, y0, x1, y1, mx, my, color) }
+    fn pcomposite_opaque(&mut self, buffer: &Buffer) { Buffer::pcomposite_opaque(self, buffer) }
+    fn pcomposite_alpha(&mut self, buffer: &Buffer, opacity: u8) { Buffer::pcomposite_alpha(self, buffer, opacity) }
+    fn pcomposite_multiply(&mut self, buffer: &Buffer) { Buffer::pcomposite_multiply(self, buffer) }
+}
+
+impl Canvas for PartitionedBuffer {
+    fn width(&self) -> usize { self.buffer.width }
+    fn height(&self) -> usize { self.buffer.height }
+
+    fn begin_frame(&mut self) { PartitionedBuffer::begin_frame(self) }
+    fn clear(&mut self) { PartitionedBuffer::clear(self) }
+    fn clear_color(&mut self, color: Color) { PartitionedBuffer::clear_color(self, color) }
+    fn add_shader(&mut self, buffer_shader: BufferShader) { PartitionedBuffer::add_shader(self, buffer_shader) }
+    fn clear_shaders(&mut self) { PartitionedBuffer::clear_shaders(self) }
+    fn set_uniforms(&mut self, uniforms: ShaderUniforms) { PartitionedBuffer::set_uniforms(self, uniforms) }
+    fn set_stencil_state(&mut self, stencil_state: StencilState) { PartitionedBuffer::set_stencil_state(self, stencil_state) }
+    fn set_mask(&mut self, mask: Option<BufferMask>) { PartitionedBuffer::set_mask(self, mask) }
+    fn clear_stencil(&mut self, value: u8) { PartitionedBuffer::clear_stencil(self, value) }
+    fn set_dirty_tracking(&mut self, enabled: bool) { PartitionedBuffer::set_dirty_tracking(self, enabled) }
+    fn dirty_rects(&self) -> Vec<DirtyRect> { PartitionedBuffer::dirty_rects(self) }
+
+    fn tint_buffer(&mut self, color: Color) { PartitionedBuffer::tint_buffer(self, color) }
+    fn blit(&mut self, src: &Buffer, x: i32, y: i32) { PartitionedBuffer::blit(self, src, x, y) }
+    fn post_process(&mut self, effect: &dyn PostProcess) { PartitionedBuffer::post_process(self, effect) }
+    fn submit(&mut self, commands: &CommandBuffer) { PartitionedBuffer::submit(self, commands) }
+
+    fn begin_primitive(&mut self) -> ShaderParams { PartitionedBuffer::begin_primitive(self) }
+    fn pspan(&mut self, x_start: i32, y: i32, colors: &mut [Color]) { PartitionedBuffer::pspan(self, x_start, y, colors) }
+    fn pspan_with(&mut self, x_start: i32, y: i32, colors: &mut [Color], varyings: &[ShaderVaryings], params: ShaderParams) {
+        PartitionedBuffer::pspan_with(self, x_start, y, colors, varyings, params)
+    }
+    fn ppixel_with(&mut self, x: i32, y: i32, color: Color, params: ShaderParams) { PartitionedBuffer::ppixel_with(self, x, y, color, params) }
+
+    fn pset(&mut self, x: i32, y: i32, color: Color) { PartitionedBuffer::pset(self, x, y, color) }
+    fn pget(&self, x: i32, y: i32) -> Color { PartitionedBuffer::pget(self, x, y) }
+    fn pget_wrap(&self, x: i32, y: i32) -> Color { PartitionedBuffer::pget_wrap(self, x, y) }
+    fn pline(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) { PartitionedBuffer::pline(self, x0, y0, x1, y1, color) }
+    fn prectangle(&mut self, filled: bool, x: i32, y: i32, w: i32, h: i32, color: Color) { PartitionedBuffer::prectangle(self, filled, x, y, w, h, color) }
+    fn ptriangle(&mut self, filled: bool, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
+        PartitionedBuffer::ptriangle(self, filled, x0, y0, x1, y1, x2, y2, color)
+    }
+    fn pcircle(&mut self, filled: bool, xc: i32, yc: i32, r: i32, color: Color) { PartitionedBuffer::pcircle(self, filled, xc, yc, r, color) }
+    fn pimg(&mut self, image: &Buffer, x: i32, y: i32) { PartitionedBuffer::pimg(self, image, x, y) }
+    fn pimg_effects(&mut self, image: &Buffer, x: i32, y: i32, effects: &[SpriteEffect]) { PartitionedBuffer::pimg_effects(self, image, x, y, effects) }
+    fn pimgrect(&mut self, image: &Buffer, x: i32, y: i32, rx: i32, ry: i32, rw: i32, rh: i32) { PartitionedBuffer::pimgrect(self, image, x, y, rx, ry, rw, rh) }
+    fn pimgrect_flip(&mut self, image: &Buffer, x: i32, y: i32, rect: (i32, i32, i32, i32), flip: ImageFlip) {
+        PartitionedBuffer::pimgrect_flip(self, image, x, y, rect, flip)
+    }
+    fn pimgmtx(&mut self, image: &Buffer, position_x: f32, position_y: f32, rotation: f32, scale_x: f32, scale_y: f32, offset_x: f32, offset_y: f32) {
+        PartitionedBuffer::pimgmtx(self, image, position_x, position_y, rotation, scale_x, scale_y, offset_x, offset_y)
+    }
+    fn ptritex(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, image: &Buffer) {
+        PartitionedBuffer::ptritex(self, x0, y0, x1, y1, x2, y2, image)
+    }
+    fn ptritex_uv(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, u0: f32, v0: f32, u1: f32, v1: f32, u2: f32, v2: f32, image: &Buffer) {
+        PartitionedBuffer::ptritex_uv(self, x0, y0, x1, y1, x2, y2, u0, v0, u1, v1, u2, v2, image)
+    }
+    fn ptritex_uvw(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, u0: f32, v0: f32, w0: f32, u1: f32, v1: f32, w1: f32, u2: f32, v2: f32, w2: f32, image: &Buffer) {
+        PartitionedBuffer::ptritex_uvw(self, x0, y0, x1, y1, x2, y2, u0, v0, w0, u1, v1, w1, u2, v2, w2, image)
+    }
+    fn pprint(&mut self, font: &Font, text: String, x: i32, y: i32, newline_space: i32, wrap_width: Option<u32>) {
+        PartitionedBuffer::pprint(self, font, text, x, y, newline_space, wrap_width)
+    }
+    fn pbeizer(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, mx: i32, my: i32, color: Color) { PartitionedBuffer::pbeizer(self, x0, y0, x1, y1, mx, my, color) }
+    fn pcomposite_opaque(&mut self, buffer: &Buffer) { PartitionedBuffer::pcomposite_opaque(self, buffer) }
+    fn pcomposite_alpha(&mut self, buffer: &Buffer, opacity: u8) { PartitionedBuffer::pcomposite_alpha(self, buffer, opacity) }
+    fn pcomposite_multiply(&mut self, buffer: &Buffer) { PartitionedBuffer::pcomposite_multiply(self, buffer) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sprite_effect::Neighbourhood;
+
+    // Game code written once against Canvas, with big enough shapes that the partitioned buffer draws them in parallel
+    fn draw_scene(canvas: &mut dyn Canvas, sprite: &Buffer) {
+        canvas.set_dirty_tracking(true);
+        canvas.begin_frame();
+        canvas.clear_color(Color::BLACK);
+        canvas.add_shader(BufferShader::new_fn(|ctx| Some(Color::new(ctx.color().b, ctx.color().g, ctx.color().r, 255)), true, 0));
+
+        canvas.prectangle(true, 10, 10, 120, 90, Color::RED);
+        canvas.ptriangle(true, 5, 150, 200, 20, 190, 170, Color::GREEN);
+        canvas.pline(0, 0, 199, 179, Color::WHITE);
+        canvas.pset(3, 4, Color::WHITE);
+
+        let mut span = vec![Color::RED; 50];
+        canvas.pspan(20, 120, &mut span);
+
+        let effects = [SpriteEffect::Outline { thickness: 2, color: Color::WHITE, neighbours: Neighbourhood::Eight }];
+        canvas.pimg_effects(sprite, 60, 60, &effects);
+        canvas.tint_buffer(Color::new(200, 255, 255, 255));
+    }
+
+    #[test]
+    fn buffer_and_partitioned_buffer_draw_the_same() {
+        let mut sprite = Buffer::new(16, 16);
+        sprite.prectangle(true, 4, 4, 8, 8, Color::GREEN);
+
+        let mut buffer = Buffer::new(200, 180);
+        draw_scene(&mut buffer, &sprite);
+
+        let mut partitioned = PartitionedBuffer::new(200, 180, 2, 64);
+        partitioned.set_scheme(crate::partitioned_buffer::PartitionScheme::Split3x3);
+        draw_scene(&mut partitioned, &sprite);
+
+        assert!(buffer.color == partitioned.buffer.color);
+        assert!(!Canvas::dirty_rects(&partitioned).is_empty());
+    }
+}
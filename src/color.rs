@@ -1,6 +1,8 @@
+use crate::color_space::{byte_to_linear, linear_to_byte, linear_to_srgb};
+
 /// 32-bit Color using  1-byte channels for Red, Green, Blue, and Alpha.
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Color {
 	pub r: u8,
 	pub g: u8,
@@ -8,6 +10,7 @@ pub struct Color {
 	pub a: u8,
 }
 
+/// Hue in degrees from 0.0 to 360.0, saturation and value from 0.0 to 1.0.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct HSV {
 	pub hue: f32,
@@ -15,8 +18,54 @@ pub struct HSV {
 	pub value: f32,
 }
 
+/// Hue in degrees from 0.0 to 360.0, saturation and lightness from 0.0 to 1.0.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HSL {
+	pub hue: f32,
+	pub saturation: f32,
+	pub lightness: f32,
+}
+
+/// CIE 1931 XYZ with a D65 white point, where white has a 'y' of 1.0.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct XYZ {
+	pub x: f32,
+	pub y: f32,
+	pub z: f32,
+}
+
+/// CIELAB with a D65 white point. 'l' goes from 0.0 to 100.0, 'a' and 'b' are roughly -128.0 to 127.0.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Lab {
+	pub l: f32,
+	pub a: f32,
+	pub b: f32,
+}
+
+/// Björn Ottosson's OKLab. 'l' goes from 0.0 to 1.0, 'a' and 'b' are roughly -0.4 to 0.4.
+/// Equal steps look like equal changes in color, which makes it the best space to blend gradients in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OkLab {
+	pub l: f32,
+	pub a: f32,
+	pub b: f32,
+}
+
+/// What the arithmetic operators do with alpha. The operators themselves always use Keep.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorAlpha {
+	/// Keep the alpha of the left color.
+	Keep,
+	/// Run the same operation on alpha as on the other channels.
+	Apply,
+	/// Make the result fully opaque.
+	Opaque,
+}
+
 impl Color {
 
+	/// Opaque black, despite the name.
+	#[deprecated(note = "CLEAR is opaque black. Use Color::TRANSPARENT for an empty pixel or Color::BLACK for black")]
 	pub const CLEAR:	Color = Color { r:   0, g:   0, b:   0, a: 255 };
 	pub const WHITE: 	Color = Color { r: 255, g: 255, b: 255, a: 255 };
 	pub const BLACK: 	Color = Color { r:   0, g:   0, b:   0, a: 255 };
@@ -26,6 +75,7 @@ impl Color {
 	pub const MAGENTA: 	Color = Color { r: 255, g:   0, b: 255, a: 255 };
 	pub const YELLOW: 	Color = Color { r: 255, g: 255, b:   0, a: 255 };
 	pub const CYAN: 	Color = Color { r:   0, g: 255, b: 255, a: 255 };
+	pub const TRANSPARENT: Color = Color { r:   0, g:   0, b:   0, a:   0 };
 
 	/// InVeNt NeW cOlOrS
 	pub fn new(r: u8, g: u8, b: u8, a: u8) -> Color {
@@ -38,9 +88,9 @@ impl Color {
 		[self.r, self.g, self.b, self.a]
 	}
 
-	/// Accurate but slow alpha-blending function
+	/// Accurate but slow alpha-blending function. A fully transparent 'src' leaves 'dst' as it is.
 	pub fn blend_slow(src: Color, dst: Color, opacity: f32) -> Color {
-		if src.a <= 0 { return Color::CLEAR; }
+		if src.a == 0 { return dst; }
 
 		let src_rf32 = src.r as f32 / 255.0;
 		let src_gf32 = src.g as f32 / 255.0;
@@ -96,6 +146,63 @@ impl Color {
 		}
 	}
 
+	/// Copy of the color with a different alpha.
+	pub fn with_alpha(&self, a: u8) -> Color {
+		Color { a, ..*self }
+	}
+
+	/// Perceived brightness from Rec. 709 weights, 0 to 255.
+	pub fn luminance(&self) -> u8 {
+		((self.r as u32 * 54 + self.g as u32 * 183 + self.b as u32 * 19) >> 8) as u8
+	}
+
+	/// Grey of the same luminance, keeping alpha.
+	pub fn grayscale(&self) -> Color {
+		let l = self.luminance();
+		Color::new(l, l, l, self.a)
+	}
+
+	/// Pushes red, green and blue away from mid grey by 'amount', or towards it below 1.0. 1.0 changes nothing.
+	pub fn contrast(&self, amount: f32) -> Color {
+		let channel = |c: u8| ((c as f32 - 127.5) * amount + 127.5).round().clamp(0.0, 255.0) as u8;
+		Color::new(channel(self.r), channel(self.g), channel(self.b), self.a)
+	}
+
+	/// Adds 'amount' to red, green and blue, where 1.0 is full brightness and negative values darken.
+	pub fn brightness(&self, amount: f32) -> Color {
+		let channel = |c: u8| (c as f32 + amount * 255.0).round().clamp(0.0, 255.0) as u8;
+		Color::new(channel(self.r), channel(self.g), channel(self.b), self.a)
+	}
+
+	/// Saturating channel add.
+	pub fn add_with(&self, rhs: Color, alpha: ColorAlpha) -> Color {
+		self.combine(rhs, alpha, u8::saturating_add)
+	}
+
+	/// Saturating channel subtract.
+	pub fn sub_with(&self, rhs: Color, alpha: ColorAlpha) -> Color {
+		self.combine(rhs, alpha, u8::saturating_sub)
+	}
+
+	/// Channel multiply where 255 is 1.0, so multiplying by white changes nothing.
+	pub fn mul_with(&self, rhs: Color, alpha: ColorAlpha) -> Color {
+		self.combine(rhs, alpha, mul_byte)
+	}
+
+	/// Channel divide where 255 is 1.0, undoing mul_with. Saturates at 255, and dividing by 0 gives 255 unless the channel is 0.
+	pub fn div_with(&self, rhs: Color, alpha: ColorAlpha) -> Color {
+		self.combine(rhs, alpha, div_byte)
+	}
+
+	fn combine(&self, rhs: Color, alpha: ColorAlpha, op: fn(u8, u8) -> u8) -> Color {
+		let a = match alpha {
+			ColorAlpha::Keep => self.a,
+			ColorAlpha::Apply => op(self.a, rhs.a),
+			ColorAlpha::Opaque => 255,
+		};
+		Color::new(op(self.r, rhs.r), op(self.g, rhs.g), op(self.b, rhs.b), a)
+	}
+
 	/// Hue, Saturation, and Value color definition. Should not be used per pixel for performance reasons.
 	pub fn hsv(hue: f32, saturation: f32, value: f32) -> Color {
 		let hi: i32 = ((hue / 60.0).floor() as i32) % 6;
@@ -117,126 +224,529 @@ impl Color {
 		}
 	}
 
+	/// Mixes the sRGB bytes directly, which is fast but dips darker halfway between. See lerp_linear.
 	pub fn lerp_rgb(c1: Color, c2: Color, t: f32) -> Color {
-		let tb = (f32::clamp(t, 0.0, 1.0) * 255.0) as u8;
-		let mut cf: Color = c1;
+		let t = f32::clamp(t, 0.0, 1.0);
+		let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
 
-		cf.r = c1.r + (c2.r - c1.r) * tb;
-		cf.g = c1.g + (c2.g - c1.g) * tb;
-		cf.b = c1.b + (c2.b - c1.b) * tb;
+		Color { r: lerp(c1.r, c2.r), g: lerp(c1.g, c2.g), b: lerp(c1.b, c2.b), a: c1.a }
+	}
 
-		cf
+	/// Red, green and blue in linear light from 0.0 to 1.0, with alpha as it is.
+	pub fn to_linear(&self) -> [f32; 4] {
+		[byte_to_linear(self.r), byte_to_linear(self.g), byte_to_linear(self.b), self.a as f32 / 255.0]
 	}
+
+	/// Turns linear light back into an sRGB color. Values outside 0.0 to 1.0 are clamped.
+	pub fn from_linear(linear: [f32; 4]) -> Color {
+		Color {
+			r: linear_to_byte(linear[0]),
+			g: linear_to_byte(linear[1]),
+			b: linear_to_byte(linear[2]),
+			a: (linear[3] * 255.0).round().clamp(0.0, 255.0) as u8,
+		}
+	}
+
+	/// Same as lerp_rgb, mixing in linear light so the colors between stay as bright as they should.
+	/// Alpha is mixed too.
+	pub fn lerp_linear(c1: Color, c2: Color, t: f32) -> Color {
+		let t = f32::clamp(t, 0.0, 1.0);
+		let (l1, l2) = (c1.to_linear(), c2.to_linear());
+
+		Color::from_linear([
+			l1[0] + (l2[0] - l1[0]) * t,
+			l1[1] + (l2[1] - l1[1]) * t,
+			l1[2] + (l2[2] - l1[2]) * t,
+			l1[3] + (l2[3] - l1[3]) * t,
+		])
+	}
+
+	/// Same as blend_slow, blending in linear light. Avoids the dark fringes sRGB blending leaves around soft edges.
+	/// A fully transparent 'src' leaves 'dst' as it is.
+	pub fn blend_linear(src: Color, dst: Color, opacity: f32) -> Color {
+		if src.a == 0 { return dst; }
+
+		let (s, d) = (src.to_linear(), dst.to_linear());
+		let sa = s[3] * opacity;
+
+		let fa = sa + d[3] * (1.0 - sa);
+		if fa <= 0.0 { return Color::TRANSPARENT; }
+
+		Color::from_linear([
+			(s[0] * sa + d[0] * (1.0 - sa)) / fa,
+			(s[1] * sa + d[1] * (1.0 - sa)) / fa,
+			(s[2] * sa + d[2] * (1.0 - sa)) / fa,
+			fa,
+		])
+	}
+
+	/// Reads a hex color like "#ff8800", "#ff8800aa", "#f80" or "#f80a". The '#' is optional.
+	/// Colors without alpha are opaque.
+	pub fn from_hex(hex: &str) -> Result<Color, String> {
+		let digits = hex.trim();
+		let digits = digits.strip_prefix('#').unwrap_or(digits);
+		let error = || format!("ERROR - COLOR: '{}' is not a hex color", hex);
+
+		// from_str_radix would also take a leading '+'
+		if !digits.bytes().all(|b| b.is_ascii_hexdigit()) { return Err(error()); }
+
+		let nibble = |i: usize| u8::from_str_radix(&digits[i..i + 1], 16).map(|n| n * 17).map_err(|_| error());
+		let byte = |i: usize| u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).map_err(|_| error());
+
+		match digits.len() {
+			3 => Ok(Color::new(nibble(0)?, nibble(1)?, nibble(2)?, 255)),
+			4 => Ok(Color::new(nibble(0)?, nibble(1)?, nibble(2)?, nibble(3)?)),
+			6 => Ok(Color::new(byte(0)?, byte(1)?, byte(2)?, 255)),
+			8 => Ok(Color::new(byte(0)?, byte(1)?, byte(2)?, byte(3)?)),
+			_ => Err(error()),
+		}
+	}
+
+	/// Writes the color as "#rrggbb", or "#rrggbbaa" if it isn't opaque.
+	pub fn to_hex(&self) -> String {
+		if self.a == 255 {
+			format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+		} else {
+			format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
+		}
+	}
+
+	pub fn to_hsv(&self) -> HSV {
+		let (r, g, b) = (self.r as f32 / 255.0, self.g as f32 / 255.0, self.b as f32 / 255.0);
+		let max = r.max(g).max(b);
+		let delta = max - r.min(g).min(b);
+
+		HSV {
+			hue: hue_of(r, g, b, max, delta),
+			saturation: if max <= 0.0 { 0.0 } else { delta / max },
+			value: max,
+		}
+	}
+
+	/// Same as Color::hsv, rounding to the nearest byte so colors survive a round trip through to_hsv.
+	pub fn from_hsv(hsv: HSV, alpha: u8) -> Color {
+		let chroma = hsv.value * hsv.saturation;
+		from_hue(hsv.hue, chroma, hsv.value - chroma, alpha)
+	}
+
+	pub fn to_hsl(&self) -> HSL {
+		let (r, g, b) = (self.r as f32 / 255.0, self.g as f32 / 255.0, self.b as f32 / 255.0);
+		let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+		let delta = max - min;
+		let lightness = (max + min) / 2.0;
+
+		HSL {
+			hue: hue_of(r, g, b, max, delta),
+			saturation: if delta <= 0.0 { 0.0 } else { delta / (1.0 - (2.0 * lightness - 1.0).abs()) },
+			lightness,
+		}
+	}
+
+	pub fn from_hsl(hsl: HSL, alpha: u8) -> Color {
+		let chroma = (1.0 - (2.0 * hsl.lightness - 1.0).abs()) * hsl.saturation;
+		from_hue(hsl.hue, chroma, hsl.lightness - chroma / 2.0, alpha)
+	}
+
+	pub fn to_xyz(&self) -> XYZ {
+		let [r, g, b, _] = self.to_linear();
+		XYZ {
+			x: 0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+			y: 0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+			z: 0.0193339 * r + 0.119192 * g + 0.9503041 * b,
+		}
+	}
+
+	/// Colors outside what sRGB can show are clamped.
+	pub fn from_xyz(xyz: XYZ, alpha: u8) -> Color {
+		let XYZ { x, y, z } = xyz;
+		from_linear_rgb(
+			3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+			-0.969266 * x + 1.8760108 * y + 0.0415560 * z,
+			0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+			alpha,
+		)
+	}
+
+	pub fn to_lab(&self) -> Lab {
+		let xyz = self.to_xyz();
+		let (fx, fy, fz) = (lab_f(xyz.x / D65_WHITE[0]), lab_f(xyz.y / D65_WHITE[1]), lab_f(xyz.z / D65_WHITE[2]));
+		Lab { l: 116.0 * fy - 16.0, a: 500.0 * (fx - fy), b: 200.0 * (fy - fz) }
+	}
+
+	/// Colors outside what sRGB can show are clamped.
+	pub fn from_lab(lab: Lab, alpha: u8) -> Color {
+		let fy = (lab.l + 16.0) / 116.0;
+		let (fx, fz) = (fy + lab.a / 500.0, fy - lab.b / 200.0);
+		Color::from_xyz(XYZ { x: lab_f_inverse(fx) * D65_WHITE[0], y: lab_f_inverse(fy) * D65_WHITE[1], z: lab_f_inverse(fz) * D65_WHITE[2] }, alpha)
+	}
+
+	pub fn to_oklab(&self) -> OkLab {
+		let [r, g, b, _] = self.to_linear();
+		let l = (0.41222146 * r + 0.53633255 * g + 0.051445995 * b).cbrt();
+		let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+		let s = (0.08830246 * r + 0.28171885 * g + 0.6299787 * b).cbrt();
+
+		OkLab {
+			l: 0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+			a: 1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+			b: 0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+		}
+	}
+
+	/// Colors outside what sRGB can show are clamped.
+	pub fn from_oklab(oklab: OkLab, alpha: u8) -> Color {
+		let l = (oklab.l + 0.39633778 * oklab.a + 0.21580376 * oklab.b).powi(3);
+		let m = (oklab.l - 0.105561346 * oklab.a - 0.06385417 * oklab.b).powi(3);
+		let s = (oklab.l - 0.08948418 * oklab.a - 1.2914855 * oklab.b).powi(3);
+
+		from_linear_rgb(
+			4.0767417 * l - 3.3077116 * m + 0.23096994 * s,
+			-1.268438 * l + 2.6097574 * m - 0.34131938 * s,
+			-0.0041960863 * l - 0.7034186 * m + 1.7076147 * s,
+			alpha,
+		)
+	}
+
+	/// Mixes two colors in OKLab, so the colors between look evenly spaced and keep their brightness.
+	/// Alpha is mixed in a straight line.
+	pub fn lerp_oklab(c1: Color, c2: Color, t: f32) -> Color {
+		let t = f32::clamp(t, 0.0, 1.0);
+		let (o1, o2) = (c1.to_oklab(), c2.to_oklab());
+		let alpha = (c1.a as f32 + (c2.a as f32 - c1.a as f32) * t).round() as u8;
+
+		Color::from_oklab(OkLab { l: o1.l + (o2.l - o1.l) * t, a: o1.a + (o2.a - o1.a) * t, b: o1.b + (o2.b - o1.b) * t }, alpha)
+	}
+}
+
+const D65_WHITE: [f32; 3] = [0.95047, 1.0, 1.08883];
+
+// Shared hue of HSV and HSL in degrees, from channels in 0.0 to 1.0 and their largest value and range
+fn hue_of(r: f32, g: f32, b: f32, max: f32, delta: f32) -> f32 {
+	if delta <= 0.0 { return 0.0; }
+
+	let sector = if max == r {
+		((g - b) / delta).rem_euclid(6.0)
+	} else if max == g {
+		(b - r) / delta + 2.0
+	} else {
+		(r - g) / delta + 4.0
+	};
+	sector * 60.0
+}
+
+// Builds a color from a hue, its chroma and the amount added to every channel, for HSV and HSL
+fn from_hue(hue: f32, chroma: f32, offset: f32, alpha: u8) -> Color {
+	let sector = hue.rem_euclid(360.0) / 60.0;
+	let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+
+	let (r, g, b) = match sector as i32 {
+		0 => (chroma, x, 0.0),
+		1 => (x, chroma, 0.0),
+		2 => (0.0, chroma, x),
+		3 => (0.0, x, chroma),
+		4 => (x, 0.0, chroma),
+		_ => (chroma, 0.0, x),
+	};
+
+	let byte = |v: f32| ((v + offset) * 255.0).round().clamp(0.0, 255.0) as u8;
+	Color::new(byte(r), byte(g), byte(b), alpha)
+}
+
+// Exact rather than through the table, so colors from other models land on the nearest byte
+fn from_linear_rgb(r: f32, g: f32, b: f32, alpha: u8) -> Color {
+	let byte = |v: f32| (linear_to_srgb(v.clamp(0.0, 1.0)) * 255.0).round() as u8;
+	Color::new(byte(r), byte(g), byte(b), alpha)
+}
+
+fn lab_f(t: f32) -> f32 {
+	const DELTA: f32 = 6.0 / 29.0;
+	if t > DELTA * DELTA * DELTA { t.cbrt() } else { t / (3.0 * DELTA * DELTA) + 4.0 / 29.0 }
+}
+
+fn lab_f_inverse(t: f32) -> f32 {
+	const DELTA: f32 = 6.0 / 29.0;
+	if t > DELTA { t * t * t } else { 3.0 * DELTA * DELTA * (t - 4.0 / 29.0) }
+}
+
+// Rounds a product of two bytes back into a byte, so x * 255 / 255 is always x
+pub(crate) fn mul_byte(a: u8, b: u8) -> u8 {
+	let x = a as u32 * b as u32 + 128;
+	((x + (x >> 8)) >> 8) as u8
+}
+
+// The inverse of mul_byte, saturating at 255. Dividing by zero gives 255 unless the top is also zero
+fn div_byte(a: u8, b: u8) -> u8 {
+	if b == 0 { return if a == 0 { 0 } else { 255 }; }
+	u32::min((a as u32 * 255 + b as u32 / 2) / b as u32, 255) as u8
+}
+
+fn scale_byte(a: u8, factor: f32) -> u8 {
+	(a as f32 * factor).round().clamp(0.0, 255.0) as u8
 }
 
 impl std::ops::Add for Color {
 	type Output = Self;
 
+	/// Saturating add of red, green and blue, keeping the left alpha. See add_with.
 	fn add(self, rhs: Self) -> Self {
-		Color::new(
-			u8::saturating_add(self.r, rhs.r),
-			u8::saturating_add(self.g, rhs.g),
-			u8::saturating_add(self.b, rhs.b),
-			u8::saturating_add(self.a, rhs.a)
-		)
+		self.add_with(rhs, ColorAlpha::Keep)
 	}
 }
 
 impl std::ops::Sub for Color {
 	type Output = Self;
 
+	/// Saturating subtract of red, green and blue, keeping the left alpha. See sub_with.
 	fn sub(self, rhs: Self) -> Self {
-		Color::new(
-			u8::saturating_sub(self.r, rhs.r),
-			u8::saturating_sub(self.g, rhs.g),
-			u8::saturating_sub(self.b, rhs.b),
-			u8::saturating_sub(self.a, rhs.a)
-		)
+		self.sub_with(rhs, ColorAlpha::Keep)
 	}
 }
 
 impl std::ops::Mul for Color {
 	type Output = Self;
 
+	/// Multiplies red, green and blue as if they went from 0.0 to 1.0, keeping the left alpha. See mul_with.
 	fn mul(self, rhs: Self) -> Self {
-		let sr32 = self.r as u32;
-		let sg32 = self.g as u32;
-		let sb32 = self.b as u32;
-
-		let rhsr32 = rhs.r as u32;
-		let rhsg32 = rhs.g as u32;
-		let rhsb32 = rhs.b as u32;
-		
-		Color::new(
-			(((sr32 * rhsr32 + 255)) >> 8) as u8,
-			(((sg32 * rhsg32 + 255)) >> 8) as u8,
-			(((sb32 * rhsb32 + 255)) >> 8) as u8,
-			self.a,
-		)
+		self.mul_with(rhs, ColorAlpha::Keep)
 	}
 }
 
 impl std::ops::Div for Color {
 	type Output = Self;
 
+	/// Divides red, green and blue as if they went from 0.0 to 1.0, keeping the left alpha. See div_with.
 	fn div(self, rhs: Self) -> Self {
-		Color::new(
-			u8::saturating_div(self.r, rhs.r),
-			u8::saturating_div(self.g, rhs.g),
-			u8::saturating_div(self.b, rhs.b),
-			u8::saturating_div(self.a, rhs.a)
-		)
+		self.div_with(rhs, ColorAlpha::Keep)
+	}
+}
+
+impl std::ops::Mul<f32> for Color {
+	type Output = Self;
+
+	/// Scales red, green and blue, keeping alpha.
+	fn mul(self, rhs: f32) -> Self {
+		Color::new(scale_byte(self.r, rhs), scale_byte(self.g, rhs), scale_byte(self.b, rhs), self.a)
+	}
+}
+
+impl std::ops::Div<f32> for Color {
+	type Output = Self;
+
+	/// Scales red, green and blue by 1 / rhs, keeping alpha. Dividing by zero saturates every channel that isn't zero.
+	fn div(self, rhs: f32) -> Self {
+		self * (1.0 / rhs)
 	}
 }
 
 impl std::ops::AddAssign for Color {
 	fn add_assign(&mut self, rhs: Self) {
-        *self = Color::new(
-			u8::saturating_add(self.r, rhs.r),
-			u8::saturating_add(self.g, rhs.g),
-			u8::saturating_add(self.b, rhs.b),
-			u8::saturating_add(self.a, rhs.a)
-		);
-    }
+		*self = *self + rhs;
+	}
 }
 
 impl std::ops::SubAssign for Color {
 	fn sub_assign(&mut self, rhs: Self) {
-        *self = Color::new(
-			u8::saturating_sub(self.r, rhs.r),
-			u8::saturating_sub(self.g, rhs.g),
-			u8::saturating_sub(self.b, rhs.b),
-			u8::saturating_sub(self.a, rhs.a)
-		);
-    }
+		*self = *self - rhs;
+	}
 }
 
 impl std::ops::MulAssign for Color {
 	fn mul_assign(&mut self, rhs: Self) {
-		let sr32 = self.r as u32;
-		let sg32 = self.g as u32;
-		let sb32 = self.b as u32;
-
-		let rhsr32 = rhs.r as u32;
-		let rhsg32 = rhs.g as u32;
-		let rhsb32 = rhs.b as u32;
-		
-		*self = Color::new(
-			(((sr32 * rhsr32 + 255)) >> 8) as u8,
-			(((sg32 * rhsg32 + 255)) >> 8) as u8,
-			(((sb32 * rhsb32 + 255)) >> 8) as u8,
-			self.a,
-		)
+		*self = *self * rhs;
 	}
 }
 
 impl std::ops::DivAssign for Color {
 	fn div_assign(&mut self, rhs: Self) {
-        *self = Color::new(
-			u8::saturating_div(self.r, rhs.r),
-			u8::saturating_div(self.g, rhs.g),
-			u8::saturating_div(self.b, rhs.b),
-			u8::saturating_div(self.a, rhs.a)
-		);
-    }
-}
\ No newline at end of file
+		*self = *self / rhs;
+	}
+}
+
+impl std::ops::MulAssign<f32> for Color {
+	fn mul_assign(&mut self, rhs: f32) {
+		*self = *self * rhs;
+	}
+}
+
+impl std::ops::DivAssign<f32> for Color {
+	fn div_assign(&mut self, rhs: f32) {
+		*self = *self / rhs;
+	}
+}
+
+impl std::ops::Not for Color {
+	type Output = Self;
+
+	/// Same as inverted.
+	fn not(self) -> Self {
+		self.inverted()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Every 15th level of each channel, which includes 0 and 255
+	fn sample_colors() -> impl Iterator<Item = Color> {
+		(0..=255u8).step_by(15).flat_map(|r| (0..=255u8).step_by(15).flat_map(move |g| (0..=255u8).step_by(15).map(move |b| Color::new(r, g, b, 255))))
+	}
+
+	#[test]
+	fn from_hex_reads_every_length() {
+		assert_eq!(Color::from_hex("#f80"), Ok(Color::new(255, 136, 0, 255)));
+		assert_eq!(Color::from_hex("f80a"), Ok(Color::new(255, 136, 0, 170)));
+		assert_eq!(Color::from_hex("#FF8800"), Ok(Color::new(255, 136, 0, 255)));
+		assert_eq!(Color::from_hex(" ff880080 "), Ok(Color::new(255, 136, 0, 128)));
+	}
+
+	#[test]
+	fn from_hex_rejects_bad_input() {
+		for bad in ["", "#", "##fff", "ff", "fffff", "fffffff", "fffffffff", "ggg", "+f+f+f", "-1-1-1", "ff 00 ff", "ff8800é"] {
+			assert!(Color::from_hex(bad).is_err(), "'{}' should not parse", bad);
+		}
+	}
+
+	#[test]
+	fn hex_round_trips() {
+		for color in sample_colors().chain([Color::new(1, 2, 3, 4)]) {
+			assert_eq!(Color::from_hex(&color.to_hex()), Ok(color));
+		}
+	}
+
+	#[test]
+	fn color_models_round_trip() {
+		for color in sample_colors() {
+			assert_eq!(Color::from_hsv(color.to_hsv(), 255), color);
+			assert_eq!(Color::from_hsl(color.to_hsl(), 255), color);
+			assert_eq!(Color::from_xyz(color.to_xyz(), 255), color);
+			assert_eq!(Color::from_lab(color.to_lab(), 255), color);
+			assert_eq!(Color::from_oklab(color.to_oklab(), 255), color);
+		}
+	}
+
+	#[test]
+	fn color_models_match_reference_values() {
+		let red = Color::RED;
+		assert!((red.to_hsv().hue - 0.0).abs() < 1e-3 && (red.to_hsv().saturation - 1.0).abs() < 1e-3);
+		assert!((Color::new(0, 0, 255, 255).to_hsl().hue - 240.0).abs() < 1e-3);
+
+		let lab = Color::WHITE.to_lab();
+		assert!((lab.l - 100.0).abs() < 1e-2 && lab.a.abs() < 1e-2 && lab.b.abs() < 1e-2);
+
+		let oklab = red.to_oklab();
+		assert!((oklab.l - 0.62796).abs() < 1e-3 && (oklab.a - 0.22486).abs() < 1e-3 && (oklab.b - 0.12585).abs() < 1e-3);
+	}
+
+	const ALPHA_MODES: [ColorAlpha; 3] = [ColorAlpha::Keep, ColorAlpha::Apply, ColorAlpha::Opaque];
+
+	// Every pair of bytes, with the pair also spread over the other channels so each one gets checked
+	fn byte_pairs() -> impl Iterator<Item = (u8, u8, Color, Color)> {
+		(0..=255u8).flat_map(|a| (0..=255u8).map(move |b| (a, b, Color::new(a, b, a, a), Color::new(b, a, b, b))))
+	}
+
+	fn check_alpha(result: Color, x: Color, y: Color, mode: ColorAlpha, op: fn(u8, u8) -> u8) {
+		let expected = match mode {
+			ColorAlpha::Keep => x.a,
+			ColorAlpha::Apply => op(x.a, y.a),
+			ColorAlpha::Opaque => 255,
+		};
+		assert_eq!(result.a, expected, "{:?} {:?} {:?}", x, y, mode);
+	}
+
+	#[test]
+	fn add_and_sub_saturate() {
+		for (a, b, x, y) in byte_pairs() {
+			for mode in ALPHA_MODES {
+				let sum = x.add_with(y, mode);
+				assert_eq!((sum.r, sum.g, sum.b), (a.saturating_add(b), b.saturating_add(a), a.saturating_add(b)));
+				check_alpha(sum, x, y, mode, u8::saturating_add);
+
+				let difference = x.sub_with(y, mode);
+				assert_eq!((difference.r, difference.g, difference.b), (a.saturating_sub(b), b.saturating_sub(a), a.saturating_sub(b)));
+				check_alpha(difference, x, y, mode, u8::saturating_sub);
+			}
+		}
+	}
+
+	#[test]
+	fn mul_and_div_round_to_nearest() {
+		let nearest = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+		for (a, b, x, y) in byte_pairs() {
+			for mode in ALPHA_MODES {
+				let product = x.mul_with(y, mode);
+				assert_eq!(product.r, nearest(a as f32 * b as f32 / 255.0));
+				check_alpha(product, x, y, mode, mul_byte);
+
+				let quotient = x.div_with(y, mode);
+				let expected = if b == 0 { if a == 0 { 0 } else { 255 } } else { nearest(a as f32 * 255.0 / b as f32) };
+				assert_eq!(quotient.r, expected);
+				check_alpha(quotient, x, y, mode, div_byte);
+			}
+		}
+	}
+
+	#[test]
+	fn white_is_the_identity() {
+		for (_, _, x, _) in byte_pairs() {
+			assert_eq!(x * Color::WHITE, x);
+			assert_eq!(x / Color::WHITE, x);
+			assert_eq!(x.mul_with(Color::WHITE, ColorAlpha::Apply), x);
+			assert_eq!(x.div_with(Color::WHITE, ColorAlpha::Apply), x);
+			assert_eq!(x + Color::TRANSPARENT, x);
+			assert_eq!(x - Color::TRANSPARENT, x);
+		}
+	}
+
+	#[test]
+	fn div_undoes_mul() {
+		for b in 1..=255u8 {
+			let products: Vec<u8> = (0..=255u8).map(|a| mul_byte(a, b)).collect();
+			for a in 0..=255u8 {
+				let x = Color::new(a, a, a, a);
+				let y = Color::new(b, b, b, b);
+				let back = (x * y) / y;
+
+				// Only exact when no other byte has the same product, otherwise within the rounding of the multiply
+				if products.iter().filter(|p| **p == products[a as usize]).count() == 1 {
+					assert_eq!(back, x);
+				} else {
+					assert!((back.r as f32 - a as f32).abs() <= 127.5 / b as f32 + 0.5, "{} * {} came back as {}", a, b, back.r);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn operators_keep_alpha() {
+		for (_, _, x, y) in byte_pairs() {
+			assert_eq!((x + y).a, x.a);
+			assert_eq!((x - y).a, x.a);
+			assert_eq!((x * y).a, x.a);
+			assert_eq!((x / y).a, x.a);
+			assert_eq!((x * 0.5).a, x.a);
+		}
+	}
+
+	#[test]
+	fn neutral_adjustments_change_nothing() {
+		for (_, _, x, _) in byte_pairs() {
+			assert_eq!(x.contrast(1.0), x);
+			assert_eq!(x.brightness(0.0), x);
+			assert_eq!(x * 1.0, x);
+			assert_eq!(!!x, x);
+		}
+	}
+
+	#[test]
+	fn helpers_match_reference_values() {
+		assert_eq!(Color::TRANSPARENT.a, 0);
+		assert_eq!(Color::RED.with_alpha(7), Color::new(255, 0, 0, 7));
+		assert_eq!(Color::WHITE.luminance(), 255);
+		assert_eq!(Color::BLACK.luminance(), 0);
+		assert_eq!(Color::new(200, 100, 50, 9).grayscale(), Color::new(117, 117, 117, 9));
+		assert_eq!(Color::new(200, 100, 50, 9).contrast(0.0), Color::new(128, 128, 128, 9));
+		assert_eq!(Color::new(200, 100, 50, 9).brightness(1.0), Color::new(255, 255, 255, 9));
+		assert_eq!(Color::new(200, 100, 50, 9).brightness(-1.0), Color::new(0, 0, 0, 9));
+	}
+}
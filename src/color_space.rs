@@ -0,0 +1,90 @@
+//! Conversions between sRGB, which is how Color and images store their bytes, and linear light, where blending
+//! and interpolation give the results you'd expect. Mixing sRGB bytes directly makes blends and gradients too dark.
+
+use std::sync::OnceLock;
+
+// Linear light to sRGB is looked up in this many steps, fine enough that every byte survives a round trip
+const LINEAR_STEPS: usize = 8192;
+
+/// Exact sRGB to linear conversion of a value from 0.0 to 1.0.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 { value / 12.92 } else { ((value + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Exact linear to sRGB conversion of a value from 0.0 to 1.0.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 { value * 12.92 } else { 1.055 * value.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// Converts an sRGB byte to linear light from 0.0 to 1.0, through a lookup table.
+pub fn byte_to_linear(byte: u8) -> f32 {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| srgb_to_linear(i as f32 / 255.0)))[byte as usize]
+}
+
+/// Converts linear light to an sRGB byte through a lookup table. Values outside 0.0 to 1.0 are clamped.
+pub fn linear_to_byte(value: f32) -> u8 {
+    static TABLE: OnceLock<Vec<u8>> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        (0..=LINEAR_STEPS).map(|i| (linear_to_srgb(i as f32 / LINEAR_STEPS as f32) * 255.0).round() as u8).collect()
+    });
+
+    table[(value.clamp(0.0, 1.0) * LINEAR_STEPS as f32).round() as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-5, "{} is not {}", value, expected);
+    }
+
+    #[test]
+    fn srgb_matches_reference_values() {
+        assert_close(srgb_to_linear(0.0), 0.0);
+        assert_close(srgb_to_linear(0.04045), 0.0031308);
+        assert_close(srgb_to_linear(0.5), 0.214041);
+        assert_close(srgb_to_linear(1.0), 1.0);
+
+        assert_close(linear_to_srgb(0.0), 0.0);
+        assert_close(linear_to_srgb(0.0031308), 0.04045);
+        assert_close(linear_to_srgb(0.214041), 0.5);
+        assert_close(linear_to_srgb(1.0), 1.0);
+    }
+
+    #[test]
+    fn tables_match_exact_conversions() {
+        for byte in 0..=255u8 {
+            assert_close(byte_to_linear(byte), srgb_to_linear(byte as f32 / 255.0));
+        }
+    }
+
+    #[test]
+    fn every_byte_survives_a_round_trip() {
+        for byte in 0..=255u8 {
+            assert_eq!(linear_to_byte(byte_to_linear(byte)), byte);
+        }
+    }
+
+    #[test]
+    fn linear_to_byte_clamps() {
+        assert_eq!(linear_to_byte(-1.0), 0);
+        assert_eq!(linear_to_byte(2.0), 255);
+    }
+
+    #[test]
+    fn lerp_linear_halfway_is_brighter_than_srgb() {
+        assert_eq!(Color::lerp_linear(Color::BLACK, Color::WHITE, 0.5), Color::new(188, 188, 188, 255));
+        assert_eq!(Color::lerp_rgb(Color::BLACK, Color::WHITE, 0.5), Color::new(128, 128, 128, 255));
+    }
+
+    #[test]
+    fn blend_linear_transparent_source_keeps_destination() {
+        let dst = Color::new(10, 200, 30, 255);
+        assert_eq!(Color::blend_linear(Color::TRANSPARENT, dst, 1.0), dst);
+        assert_eq!(Color::blend_linear(Color::new(255, 0, 0, 0), Color::WHITE, 1.0), Color::WHITE);
+        assert_eq!(Color::blend_linear(Color::new(255, 0, 0, 255), Color::TRANSPARENT, 0.0), Color::TRANSPARENT);
+    }
+}
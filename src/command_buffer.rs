@@ -0,0 +1,232 @@
+use glam::*;
+
+use crate::buffer::Buffer;
+use crate::color::Color;
+use crate::font::Font;
+use crate::math::lerpf;
+use crate::shader::ShaderUniforms;
+use crate::stencil::{BufferMask, StencilState};
+
+/// A single recorded call. Images and fonts are borrowed, so a CommandBuffer only lives as long as what it draws.
+#[derive(Clone)]
+pub enum DrawCommand<'a> {
+    ClearColor { color: Color },
+    Pixel { x: i32, y: i32, color: Color },
+    Line { x0: i32, y0: i32, x1: i32, y1: i32, color: Color },
+    Rectangle { filled: bool, x: i32, y: i32, w: i32, h: i32, color: Color },
+    Triangle { filled: bool, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, color: Color },
+    Circle { filled: bool, xc: i32, yc: i32, r: i32, color: Color },
+    Image { image: &'a Buffer, x: i32, y: i32 },
+    ImageRect { image: &'a Buffer, x: i32, y: i32, rx: i32, ry: i32, rw: i32, rh: i32 },
+    ImageMtx { image: &'a Buffer, position_x: f32, position_y: f32, rotation: f32, scale_x: f32, scale_y: f32, offset_x: f32, offset_y: f32 },
+    TriTexUvw { points: [(i32, i32); 3], uvw: [(f32, f32, f32); 3], image: &'a Buffer },
+    Print { font: &'a Font, text: String, x: i32, y: i32, newline_space: i32, wrap_width: Option<u32> },
+
+    // State changes reach every tile and don't count as primitives
+    SetUniforms { uniforms: ShaderUniforms },
+    SetStencilState { stencil_state: StencilState },
+    SetMask { mask: Option<BufferMask> },
+}
+
+impl<'a> DrawCommand<'a> {
+    /// Screen space area the command can touch as (x0, y0, x1, y1), with the end exclusive.
+    /// None for commands that reach the whole buffer.
+    pub fn bounds(&self) -> Option<(i32, i32, i32, i32)> {
+        match self {
+            DrawCommand::Pixel { x, y, .. } => Some((*x, *y, x + 1, y + 1)),
+            DrawCommand::Line { x0, y0, x1, y1, .. } => Some((*x0.min(x1), *y0.min(y1), x0.max(x1) + 1, y0.max(y1) + 1)),
+            // Outlines with a negative size still draw, back from x and y
+            DrawCommand::Rectangle { x, y, w, h, .. } => {
+                let (x0, x1) = (i32::min(*x, x + w), i32::max(*x, x + w));
+                let (y0, y1) = (i32::min(*y, y + h), i32::max(*y, y + h));
+                Some((x0, y0, x1 + 1, y1 + 1))
+            },
+            DrawCommand::Triangle { x0, y0, x1, y1, x2, y2, .. } => {
+                Some((*x0.min(x1).min(x2), *y0.min(y1).min(y2), x0.max(x1).max(x2) + 1, y0.max(y1).max(y2) + 1))
+            },
+            DrawCommand::Circle { xc, yc, r, .. } => Some((xc - r, yc - r, xc + r + 1, yc + r + 1)),
+            DrawCommand::Image { image, x, y } => Some((*x, *y, x + image.width as i32, y + image.height as i32)),
+            DrawCommand::ImageRect { x, y, rw, rh, .. } => Some((*x, *y, x + rw, y + rh)),
+            DrawCommand::ImageMtx { image, position_x, position_y, rotation, scale_x, scale_y, offset_x, offset_y } => {
+                let offset = Vec2::new(-lerpf(0.0, image.width as f32, *offset_x), -lerpf(0.0, image.height as f32, *offset_y));
+                let cmtx = Affine2::from_translation(Vec2::new(*position_x, *position_y)) * Affine2::from_angle(*rotation)
+                    * Affine2::from_scale(Vec2::new(*scale_x, *scale_y)) * Affine2::from_translation(offset);
+
+                let (w, h) = (image.width as f32, image.height as f32);
+                let corners = [Vec2::ZERO, Vec2::new(w, 0.0), Vec2::new(0.0, h), Vec2::new(w, h)].map(|c| cmtx.transform_point2(c));
+                let min = corners.iter().fold(Vec2::splat(f32::MAX), |a, c| a.min(*c));
+                let max = corners.iter().fold(Vec2::splat(f32::MIN), |a, c| a.max(*c));
+
+                // pimgmtx rounds texels up, so give it a pixel of slack on every side
+                Some((min.x.floor() as i32 - 1, min.y.floor() as i32 - 1, max.x.ceil() as i32 + 2, max.y.ceil() as i32 + 2))
+            },
+            DrawCommand::TriTexUvw { points, .. } => {
+                let xs = points.map(|p| p.0);
+                let ys = points.map(|p| p.1);
+                Some((xs[0].min(xs[1]).min(xs[2]), ys[0].min(ys[1]).min(ys[2]), xs[0].max(xs[1]).max(xs[2]) + 1, ys[0].max(ys[1]).max(ys[2]) + 1))
+            },
+            _ => None,
+        }
+    }
+
+    /// True if the command draws something, and so takes up a primitive id.
+    pub fn is_primitive(&self) -> bool {
+        !matches!(self, DrawCommand::ClearColor { .. } | DrawCommand::SetUniforms { .. } | DrawCommand::SetStencilState { .. } | DrawCommand::SetMask { .. })
+    }
+
+    /// Runs the command on a buffer whose top left corner is at ('offset_x', 'offset_y') in screen space.
+    pub fn draw(&self, buffer: &mut Buffer, offset_x: i32, offset_y: i32) {
+        let (ox, oy) = (offset_x, offset_y);
+        match self {
+            DrawCommand::ClearColor { color } => buffer.clear_color(*color),
+            DrawCommand::Pixel { x, y, color } => {
+                let params = buffer.begin_primitive();
+                buffer.ppixel_with(x - ox, y - oy, *color, params);
+            },
+            DrawCommand::Line { x0, y0, x1, y1, color } => buffer.pline(x0 - ox, y0 - oy, x1 - ox, y1 - oy, *color),
+            DrawCommand::Rectangle { filled, x, y, w, h, color } => buffer.prectangle(*filled, x - ox, y - oy, *w, *h, *color),
+            DrawCommand::Triangle { filled, x0, y0, x1, y1, x2, y2, color } => {
+                buffer.ptriangle(*filled, x0 - ox, y0 - oy, x1 - ox, y1 - oy, x2 - ox, y2 - oy, *color)
+            },
+            DrawCommand::Circle { filled, xc, yc, r, color } => buffer.pcircle(*filled, xc - ox, yc - oy, *r, *color),
+            DrawCommand::Image { image, x, y } => buffer.pimg(image, x - ox, y - oy),
+            DrawCommand::ImageRect { image, x, y, rx, ry, rw, rh } => buffer.pimgrect(image, x - ox, y - oy, *rx, *ry, *rw, *rh),
+            DrawCommand::ImageMtx { image, position_x, position_y, rotation, scale_x, scale_y, offset_x, offset_y } => {
+                buffer.pimgmtx(image, position_x - ox as f32, position_y - oy as f32, *rotation, *scale_x, *scale_y, *offset_x, *offset_y)
+            },
+            DrawCommand::TriTexUvw { points, uvw, image } => {
+                buffer.ptritex_uvw(
+                    points[0].0 - ox, points[0].1 - oy,
+                    points[1].0 - ox, points[1].1 - oy,
+                    points[2].0 - ox, points[2].1 - oy,
+                    uvw[0].0, uvw[0].1, uvw[0].2,
+                    uvw[1].0, uvw[1].1, uvw[1].2,
+                    uvw[2].0, uvw[2].1, uvw[2].2,
+                    image,
+                )
+            },
+            DrawCommand::Print { font, text, x, y, newline_space, wrap_width } => {
+                buffer.pprint(font, text.clone(), x - ox, y - oy, *newline_space, *wrap_width)
+            },
+            DrawCommand::SetUniforms { uniforms } => buffer.uniforms = *uniforms,
+            DrawCommand::SetStencilState { stencil_state } => buffer.set_stencil_state(*stencil_state),
+            DrawCommand::SetMask { mask } => buffer.set_mask(mask.clone()),
+        }
+    }
+}
+
+/// Records draw calls to be drawn later in one go with PartitionedBuffer::submit, which sorts them into
+/// the tiles they touch and draws every tile in parallel. Also works with Buffer::submit, drawing in order.
+/// Calls mirror the ones on Buffer.
+#[derive(Clone, Default)]
+pub struct CommandBuffer<'a> {
+    pub commands: Vec<DrawCommand<'a>>,
+}
+
+// Recording takes the same arguments as the Buffer functions that will later draw the commands
+#[allow(clippy::too_many_arguments)]
+impl<'a> CommandBuffer<'a> {
+    pub fn new() -> CommandBuffer<'a> {
+        CommandBuffer { commands: Vec::new() }
+    }
+
+    /// Forgets every recorded command, keeping the memory for the next frame.
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// How many of the commands draw something.
+    pub fn primitive_count(&self) -> u32 {
+        self.commands.iter().filter(|c| c.is_primitive()).count() as u32
+    }
+
+    /// The primitive id each command draws with when the next id is 'first_id'. State changes share the id of the next primitive.
+    pub fn primitive_ids(&self, first_id: u32) -> Vec<u32> {
+        let mut id = first_id;
+        self.commands.iter().map(|c| {
+            let current = id;
+            if c.is_primitive() { id = id.wrapping_add(1); }
+            current
+        }).collect()
+    }
+
+    pub fn push(&mut self, command: DrawCommand<'a>) {
+        self.commands.push(command);
+    }
+
+    pub fn clear_color(&mut self, color: Color) {
+        self.push(DrawCommand::ClearColor { color });
+    }
+
+    /// Draws a single pixel through the shader stack.
+    pub fn pset(&mut self, x: i32, y: i32, color: Color) {
+        self.push(DrawCommand::Pixel { x, y, color });
+    }
+
+    pub fn pline(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
+        self.push(DrawCommand::Line { x0, y0, x1, y1, color });
+    }
+
+    pub fn prectangle(&mut self, filled: bool, x: i32, y: i32, w: i32, h: i32, color: Color) {
+        self.push(DrawCommand::Rectangle { filled, x, y, w, h, color });
+    }
+
+    pub fn ptriangle(&mut self, filled: bool, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
+        self.push(DrawCommand::Triangle { filled, x0, y0, x1, y1, x2, y2, color });
+    }
+
+    pub fn pcircle(&mut self, filled: bool, xc: i32, yc: i32, r: i32, color: Color) {
+        self.push(DrawCommand::Circle { filled, xc, yc, r, color });
+    }
+
+    pub fn pimg(&mut self, image: &'a Buffer, x: i32, y: i32) {
+        self.push(DrawCommand::Image { image, x, y });
+    }
+
+    pub fn pimgrect(&mut self, image: &'a Buffer, x: i32, y: i32, rx: i32, ry: i32, rw: i32, rh: i32) {
+        self.push(DrawCommand::ImageRect { image, x, y, rx, ry, rw, rh });
+    }
+
+    pub fn pimgmtx(&mut self, image: &'a Buffer, position_x: f32, position_y: f32, rotation: f32, scale_x: f32, scale_y: f32, offset_x: f32, offset_y: f32) {
+        self.push(DrawCommand::ImageMtx { image, position_x, position_y, rotation, scale_x, scale_y, offset_x, offset_y });
+    }
+
+    pub fn ptritex_uvw(&mut self,
+        x0: i32, y0: i32,
+        x1: i32, y1: i32,
+        x2: i32, y2: i32,
+        u0: f32, v0: f32, w0: f32,
+        u1: f32, v1: f32, w1: f32,
+        u2: f32, v2: f32, w2: f32,
+        image: &'a Buffer) {
+        self.push(DrawCommand::TriTexUvw { points: [(x0, y0), (x1, y1), (x2, y2)], uvw: [(u0, v0, w0), (u1, v1, w1), (u2, v2, w2)], image });
+    }
+
+    /// Text isn't binned, every tile draws it and clips it to itself.
+    pub fn pprint(&mut self, font: &'a Font, text: String, x: i32, y: i32, newline_space: i32, wrap_width: Option<u32>) {
+        self.push(DrawCommand::Print { font, text, x, y, newline_space, wrap_width });
+    }
+
+    /// Changes the uniforms for every command recorded after this.
+    pub fn set_uniforms(&mut self, uniforms: ShaderUniforms) {
+        self.push(DrawCommand::SetUniforms { uniforms });
+    }
+
+    /// Changes the stencil state for every command recorded after this.
+    pub fn set_stencil_state(&mut self, stencil_state: StencilState) {
+        self.push(DrawCommand::SetStencilState { stencil_state });
+    }
+
+    /// Changes the mask for every command recorded after this.
+    pub fn set_mask(&mut self, mask: Option<BufferMask>) {
+        self.push(DrawCommand::SetMask { mask });
+    }
+}
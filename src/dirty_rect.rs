@@ -0,0 +1,204 @@
+/// A rectangle of pixels that changed, in buffer space.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl DirtyRect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> DirtyRect {
+        DirtyRect { x, y, width, height }
+    }
+
+    pub fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    pub fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// True if 'other' fits entirely inside this rect.
+    pub fn contains(&self, other: &DirtyRect) -> bool {
+        other.x >= self.x && other.y >= self.y && other.right() <= self.right() && other.bottom() <= self.bottom()
+    }
+
+    /// True if the rects overlap or meet, even if only at a corner.
+    pub fn touches(&self, other: &DirtyRect) -> bool {
+        self.x <= other.right() && other.x <= self.right() && self.y <= other.bottom() && other.y <= self.bottom()
+    }
+
+    /// True if at least one pixel is in both rects.
+    pub fn overlaps(&self, other: &DirtyRect) -> bool {
+        self.x < other.right() && other.x < self.right() && self.y < other.bottom() && other.y < self.bottom()
+    }
+
+    /// True if the rects sit side by side along the whole of an edge, so their union covers exactly their pixels.
+    pub fn shares_edge(&self, other: &DirtyRect) -> bool {
+        let beside = (self.right() == other.x || other.right() == self.x) && self.y == other.y && self.height == other.height;
+        let stacked = (self.bottom() == other.y || other.bottom() == self.y) && self.x == other.x && self.width == other.width;
+        beside || stacked
+    }
+
+    /// The smallest rect covering both.
+    pub fn union(&self, other: &DirtyRect) -> DirtyRect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        DirtyRect::new(x, y, self.right().max(other.right()) - x, self.bottom().max(other.bottom()) - y)
+    }
+}
+
+/// Every rect drawn to since tracking started or the region was last cleared, merged as they come in.
+/// Rects that overlap or share a full edge are always joined, so the rects never overlap. Joining overlapping
+/// rects can cover pixels neither did, but rects that only meet at a corner or along part of an edge are kept
+/// apart. Once there are more than 'max_rects' the pair that wastes the fewest pixels when joined is merged,
+/// so the list stays short enough to hand to a presenter.
+#[derive(Debug, Clone)]
+pub struct DirtyRegion {
+    pub rects: Vec<DirtyRect>,
+    pub max_rects: usize,
+}
+
+impl Default for DirtyRegion {
+    fn default() -> Self {
+        DirtyRegion::new(DirtyRegion::MAX_RECTS_DEFAULT)
+    }
+}
+
+impl DirtyRegion {
+    pub const MAX_RECTS_DEFAULT: usize = 16;
+
+    pub fn new(max_rects: usize) -> DirtyRegion {
+        DirtyRegion { rects: Vec::new(), max_rects: max_rects.max(1) }
+    }
+
+    /// Adds a rect, merging it into any it overlaps or shares a full edge with.
+    pub fn add(&mut self, rect: DirtyRect) {
+        if rect.is_empty() { return; }
+
+        // Spans of the same primitive usually land inside the last rect
+        if let Some(last) = self.rects.last() {
+            if last.contains(&rect) { return; }
+        }
+
+        self.insert_merged(rect);
+
+        while self.rects.len() > self.max_rects {
+            let (mut best, mut best_waste) = ((0, 1), usize::MAX);
+            for i in 0..self.rects.len() {
+                for j in i + 1..self.rects.len() {
+                    let (a, b) = (self.rects[i], self.rects[j]);
+                    let waste = a.union(&b).area().saturating_sub(a.area() + b.area());
+                    if waste < best_waste {
+                        best = (i, j);
+                        best_waste = waste;
+                    }
+                }
+            }
+
+            let b = self.rects.swap_remove(best.1);
+            let a = self.rects.swap_remove(best.0);
+            self.insert_merged(a.union(&b));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rects.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    /// Pixels covered by the region, which can be more than were drawn to as merging rounds up to rects.
+    /// Rects never overlap, so nothing is counted twice.
+    pub fn area(&self) -> usize {
+        self.rects.iter().map(|r| r.area()).sum()
+    }
+
+    // Grows 'rect' over every rect it overlaps or shares an edge with until there are none, then adds it
+    fn insert_merged(&mut self, rect: DirtyRect) {
+        let mut rect = rect;
+        while let Some(i) = self.rects.iter().position(|r| r.overlaps(&rect) || r.shares_edge(&rect)) {
+            rect = rect.union(&self.rects.swap_remove(i));
+        }
+        self.rects.push(rect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region_of(rects: &[DirtyRect], max_rects: usize) -> DirtyRegion {
+        let mut region = DirtyRegion::new(max_rects);
+        for rect in rects { region.add(*rect); }
+        region
+    }
+
+    #[test]
+    fn overlapping_rects_merge() {
+        let region = region_of(&[DirtyRect::new(0, 0, 10, 10), DirtyRect::new(5, 5, 10, 10)], 16);
+        assert_eq!(region.rects, vec![DirtyRect::new(0, 0, 15, 15)]);
+    }
+
+    #[test]
+    fn rects_sharing_a_full_edge_merge() {
+        let region = region_of(&[DirtyRect::new(0, 0, 10, 4), DirtyRect::new(10, 0, 6, 4), DirtyRect::new(0, 4, 16, 2)], 16);
+        assert_eq!(region.rects, vec![DirtyRect::new(0, 0, 16, 6)]);
+    }
+
+    #[test]
+    fn rects_touching_at_a_corner_or_part_of_an_edge_stay_apart() {
+        let corner = region_of(&[DirtyRect::new(0, 0, 10, 10), DirtyRect::new(10, 10, 10, 10)], 16);
+        assert_eq!(corner.rects.len(), 2);
+        assert_eq!(corner.area(), 200);
+
+        let partial_edge = region_of(&[DirtyRect::new(0, 0, 10, 10), DirtyRect::new(10, 5, 10, 10)], 16);
+        assert_eq!(partial_edge.rects.len(), 2);
+    }
+
+    #[test]
+    fn max_rects_caps_the_list() {
+        let rects: Vec<DirtyRect> = (0..40).map(|i| DirtyRect::new(i * 20, (i % 3) * 30, 4, 4)).collect();
+        let region = region_of(&rects, 5);
+
+        assert!(region.rects.len() <= 5);
+        for rect in &rects {
+            assert!(region.rects.iter().any(|r| r.contains(rect)), "{:?} was lost", rect);
+        }
+    }
+
+    #[test]
+    fn area_never_counts_a_pixel_twice() {
+        let mut seed: u32 = 12345;
+        let mut next = |limit: usize| -> usize {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as usize % limit
+        };
+
+        for max_rects in [1, 4, 16] {
+            let rects: Vec<DirtyRect> = (0..60).map(|_| DirtyRect::new(next(90), next(90), next(12) + 1, next(12) + 1)).collect();
+            let region = region_of(&rects, max_rects);
+
+            let mut hits = vec![0u8; 128 * 128];
+            for rect in &region.rects {
+                for y in rect.y..rect.bottom() {
+                    for x in rect.x..rect.right() { hits[y * 128 + x] += 1; }
+                }
+            }
+
+            assert!(hits.iter().all(|h| *h <= 1));
+            assert_eq!(region.area(), hits.iter().filter(|h| **h == 1).count());
+        }
+    }
+}
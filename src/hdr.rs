@@ -0,0 +1,202 @@
+use rayon::prelude::*;
+
+use crate::buffer::Buffer;
+use crate::color::Color;
+use crate::pixel_format::PixelFormat;
+
+/// How HDR values above 1.0 are squeezed back into a displayable 0.0 to 1.0 when resolving a frame.
+/// Every operator is applied to each channel after it's multiplied by the exposure.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ToneMap {
+    /// Cuts off anything above 1.0. Only the exposure changes the image.
+    Clamp,
+    /// 1 - e^-x. Soft highlights that never quite reach white.
+    Exposure,
+    /// x / (1 + x). Keeps darks almost untouched and rolls bright values off slowly.
+    Reinhard,
+    /// Like Reinhard, but values at 'white' and above map to full white.
+    ReinhardExtended { white: f32 },
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve. Punchier contrast with a filmic shoulder.
+    AcesFilmic,
+}
+
+impl ToneMap {
+    /// Maps one channel, already multiplied by the exposure.
+    pub fn map(&self, value: f32) -> f32 {
+        let x = value.max(0.0);
+        let mapped = match *self {
+            ToneMap::Clamp => x,
+            ToneMap::Exposure => 1.0 - (-x).exp(),
+            ToneMap::Reinhard => x / (1.0 + x),
+            ToneMap::ReinhardExtended { white } => x * (1.0 + x / (white * white).max(f32::EPSILON)) / (1.0 + x),
+            ToneMap::AcesFilmic => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        };
+        mapped.clamp(0.0, 1.0)
+    }
+}
+
+impl Buffer {
+    /// Makes a buffer that stores RgbaF32, where light can keep adding up past full brightness.
+    /// Draw into it as usual, add light with padd_hdr, pimg_add and plight, then resolve it with tone_map_into.
+    pub fn new_hdr(width: usize, height: usize) -> Buffer {
+        Buffer::new_with_format(width, height, PixelFormat::RgbaF32)
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        self.format == PixelFormat::RgbaF32
+    }
+
+    /// Reads a pixel as floats where 1.0 is full. Zero outside the buffer.
+    pub fn pget_hdr(&self, x: i32, y: i32) -> [f32; 4] {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 { return [0.0; 4]; }
+
+        let stride = self.format.bytes_per_pixel();
+        let idx = (y as usize * self.width + x as usize) * stride;
+        self.format.decode_f32(&self.color[idx..idx + stride])
+    }
+
+    /// Writes a pixel as floats. Buffers that aren't HDR clamp it to 0.0 to 1.0.
+    /// Like blits, this skips the shader stack and the stencil.
+    pub fn pset_hdr(&mut self, x: i32, y: i32, value: [f32; 4]) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 { return; }
+
+        let stride = self.format.bytes_per_pixel();
+        let idx = (y as usize * self.width + x as usize) * stride;
+        self.format.encode_f32(value, &mut self.color[idx..idx + stride]);
+        self.mark_dirty(x, y, 1, 1);
+    }
+
+    /// Adds light to a pixel's red, green and blue, keeping its alpha.
+    pub fn padd_hdr(&mut self, x: i32, y: i32, light: [f32; 3]) {
+        let mut value = self.pget_hdr(x, y);
+        value[0] += light[0];
+        value[1] += light[1];
+        value[2] += light[2];
+        self.pset_hdr(x, y, value);
+    }
+
+    /// Adds an image on top of what's there, scaled by its alpha and 'intensity'. The usual way to draw light sprites.
+    pub fn pimg_add(&mut self, image: &Buffer, x: i32, y: i32, intensity: f32) {
+        let x0 = i32::clamp(x, 0, self.width as i32);
+        let x1 = i32::clamp(x + image.width as i32, 0, self.width as i32);
+        let y0 = i32::clamp(y, 0, self.height as i32);
+        let y1 = i32::clamp(y + image.height as i32, 0, self.height as i32);
+        if x1 <= x0 || y1 <= y0 { return; }
+
+        let (format, stride, width) = (self.format, self.format.bytes_per_pixel(), self.width);
+        let (image_stride, image_width) = (image.format.bytes_per_pixel(), image.width);
+
+        self.color.par_chunks_exact_mut(width * stride).enumerate().for_each(|(py, row)| {
+            let py = py as i32;
+            if py < y0 || py >= y1 { return; }
+
+            for px in x0..x1 {
+                let from = ((py - y) as usize * image_width + (px - x) as usize) * image_stride;
+                let light = image.format.decode_f32(&image.color[from..from + image_stride]);
+                let scale = light[3] * intensity;
+                if scale <= 0.0 { continue; }
+
+                let pixel = &mut row[px as usize * stride..(px as usize + 1) * stride];
+                let mut value = format.decode_f32(pixel);
+                value[0] += light[0] * scale;
+                value[1] += light[1] * scale;
+                value[2] += light[2] * scale;
+                format.encode_f32(value, pixel);
+            }
+        });
+        self.mark_dirty(x0, y0, x1 - x0, y1 - y0);
+    }
+
+    /// Adds a round light of 'color' times 'intensity' in the middle, fading smoothly to nothing at 'radius'.
+    pub fn plight(&mut self, xc: i32, yc: i32, radius: i32, color: Color, intensity: f32) {
+        if radius <= 0 { return; }
+
+        let x0 = i32::clamp(xc - radius, 0, self.width as i32);
+        let x1 = i32::clamp(xc + radius + 1, 0, self.width as i32);
+        let y0 = i32::clamp(yc - radius, 0, self.height as i32);
+        let y1 = i32::clamp(yc + radius + 1, 0, self.height as i32);
+        if x1 <= x0 || y1 <= y0 { return; }
+
+        let (format, stride, width) = (self.format, self.format.bytes_per_pixel(), self.width);
+        let light = [color.r as f32 / 255.0 * intensity, color.g as f32 / 255.0 * intensity, color.b as f32 / 255.0 * intensity];
+        let radius = radius as f32;
+
+        self.color.par_chunks_exact_mut(width * stride).enumerate().for_each(|(py, row)| {
+            let py = py as i32;
+            if py < y0 || py >= y1 { return; }
+
+            for px in x0..x1 {
+                let (dx, dy) = ((px - xc) as f32, (py - yc) as f32);
+                let falloff = 1.0 - (dx * dx + dy * dy).sqrt() / radius;
+                if falloff <= 0.0 { continue; }
+                let falloff = falloff * falloff;
+
+                let pixel = &mut row[px as usize * stride..(px as usize + 1) * stride];
+                let mut value = format.decode_f32(pixel);
+                value[0] += light[0] * falloff;
+                value[1] += light[1] * falloff;
+                value[2] += light[2] * falloff;
+                format.encode_f32(value, pixel);
+            }
+        });
+        self.mark_dirty(x0, y0, x1 - x0, y1 - y0);
+    }
+
+    /// Resolves the buffer into a new Rgba8 buffer the same size. See tone_map_into.
+    pub fn tone_mapped(&self, tone_map: ToneMap, exposure: f32) -> Buffer {
+        let mut target = Buffer::new(self.width, self.height);
+        self.tone_map_into(&mut target, tone_map, exposure);
+        target
+    }
+
+    /// Resolves the buffer into 'target', multiplying every channel by 'exposure' and mapping it with 'tone_map'.
+    /// Alpha is only clamped. 'target' is resized to match if it needs to be and keeps its own pixel format.
+    pub fn tone_map_into(&self, target: &mut Buffer, tone_map: ToneMap, exposure: f32) {
+        if target.width != self.width || target.height != self.height {
+            target.resize(self.width, self.height);
+        }
+
+        let (format, target_format) = (self.format, target.format);
+        target.color.par_chunks_exact_mut(target_format.bytes_per_pixel()).zip(self.color.par_chunks_exact(format.bytes_per_pixel())).for_each(|(out, pixel)| {
+            let value = format.decode_f32(pixel);
+            let mapped = [
+                tone_map.map(value[0] * exposure),
+                tone_map.map(value[1] * exposure),
+                tone_map.map(value[2] * exposure),
+                value[3].clamp(0.0, 1.0),
+            ];
+            target_format.encode_f32(mapped, out);
+        });
+        target.mark_all_dirty();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn light_above_one_survives_tint_composite_and_tone_map() {
+        let mut hdr = Buffer::new_hdr(2, 1);
+        hdr.pset_hdr(0, 0, [4.0, 2.0, 0.5, 1.0]);
+        hdr.pset_hdr(1, 0, [1.0, 1.0, 1.0, 1.0]);
+
+        hdr.tint_buffer(Color::new(255, 255, 128, 255));
+        let tinted = hdr.pget_hdr(0, 0);
+        assert_eq!((tinted[0], tinted[1], tinted[3]), (4.0, 2.0, 1.0));
+        assert!((tinted[2] - 0.5 * 128.0 / 255.0).abs() < 1e-6);
+
+        let mut light = Buffer::new_hdr(2, 1);
+        light.pset_hdr(0, 0, [0.5, 2.0, 1.0, 1.0]);
+        light.pset_hdr(1, 0, [3.0, 3.0, 3.0, 1.0]);
+        hdr.pcomposite_multiply(&light);
+        assert_eq!(hdr.pget_hdr(0, 0)[..2], [2.0, 4.0]);
+        assert_eq!(hdr.pget_hdr(1, 0)[..2], [3.0, 3.0]);
+
+        let mut target = Buffer::new(2, 1);
+        hdr.tone_map_into(&mut target, ToneMap::Reinhard, 1.0);
+        let white = (ToneMap::Reinhard.map(1.0) * 255.0).round() as u8;
+        assert_eq!(target.pget(0, 0), Color::new(170, 204, (ToneMap::Reinhard.map(0.5 * 128.0 / 255.0) * 255.0).round() as u8, 255));
+        assert!(target.pget(1, 0).r > white);
+    }
+}
@@ -10,15 +10,31 @@ pub extern crate glam;
 
 // Core
 pub mod buffer;
+pub mod pixel_format;
 pub mod partitioned_buffer;
+pub mod canvas;
 pub mod shader;
+pub mod post_process;
+pub mod hdr;
+pub mod stencil;
+pub mod dirty_rect;
+pub mod command_buffer;
+pub mod sprite_effect;
 
 // Assets
 pub mod font;
+pub mod sprite;
+pub mod aseprite;
+pub mod atlas;
+pub mod tilemap;
+pub mod tiled;
 
 // Utilities
 pub mod math;
 pub mod color;
+pub mod color_space;
+pub mod palette;
+pub mod simd;
 
 // Math 3D;
 pub mod three_dee;
@@ -76,6 +76,39 @@ pub fn point_in_triangle(px: i32, py: i32, x0: i32, y0: i32, x1: i32, y1: i32, x
 	is_inside
 }
 
+/// First and last+1 x of the pixels inside a triangle on row 'py', between 'xmin' and 'xmax'.
+/// Solved from where the row crosses each edge, giving the same pixels as testing each with point_in_triangle.
+pub fn triangle_row_extent(py: i32, xmin: i32, xmax: i32, a: (i32, i32), b: (i32, i32), c: (i32, i32)) -> Option<(i32, i32)> {
+	let ((x0, y0), (x1, y1), (x2, y2)) = (a, b, c);
+	let orientation = sign3i(x2, y2, x0, y0, x1, y1).signum() as i64;
+
+	// Flat triangles have no inside to solve for, so fall back to testing their pixels
+	if orientation == 0 {
+		let start = (xmin..xmax).find(|px| point_in_triangle(*px, py, x0, y0, x1, y1, x2, y2))?;
+		let end = (start..xmax).rev().find(|px| point_in_triangle(*px, py, x0, y0, x1, y1, x2, y2)).unwrap_or(start);
+		return Some((start, end + 1));
+	}
+
+	// Each edge's sign3i is a * px + b along the row, and the inside is where all of them share the triangle's sign
+	let (mut start, mut end) = (xmin as i64, xmax as i64 - 1);
+	for (ax, ay, bx, by) in [(x0, y0, x1, y1), (x1, y1, x2, y2), (x2, y2, x0, y0)] {
+		let (ax, ay, bx, by, py) = (ax as i64, ay as i64, bx as i64, by as i64, py as i64);
+		let a = (ay - by) * orientation;
+		let b = (-bx * (ay - by) - (ax - bx) * (py - by)) * orientation;
+
+		if a > 0 {
+			start = start.max(-b.div_euclid(a));
+		} else if a < 0 {
+			end = end.min(b.div_euclid(-a));
+		} else if b < 0 {
+			return None;
+		}
+	}
+
+	if start > end { return None; }
+	Some((start as i32, end as i32 + 1))
+}
+
 pub fn barycentric(p: (f32, f32), a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> (f32, f32, f32) {
     let v0 = (b.0 - a.0, b.1 - a.1);
 	let v1 = (c.0 - a.0, c.1 - a.1);
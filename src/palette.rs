@@ -0,0 +1,330 @@
+use std::path::Path;
+
+use crate::color::Color;
+
+/// A named, ordered set of colors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub colors: Vec<Color>,
+}
+
+impl Palette {
+    pub fn new(name: &str, colors: Vec<Color>) -> Palette {
+        Palette { name: name.to_string(), colors }
+    }
+
+    /// The 16 colors of the PICO-8 fantasy console.
+    pub fn pico8() -> Palette {
+        Palette::from_hex_list("PICO-8", &[
+            0x000000, 0x1D2B53, 0x7E2553, 0x008751, 0xAB5236, 0x5F574F, 0xC2C3C7, 0xFFF1E8,
+            0xFF004D, 0xFFA300, 0xFFEC27, 0x00E436, 0x29ADFF, 0x83769C, 0xFF77A8, 0xFFCCAA,
+        ])
+    }
+
+    /// All 64 entries of the NES's 2C02 palette, in hardware order so NES palette indices can be used directly.
+    /// Unused entries are black.
+    pub fn nes() -> Palette {
+        Palette::from_hex_list("NES", &[
+            0x7C7C7C, 0x0000FC, 0x0000BC, 0x4428BC, 0x940084, 0xA80020, 0xA81000, 0x881400,
+            0x503000, 0x007800, 0x006800, 0x005800, 0x004058, 0x000000, 0x000000, 0x000000,
+            0xBCBCBC, 0x0078F8, 0x0058F8, 0x6844FC, 0xD800CC, 0xE40058, 0xF83800, 0xE45C10,
+            0xAC7C00, 0x00B800, 0x00A800, 0x00A844, 0x008888, 0x000000, 0x000000, 0x000000,
+            0xF8F8F8, 0x3CBCFC, 0x6888FC, 0x9878F8, 0xF878F8, 0xF85898, 0xF87858, 0xFCA044,
+            0xF8B800, 0xB8F818, 0x58D854, 0x58F898, 0x00E8D8, 0x787878, 0x000000, 0x000000,
+            0xFCFCFC, 0xA4E4FC, 0xB8B8F8, 0xD8B8F8, 0xF8B8F8, 0xF8A4C0, 0xF0D0B0, 0xFCE0A8,
+            0xF8D878, 0xD8F878, 0xB8F8B8, 0xB8F8D8, 0x00FCFC, 0xF8D8F8, 0x000000, 0x000000,
+        ])
+    }
+
+    /// The four greens of the original Game Boy, darkest first.
+    pub fn gameboy() -> Palette {
+        Palette::from_hex_list("Game Boy", &[0x0F380F, 0x306230, 0x8BAC0F, 0x9BBC0F])
+    }
+
+    /// DawnBringer's 32 color palette.
+    pub fn db32() -> Palette {
+        Palette::from_hex_list("DB32", &[
+            0x000000, 0x222034, 0x45283C, 0x663931, 0x8F563B, 0xDF7126, 0xD9A066, 0xEEC39A,
+            0xFBF236, 0x99E550, 0x6ABE30, 0x37946E, 0x4B692F, 0x524B24, 0x323C39, 0x3F3F74,
+            0x306082, 0x5B6EE1, 0x639BFF, 0x5FCDE4, 0xCBDBFC, 0xFFFFFF, 0x9BADB7, 0x847E87,
+            0x696A6A, 0x595652, 0x76428A, 0xAC3232, 0xD95763, 0xD77BBA, 0x8F974A, 0x8A6F30,
+        ])
+    }
+
+    /// Endesga's 32 color palette.
+    pub fn endesga32() -> Palette {
+        Palette::from_hex_list("Endesga 32", &[
+            0xBE4A2F, 0xD77643, 0xEAD4AA, 0xE4A672, 0xB86F50, 0x733E39, 0x3E2731, 0xA22633,
+            0xE43B44, 0xF77622, 0xFEAE34, 0xFEE761, 0x63C74D, 0x3E8948, 0x265C42, 0x193C3E,
+            0x124E89, 0x0099DB, 0x2CE8F5, 0xFFFFFF, 0xC0CBDC, 0x8B9BB4, 0x5A6988, 0x3A4466,
+            0x262B44, 0x181425, 0xFF0044, 0x68386C, 0xB55088, 0xF6757A, 0xE8B796, 0xC28569,
+        ])
+    }
+
+    /// Loads a JASC .pal, GIMP .gpl or .hex palette, picked by the file's extension.
+    /// The palette is named after the file unless the file names it.
+    pub fn new_from_file(path_to: &str) -> Result<Palette, String> {
+        let text = match std::fs::read_to_string(path_to) {
+            Ok(text) => text,
+            Err(reason) => { return Err(format!("ERROR - PALETTE: Could not read {} | {}", path_to, reason)); }
+        };
+
+        let path = Path::new(path_to);
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+        let mut palette = match extension.as_str() {
+            "pal" => Palette::new_from_pal(&text)?,
+            "gpl" => Palette::new_from_gpl(&text)?,
+            "hex" => Palette::new_from_hex(&text)?,
+            _ => { return Err(format!("ERROR - PALETTE: Unknown palette format .{}, expected .pal, .gpl or .hex", extension)); }
+        };
+
+        if palette.name.is_empty() {
+            palette.name = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        }
+        Ok(palette)
+    }
+
+    /// Parses a JASC palette: a 'JASC-PAL' line, a version line, a count line, then one 'r g b' line per color.
+    pub fn new_from_pal(text: &str) -> Result<Palette, String> {
+        let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        if lines.next() != Some("JASC-PAL") {
+            return Err("ERROR - PALETTE: Not a JASC palette, missing the JASC-PAL header.".to_string());
+        }
+
+        let _version = lines.next();
+        let count: usize = match lines.next().map(|l| l.parse()) {
+            Some(Ok(count)) => count,
+            _ => { return Err("ERROR - PALETTE: JASC palette has no color count.".to_string()); }
+        };
+
+        let colors = lines.take(count).map(parse_rgb).collect::<Result<Vec<Color>, String>>()?;
+        if colors.len() != count {
+            return Err(format!("ERROR - PALETTE: JASC palette says it has {} colors but only has {}.", count, colors.len()));
+        }
+        Ok(Palette::new("", colors))
+    }
+
+    /// Parses a GIMP palette: a 'GIMP Palette' line, optional 'Name:' and 'Columns:' lines and '#' comments,
+    /// then one 'r g b' line per color, each optionally followed by the color's name.
+    pub fn new_from_gpl(text: &str) -> Result<Palette, String> {
+        let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        if lines.next() != Some("GIMP Palette") {
+            return Err("ERROR - PALETTE: Not a GIMP palette, missing the GIMP Palette header.".to_string());
+        }
+
+        let mut palette = Palette::new("", Vec::new());
+        for line in lines {
+            if line.starts_with('#') || line.starts_with("Columns:") { continue; }
+            if let Some(name) = line.strip_prefix("Name:") {
+                palette.name = name.trim().to_string();
+                continue;
+            }
+            palette.colors.push(parse_rgb(line)?);
+        }
+        Ok(palette)
+    }
+
+    /// Parses one 'rrggbb' hex color per line, the format Lospec exports.
+    pub fn new_from_hex(text: &str) -> Result<Palette, String> {
+        let colors = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).map(Color::from_hex).collect::<Result<Vec<Color>, String>>()?;
+        Ok(Palette::new("", colors))
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.colors.is_empty()
+    }
+
+    /// Color at 'index', wrapping around past the end.
+    pub fn get(&self, index: usize) -> Color {
+        if self.colors.is_empty() { return Color::TRANSPARENT; }
+        self.colors[index % self.colors.len()]
+    }
+
+    /// Index of the palette color that looks closest to 'color', ignoring alpha. 0 if the palette is empty.
+    /// Uses the 'redmean' weighted distance, cheap enough to run on every pixel of an image.
+    pub fn nearest_index(&self, color: Color) -> usize {
+        let mut best = (0, i32::MAX);
+        for (i, candidate) in self.colors.iter().enumerate() {
+            let distance = redmean_distance(color, *candidate);
+            if distance < best.1 { best = (i, distance); }
+        }
+        best.0
+    }
+
+    /// The palette color that looks closest to 'color', keeping the alpha of 'color'.
+    pub fn nearest(&self, color: Color) -> Color {
+        self.get(self.nearest_index(color)).with_alpha(color.a)
+    }
+
+    /// Writes the palette as a GIMP palette.
+    pub fn to_gpl(&self) -> String {
+        let mut text = format!("GIMP Palette\nName: {}\n#\n", self.name);
+        for color in &self.colors {
+            text += &format!("{:3} {:3} {:3}\t{}\n", color.r, color.g, color.b, &color.to_hex()[1..]);
+        }
+        text
+    }
+
+    fn from_hex_list(name: &str, hex: &[u32]) -> Palette {
+        Palette::new(name, hex.iter().map(|h| Color::new((h >> 16) as u8, (h >> 8) as u8, *h as u8, 255)).collect())
+    }
+}
+
+/// How a Gradient blends between its stops.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GradientInterpolation {
+    /// Holds each stop's color until the next stop, for banded ramps.
+    Step,
+    /// Mixes the sRGB bytes. See Color::lerp_rgb.
+    Rgb,
+    /// Mixes in linear light. See Color::lerp_linear.
+    Linear,
+    /// Mixes in OKLab, the most even looking. See Color::lerp_oklab.
+    OkLab,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GradientStop {
+    pub position: f32,
+    pub color: Color,
+}
+
+/// Colors placed along 0.0 to 1.0 that can be sampled anywhere between. Samples before the first stop
+/// or after the last one take that stop's color.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    // Kept sorted by position, so only add_stop can change it
+    stops: Vec<GradientStop>,
+    pub interpolation: GradientInterpolation,
+}
+
+impl Gradient {
+    pub fn new(interpolation: GradientInterpolation) -> Gradient {
+        Gradient { stops: Vec::new(), interpolation }
+    }
+
+    /// Spreads 'colors' evenly from 0.0 to 1.0.
+    pub fn new_from_colors(colors: &[Color], interpolation: GradientInterpolation) -> Gradient {
+        let mut gradient = Gradient::new(interpolation);
+        let last = (colors.len().max(2) - 1) as f32;
+        for (i, color) in colors.iter().enumerate() {
+            gradient.add_stop(i as f32 / last, *color);
+        }
+        gradient
+    }
+
+    /// The stops, in order of position.
+    pub fn stops(&self) -> &[GradientStop] {
+        &self.stops
+    }
+
+    /// Adds a stop, keeping the stops in order. Stops at the same position make a hard edge.
+    /// Stops at a NaN position are ignored.
+    pub fn add_stop(&mut self, position: f32, color: Color) {
+        if position.is_nan() { return; }
+        let index = self.stops.partition_point(|s| s.position <= position);
+        self.stops.insert(index, GradientStop { position, color });
+    }
+
+    /// The color at 't'. A NaN 't' gives the first stop's color.
+    pub fn sample(&self, t: f32) -> Color {
+        let (first, last) = match (self.stops.first(), self.stops.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => { return Color::TRANSPARENT; }
+        };
+        if t.is_nan() || t <= first.position { return first.color; }
+        if t >= last.position { return last.color; }
+
+        let next = self.stops.partition_point(|s| s.position <= t);
+        let (a, b) = (self.stops[next - 1], self.stops[next]);
+        let local = (t - a.position) / (b.position - a.position);
+
+        match self.interpolation {
+            GradientInterpolation::Step => a.color,
+            GradientInterpolation::Rgb => {
+                let alpha = (a.color.a as f32 + (b.color.a as f32 - a.color.a as f32) * local).round() as u8;
+                Color { a: alpha, ..Color::lerp_rgb(a.color, b.color, local) }
+            },
+            GradientInterpolation::Linear => Color::lerp_linear(a.color, b.color, local),
+            GradientInterpolation::OkLab => Color::lerp_oklab(a.color, b.color, local),
+        }
+    }
+
+    /// Samples 'count' evenly spaced colors from start to end, like a lookup table for ShaderGradientMap.
+    pub fn to_colors(&self, count: usize) -> Vec<Color> {
+        let last = (count.max(2) - 1) as f32;
+        (0..count).map(|i| self.sample(i as f32 / last)).collect()
+    }
+}
+
+// Reads the first three whitespace separated numbers of a line as a color
+fn parse_rgb(line: &str) -> Result<Color, String> {
+    let channels: Vec<u8> = line.split_whitespace().take(3).map(|v| v.parse::<u8>()).collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("ERROR - PALETTE: '{}' is not an 'r g b' color", line))?;
+
+    if channels.len() != 3 {
+        return Err(format!("ERROR - PALETTE: '{}' is not an 'r g b' color", line));
+    }
+    Ok(Color::new(channels[0], channels[1], channels[2], 255))
+}
+
+// Squared distance weighted by how bright the reds are, a close and cheap match for how different colors look
+fn redmean_distance(a: Color, b: Color) -> i32 {
+    let mean_r = (a.r as i32 + b.r as i32) / 2;
+    let (dr, dg, db) = (a.r as i32 - b.r as i32, a.g as i32 - b.g as i32, a.b as i32 - b.b as i32);
+    (((512 + mean_r) * dr * dr) >> 8) + 4 * dg * dg + (((767 - mean_r) * db * db) >> 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gradient_sample_handles_any_t() {
+        let gradient = Gradient::new_from_colors(&[Color::BLACK, Color::WHITE], GradientInterpolation::Rgb);
+        assert_eq!(gradient.sample(f32::NAN), Color::BLACK);
+        assert_eq!(gradient.sample(f32::NEG_INFINITY), Color::BLACK);
+        assert_eq!(gradient.sample(f32::INFINITY), Color::WHITE);
+        assert_eq!(gradient.sample(0.5), Color::new(128, 128, 128, 255));
+        assert_eq!(Gradient::new(GradientInterpolation::Rgb).sample(0.5), Color::TRANSPARENT);
+    }
+
+    #[test]
+    fn gradient_stops_stay_sorted() {
+        let mut gradient = Gradient::new(GradientInterpolation::Step);
+        gradient.add_stop(0.75, Color::BLUE);
+        gradient.add_stop(0.25, Color::RED);
+        gradient.add_stop(f32::NAN, Color::GREEN);
+        gradient.add_stop(0.5, Color::WHITE);
+
+        let positions: Vec<f32> = gradient.stops().iter().map(|s| s.position).collect();
+        assert_eq!(positions, vec![0.25, 0.5, 0.75]);
+        assert_eq!(gradient.sample(0.6), Color::WHITE);
+    }
+
+    #[test]
+    fn palette_files_parse() {
+        let pal = Palette::new_from_pal("JASC-PAL\n0100\n2\n255 0 0\n0 0 255\n").unwrap();
+        assert_eq!(pal.colors, vec![Color::RED, Color::BLUE]);
+        assert!(Palette::new_from_pal("JASC-PAL\n0100\n3\n255 0 0\n").is_err());
+
+        let gpl = Palette::pico8().to_gpl();
+        assert_eq!(Palette::new_from_gpl(&gpl).unwrap(), Palette::pico8());
+        assert!(Palette::new_from_gpl("GIMP Palette\n300 0 0\n").is_err());
+
+        assert_eq!(Palette::new_from_hex("ff0000\n\n0000ff\n").unwrap().colors, vec![Color::RED, Color::BLUE]);
+    }
+
+    #[test]
+    fn nearest_picks_the_closest_color() {
+        let palette = Palette::gameboy();
+        assert_eq!(palette.nearest_index(Color::BLACK), 0);
+        assert_eq!(palette.nearest_index(Color::WHITE), 3);
+        assert_eq!(palette.nearest(Color::new(0, 0, 0, 10)), Color::new(0x0F, 0x38, 0x0F, 10));
+    }
+}
@@ -1,12 +1,25 @@
 use crate::buffer::*;
 use crate::color::*;
+use crate::font::Font;
 use crate::shader;
 use crate::shader::Shader;
+use crate::shader::{ShaderContext, ShaderParams, ShaderUniforms, ShaderVaryings};
+use crate::post_process::PostProcess;
+use crate::command_buffer::{CommandBuffer, DrawCommand};
+use crate::stencil::{BufferMask, StencilState};
+use crate::dirty_rect::{DirtyRect, DirtyRegion};
+use crate::pixel_format::PixelFormat;
 
 use std::rc::Rc;
 use std::sync::Arc;
-use std::thread::*;
+use std::time::Instant;
 
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+/// How a PartitionedBuffer is cut into partitions. Every scheme covers the whole buffer, partitions along the
+/// right and bottom edges grow or shrink to take up whatever doesn't divide evenly.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PartitionScheme {
 	Full,
 	Split2x1,
@@ -18,6 +31,52 @@ pub enum PartitionScheme {
 	Split4x4,
 	Split5x5,
 	Split8x8,
+
+	/// Tiles of a fixed size, with smaller tiles along the right and bottom edges.
+	Tiles { width: usize, height: usize },
+
+	/// 'partitions' tiles that are re-cut every begin_frame so each one took about as long to draw last frame.
+	/// Starts cut evenly by area until there's a frame to measure.
+	Adaptive { partitions: usize },
+}
+
+/// Timing of the parallel work a PartitionedBuffer did over one frame, in seconds.
+/// Frames are split by PartitionedBuffer::begin_frame, which moves these into last_frame_stats.
+#[derive(Debug, Clone, Default)]
+pub struct PartitionStats {
+	/// Number of parallel draws run.
+	pub parallel_calls: u32,
+	/// Time from the start of each parallel draw until the last partition finished, added up.
+	pub parallel_time: f64,
+	/// Time spent copying partitions back into the buffer.
+	pub resolve_time: f64,
+	/// Time each partition spent drawing, by partition index.
+	pub partition_times: Vec<f64>,
+	/// Threads in the pool the work ran on.
+	pub threads: usize,
+}
+
+impl PartitionStats {
+	/// Time every partition spent drawing, added up.
+	pub fn busy_time(&self) -> f64 {
+		self.partition_times.iter().sum()
+	}
+
+	/// How much of the pool's time went to drawing, from 0.0 to 1.0. Low values mean threads sat idle
+	/// waiting on the slowest partition, or there are more threads than partitions.
+	pub fn efficiency(&self) -> f64 {
+		if self.parallel_time <= 0.0 || self.threads == 0 { return 0.0; }
+		(self.busy_time() / (self.parallel_time * self.threads as f64)).min(1.0)
+	}
+
+	/// The slowest partition's time over the average. 1.0 is perfectly balanced.
+	pub fn imbalance(&self) -> f64 {
+		let busy = self.busy_time();
+		if busy <= 0.0 { return 1.0; }
+
+		let slowest = self.partition_times.iter().cloned().fold(0.0, f64::max);
+		slowest / (busy / self.partition_times.len() as f64)
+	}
 }
 
 pub struct PartitionedBuffer {
@@ -25,6 +84,13 @@ pub struct PartitionedBuffer {
 	pub partitions: Vec<Buffer>,
 	pub scheme: PartitionScheme,
 	pub threshold: u32,
+
+	/// Threads partitions are drawn on. Kept for the life of the buffer so draws don't pay for spawning threads.
+	pub pool: ThreadPool,
+	/// Timing for the frame being drawn.
+	pub stats: PartitionStats,
+	/// Timing for the last full frame.
+	pub last_frame_stats: PartitionStats,
 }
 
 /// A Buffer that allows for parallel rendering by partioning the image into smaller pieces, usually by how many cores the current CPU has.
@@ -39,6 +105,9 @@ impl PartitionedBuffer {
 			partitions:  Vec::new(),
 			scheme: PartitionScheme::Full,
 			threshold,
+			pool: build_pool(if cores == 0 { num_cpus::get() } else { cores }),
+			stats: PartitionStats::default(),
+			last_frame_stats: PartitionStats::default(),
 		};
 
 		pr.set_core_limit(cores);
@@ -94,6 +163,32 @@ impl PartitionedBuffer {
 		}
 	}
 
+	/// Removes every shader from the buffer and every partition.
+	pub fn clear_shaders(&mut self) {
+		self.buffer.clear_shaders();
+		for part in &mut self.partitions {
+			part.clear_shaders();
+		}
+	}
+
+	// Whole buffer operations are already split across threads by the buffer itself,
+	// going through the partitions would only add a copy out and back for every pixel
+	pub fn tint_buffer(&mut self, color: Color) {
+		self.buffer.tint_buffer(color);
+	}
+
+	pub fn pcomposite_opaque(&mut self, buffer: &Buffer) {
+		self.buffer.pcomposite_opaque(buffer);
+	}
+
+	pub fn pcomposite_alpha(&mut self, buffer: &Buffer, opacity: u8) {
+		self.buffer.pcomposite_alpha(buffer, opacity);
+	}
+
+	pub fn pcomposite_multiply(&mut self, buffer: &Buffer) {
+		self.buffer.pcomposite_multiply(buffer);
+	}
+
 	pub fn add_shader(&mut self, shader: BufferShader) {
 		self.buffer.add_shader(shader.clone());
 		for part in &mut self.partitions {
@@ -101,6 +196,32 @@ impl PartitionedBuffer {
 		}
 	}
 
+	/// Resets every shader on the buffer and every partition, and starts timing a new frame.
+	/// Call it once before drawing each frame. See Buffer::begin_frame.
+	pub fn begin_frame(&mut self) {
+		self.buffer.begin_frame();
+		for part in &mut self.partitions {
+			part.begin_frame();
+		}
+
+		self.last_frame_stats = std::mem::take(&mut self.stats);
+		self.stats.threads = self.pool.current_num_threads();
+		self.rebalance_partitions();
+	}
+
+	/// Changes how many threads draw the partitions, without changing the partitions. 0 uses every core.
+	pub fn set_thread_count(&mut self, threads: usize) {
+		let threads = if threads == 0 { num_cpus::get() } else { threads };
+		if threads != self.pool.current_num_threads() {
+			self.pool = build_pool(threads);
+		}
+	}
+
+	/// Adds an active closure shader to the stack of the buffer and every partition. See ShaderFn.
+	pub fn add_shader_fn<F>(&mut self, function: F, order: u8) where F: Fn(&ShaderContext) -> Option<Color> + Send + Sync + 'static {
+		self.add_shader(BufferShader::new_fn(function, true, order));
+	}
+
 	pub fn set_core_limit(&mut self, cores: usize) {
 		let cpu_count = if cores == 0 { num_cpus::get() } else { cores };
 
@@ -129,6 +250,7 @@ impl PartitionedBuffer {
 		}
 		self.scheme = scheme;
 		self.generate_partitions();
+		self.set_thread_count(cpu_count);
 	}
 
 	pub fn resize(&mut self, width: usize, height: usize) {
@@ -136,23 +258,50 @@ impl PartitionedBuffer {
 		self.generate_partitions();
 	}
 
+	/// Converts the buffer to another pixel format and makes new partitions in it. See Buffer::convert_to.
+	pub fn convert_to(&mut self, format: PixelFormat) {
+		self.buffer.convert_to(format);
+		self.generate_partitions();
+	}
+
 	pub fn blit(&mut self, image: &Buffer, x: i32, y: i32) {
 		self.buffer.blit(image, x, y);
 	}
 
+	// Pixels, spans, lines and text draw straight into the buffer, which has the same shaders and state as the partitions.
+	// They're too small to be worth copying every partition back for, and each partition would still walk every
+	// point of a line or glyph of text to clip it. Record many of them into a CommandBuffer and submit it instead,
+	// which draws them all in one parallel pass.
 	pub fn pset(&mut self, x: i32, y: i32, color: Color) {
 		self.buffer.pset(x, y, color);
 	}
 
-	pub fn pget(&mut self, x: i32, y: i32) -> Color {
+	/// Starts a new primitive for pspan_with and ppixel_with. See Buffer::begin_primitive.
+	pub fn begin_primitive(&mut self) -> ShaderParams {
+		self.buffer.begin_primitive()
+	}
+
+	pub fn pspan(&mut self, x_start: i32, y: i32, colors: &mut [Color]) {
+		self.buffer.pspan(x_start, y, colors);
+	}
+
+	pub fn pspan_with(&mut self, x_start: i32, y: i32, colors: &mut [Color], varyings: &[ShaderVaryings], params: ShaderParams) {
+		self.buffer.pspan_with(x_start, y, colors, varyings, params);
+	}
+
+	pub fn ppixel_with(&mut self, x: i32, y: i32, color: Color, params: ShaderParams) {
+		self.buffer.ppixel_with(x, y, color, params);
+	}
+
+	pub fn pget(&self, x: i32, y: i32) -> Color {
 		self.buffer.pget(x, y)
 	}
 
-	pub fn pget_wrap(&mut self, x: i32, y: i32) -> Color {
+	pub fn pget_wrap(&self, x: i32, y: i32) -> Color {
 		self.buffer.pget_wrap(x, y)
 	}
 
-	// Too simple to parallelize
+	// Drawn straight into the buffer, see pset
 	pub fn pline(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
 		self.buffer.pline(x0, y0, x1, y1, color);
 	}
@@ -162,33 +311,10 @@ impl PartitionedBuffer {
 
 		// Run in parallel
 		if filled && self.threshold != 0 && total_area >= self.threshold as i32 {
-			scope(|s| {
-				let mut join_handles: Vec<ScopedJoinHandle<&mut Buffer>> = Vec::new();
-			
-				for part in & mut self.partitions {
-	
-					let rx = x - part.offset_x as i32;
-					let ry = y - part.offset_y as i32;
-	
-					let handle = s.spawn(move || {
-	
-						part.prectangle(filled, rx, ry, width, height, color);
-	
-						part
-					});
-					join_handles.push(handle);
-				}
-	
-				for handle in join_handles {
-					let part_return = handle.join();
-					if part_return.is_ok() {
-						let part = part_return.unwrap();
-						self.buffer.blit(&part, part.offset_x as i32, part.offset_y as i32);
-					} else {
-						println!("ERROR - THREAD PANIC: Partition failed in pcircle function!")
-					}
-				}
-			})
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				part.prectangle(filled, x - part.offset_x as i32, y - part.offset_y as i32, width, height, color);
+			});
 		} else { // Just lines
 			self.buffer.prectangle(filled, x, y, width, height, color);
 		}
@@ -199,40 +325,30 @@ impl PartitionedBuffer {
 
 		// Run in parallel
 		if self.threshold != 0 && total_area >= self.threshold as i32 as f32 {
-			scope(|s| {
-				let mut join_handles: Vec<ScopedJoinHandle<&mut Buffer>> = Vec::new();
-			
-				for part in & mut self.partitions {
-					//let mut part_clone = part.clone();
-	
-					let rx = xc - part.offset_x as i32;
-					let ry = yc - part.offset_y as i32;
-	
-					let handle = s.spawn(move || {
-	
-						part.pcircle(filled, rx, ry, radius, color);
-	
-						part
-					});
-					join_handles.push(handle);
-				}
-	
-				for handle in join_handles {
-					let part_return = handle.join();
-					if part_return.is_ok() {
-						let part = part_return.unwrap();
-						self.buffer.blit(&part, part.offset_x as i32, part.offset_y as i32);
-					} else {
-						println!("ERROR - THREAD PANIC: Partition failed in pcircle function!")
-					}
-				}
-			})
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				part.pcircle(filled, xc - part.offset_x as i32, yc - part.offset_y as i32, radius, color);
+			});
 			
 		} else {
 			self.buffer.pcircle(filled, xc, yc, radius, color);
 		}
 	}
 
+	#[allow(clippy::too_many_arguments)]
+	pub fn ptriangle(&mut self, filled: bool, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, color: Color) {
+		// Run in parallel
+		if filled && self.threshold != 0 && self.triangle_area(x0, y0, x1, y1, x2, y2) >= self.threshold {
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				let (ox, oy) = (part.offset_x as i32, part.offset_y as i32);
+				part.ptriangle(filled, x0 - ox, y0 - oy, x1 - ox, y1 - oy, x2 - ox, y2 - oy, color);
+			});
+		} else {
+			self.buffer.ptriangle(filled, x0, y0, x1, y1, x2, y2, color);
+		}
+	}
+
 	pub fn pimg(&mut self, image: &Buffer, x: i32, y: i32) {
 
 		let width = image.width;
@@ -242,34 +358,10 @@ impl PartitionedBuffer {
 
 		// Run in parallel
 		if self.threshold != 0 && total_area >= self.threshold as i32 {
-			scope(|s| {
-				let mut join_handles: Vec<ScopedJoinHandle<&mut Buffer>> = Vec::new();
-			
-				for part in &mut self.partitions {
-
-					let rx = x - part.offset_x as i32;
-					let ry = y - part.offset_y as i32;
-
-
-					let handle = s.spawn(move || {
-	
-						part.pimg(image, rx, ry);
-	
-						part
-					});
-					join_handles.push(handle);
-				}
-	
-				for handle in join_handles {
-					let part_return = handle.join();
-					if part_return.is_ok() {
-						let part = part_return.unwrap();
-						self.buffer.blit(&part, part.offset_x as i32, part.offset_y as i32);
-					} else {
-						println!("ERROR - THREAD PANIC: Partition failed in pimg function!")
-					}
-				}
-			})
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				part.pimg(image, x - part.offset_x as i32, y - part.offset_y as i32);
+			});
 			
 		} else {
 			self.buffer.pimg(&image, x, y);
@@ -286,41 +378,29 @@ impl PartitionedBuffer {
 
 		// Run in parallel
 		if self.threshold != 0 && total_area >= self.threshold as i32 {
-			scope(|s| {
-				let mut join_handles: Vec<ScopedJoinHandle<&mut Buffer>> = Vec::new();
-			
-				for part in &mut self.partitions {
-
-					let rx = x - part.offset_x as i32;
-					let ry = y - part.offset_y as i32;
-
-
-					let handle = s.spawn(move || {
-	
-						part.pimgrect(image, rx, ry, ix, iy, iw, ih);
-	
-						part
-					});
-					join_handles.push(handle);
-				}
-	
-				for handle in join_handles {
-					let part_return = handle.join();
-					if part_return.is_ok() {
-						let part = part_return.unwrap();
-						self.buffer.blit(&part, part.offset_x as i32, part.offset_y as i32);
-					} else {
-						println!("ERROR - THREAD PANIC: Partition failed in pimg function!")
-					}
-				}
-			})
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				part.pimgrect(image, x - part.offset_x as i32, y - part.offset_y as i32, ix, iy, iw, ih);
+			});
 			
 		} else {
-			self.buffer.pimg(&image, x, y);
+			self.buffer.pimgrect(&image, x, y, ix, iy, iw, ih);
 		}
 		
 	}
 
+	pub fn pimgrect_flip(&mut self, image: &Buffer, x: i32, y: i32, rect: (i32, i32, i32, i32), flip: ImageFlip) {
+		// Run in parallel
+		if self.threshold != 0 && rect.2 * rect.3 >= self.threshold as i32 {
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				part.pimgrect_flip(image, x - part.offset_x as i32, y - part.offset_y as i32, rect, flip);
+			});
+		} else {
+			self.buffer.pimgrect_flip(image, x, y, rect, flip);
+		}
+	}
+
 	pub fn pimgmtx(&mut self, image: &Buffer, x: f32, y: f32, rotation: f32, scale_x: f32, scale_y: f32, offset_x: f32, offset_y: f32) {
 
 		let width = image.width;
@@ -330,36 +410,10 @@ impl PartitionedBuffer {
 
 		// Run in parallel
 		if self.threshold != 0 && total_area >= self.threshold as i32 as f32 {
-			scope(|s| {
-
-				let mut join_handles: Vec<ScopedJoinHandle<&mut Buffer>> = Vec::new();
-
-				// First pass: Find all regions that contain the image
-				for part in &mut self.partitions {
-					let rx = x - part.offset_x as f32;
-					let ry = y - part.offset_y as f32;
-					
-					let handle = s.spawn( move || {
-						
-						part.pimgmtx(image, rx, ry, rotation, scale_x, scale_y, offset_x, offset_y);
-	
-						part
-					});
-					join_handles.push(handle);
-				}
-
-				for handle in join_handles {
-					let part_return = handle.join();
-					if part_return.is_ok() {
-						let part = part_return.unwrap();
-						self.buffer.blit(&part, part.offset_x as i32, part.offset_y as i32);
-					} else {
-						println!("ERROR - THREAD PANIC: Partition failed in pimgmtx function!")
-					}
-				}
-				
-				//self.buffer.prectangle(false, rsx as i32, rsy as i32, (rex - rsx) as i32, (rey - rsy) as i32, Color::blue());
-			})
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				part.pimgmtx(image, x - part.offset_x as f32, y - part.offset_y as f32, rotation, scale_x, scale_y, offset_x, offset_y);
+			});
 			
 		} else {
 			self.buffer.pimgmtx(&image, x, y, rotation, scale_x, scale_y, offset_x, offset_y);
@@ -375,55 +429,18 @@ impl PartitionedBuffer {
         u2: f32, v2: f32, w2: f32,
         image: &Buffer) {
 
-		let xmin = i32::clamp(i32::min(x0, i32::min(x1, x2)), 0, self.buffer.width as i32);
-		let xmax = i32::clamp(i32::max(x0, i32::max(x1, x2)), 0, self.buffer.width as i32);
-		let ymin = i32::clamp(i32::min(y0, i32::min(y1, y2)), 0, self.buffer.height as i32);
-		let ymax = i32::clamp(i32::max(y0, i32::max(y1, y2)), 0, self.buffer.height as i32);
-
-		let bb_width: i32 = xmax - xmin;
-		let bb_height: i32 = ymax - ymin;
-
-		let total_area = bb_width as u32 * bb_height as u32;
-
 		// Run in parallel
-		if self.threshold != 0 && total_area >= self.threshold {
-			scope(|s| {
-
-				let mut join_handles: Vec<ScopedJoinHandle<&mut Buffer>> = Vec::new();
-
-				// First pass: Find all regions that contain the image
-				for part in &mut self.partitions {
-					let rx0 = x0 - part.offset_x as i32;
-					let ry0 = y0 - part.offset_y as i32;
-					let rx1 = x1 - part.offset_x as i32;
-					let ry1 = y1 - part.offset_y as i32;
-					let rx2 = x2 - part.offset_x as i32;
-					let ry2 = y2 - part.offset_y as i32;
-					
-					let handle = s.spawn( move || {
-						
-						part.ptritex_uvw(
-							rx0, ry0, rx1, ry1, rx2, ry2,
-							u0, v0, w0,
-							u1, v1, w1,
-							u2, v2, w2, image
-						);
-	
-						part
-					});
-					join_handles.push(handle);
-				}
-
-				for handle in join_handles {
-					let part_return = handle.join();
-					if part_return.is_ok() {
-						let part = part_return.unwrap();
-						self.buffer.blit(&part, part.offset_x as i32, part.offset_y as i32);
-					} else {
-						println!("ERROR - THREAD PANIC: Partition failed in ptritex_uvw function!")
-					}
-				}
-			})
+		if self.threshold != 0 && self.triangle_area(x0, y0, x1, y1, x2, y2) >= self.threshold {
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				let (ox, oy) = (part.offset_x as i32, part.offset_y as i32);
+				part.ptritex_uvw(
+					x0 - ox, y0 - oy, x1 - ox, y1 - oy, x2 - ox, y2 - oy,
+					u0, v0, w0,
+					u1, v1, w1,
+					u2, v2, w2, image
+				);
+			});
 			
 		} else {
 			self.buffer.ptritex_uvw(x0, y0, x1, y1, x2, y2,
@@ -436,48 +453,306 @@ impl PartitionedBuffer {
 	}
 
 
-	fn generate_partitions(&mut self) {
-		self.partitions.clear();
-		/*let mut divx = min_pixel_size;
+	#[allow(clippy::too_many_arguments)]
+	pub fn ptritex(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32, image: &Buffer) {
+		// Run in parallel
+		if self.threshold != 0 && self.triangle_area(x0, y0, x1, y1, x2, y2) >= self.threshold {
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				let (ox, oy) = (part.offset_x as i32, part.offset_y as i32);
+				part.ptritex(x0 - ox, y0 - oy, x1 - ox, y1 - oy, x2 - ox, y2 - oy, image);
+			});
+		} else {
+			self.buffer.ptritex(x0, y0, x1, y1, x2, y2, image);
+		}
+	}
 
-		for i in min_pixel_size..self.buffer.width {
-			if (self.buffer.width % i) == 0 {
-				divx = i;
-				break;
-			} 
+	#[allow(clippy::too_many_arguments)]
+	pub fn ptritex_uv(&mut self, x0: i32, y0: i32,
+		x1: i32, y1: i32,
+		x2: i32, y2: i32,
+		u0: f32, v0: f32,
+		u1: f32, v1: f32,
+		u2: f32, v2: f32,
+		image: &Buffer) {
+
+		// Run in parallel
+		if self.threshold != 0 && self.triangle_area(x0, y0, x1, y1, x2, y2) >= self.threshold {
+			self.sync_partitions(1);
+			self.run_partitions(|part| {
+				let (ox, oy) = (part.offset_x as i32, part.offset_y as i32);
+				part.ptritex_uv(x0 - ox, y0 - oy, x1 - ox, y1 - oy, x2 - ox, y2 - oy, u0, v0, u1, v1, u2, v2, image);
+			});
+		} else {
+			self.buffer.ptritex_uv(x0, y0, x1, y1, x2, y2, u0, v0, u1, v1, u2, v2, image);
 		}
+	}
+
+	// Drawn straight into the buffer, see pset
+	pub fn pprint(&mut self, font: &Font, text: String, x: i32, y: i32, newline_space: i32, wrap_width: Option<u32>) {
+		self.buffer.pprint(font, text, x, y, newline_space, wrap_width);
+	}
+
+	// Drawn straight into the buffer, see pset
+	#[allow(clippy::too_many_arguments)]
+	pub fn pbeizer(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, mx: i32, my: i32, color: Color) {
+		self.buffer.pbeizer(x0, y0, x1, y1, mx, my, color);
+	}
+
+	// Pixels a triangle's bounding box covers inside the buffer, to decide if it's worth drawing in parallel
+	fn triangle_area(&self, x0: i32, y0: i32, x1: i32, y1: i32, x2: i32, y2: i32) -> u32 {
+		let xmin = i32::clamp(i32::min(x0, i32::min(x1, x2)), 0, self.buffer.width as i32);
+		let xmax = i32::clamp(i32::max(x0, i32::max(x1, x2)), 0, self.buffer.width as i32);
+		let ymin = i32::clamp(i32::min(y0, i32::min(y1, y2)), 0, self.buffer.height as i32);
+		let ymax = i32::clamp(i32::max(y0, i32::max(y1, y2)), 0, self.buffer.height as i32);
+
+		(xmax - xmin) as u32 * (ymax - ymin) as u32
+	}
 
-		let mut divy = min_pixel_size;
+	/// Runs a drawing function on every partition at the same time, then copies the partitions back into the buffer.
+	/// The function is given each partition in turn, use its offset_x and offset_y to move from screen space into it.
+	pub fn draw_parallel<F>(&mut self, draw: F) where F: Fn(&mut Buffer) + Sync {
+		self.sync_partitions(0);
+		self.run_partitions(draw);
 
-		for i in min_pixel_size..self.buffer.height {
-			if (self.buffer.height % i) == 0 {
-				divy = i;
-				break;
-			} 
+		// Every partition ran the same draws, so they all counted the same primitives
+		if let Some(part) = self.partitions.first() {
+			self.buffer.primitive_id = part.primitive_id;
 		}
+	}
+
+	/// Draws a whole frame's worth of recorded commands at once. Each command is sorted into the partitions its
+	/// bounds touch, every partition draws its own list in parallel, then all partitions are copied back once.
+	/// Much faster than drawing many small primitives one call at a time, which copies every partition back after each.
+	pub fn submit(&mut self, commands: &CommandBuffer) {
+		if commands.is_empty() { return; }
+
+		let first_id = self.buffer.primitive_id;
+		let primitive_ids = commands.primitive_ids(first_id);
+		self.sync_partitions(0);
+
+		// No need to pick up the frame drawn so far if the commands start by clearing it anyway
+		let scatter = !matches!(commands.commands[0], DrawCommand::ClearColor { .. });
 
-		let (cx, cy) = (self.buffer.width / divx, self.buffer.height / divy);
+		// Bounds of rotated images build a matrix, so they're worked out once per command rather than once per partition
+		let bounds: Vec<Option<(i32, i32, i32, i32)>> = commands.commands.iter().map(|command| command.bounds()).collect();
 
-		for y in 0..cy {
-			for x in 0..cx {
-				let mut r = Buffer::new(divx, divy);
-				r.offset_x = x * divx;
-				r.offset_y = y * divy;
-				self.partitions.push(r);
+		let bins: Vec<Vec<usize>> = self.partitions.iter().map(|part| {
+			let (px0, py0) = (part.offset_x as i32, part.offset_y as i32);
+			let (px1, py1) = (px0 + part.width as i32, py0 + part.height as i32);
+
+			bounds.iter().enumerate().filter(|(_, bounds)| match bounds {
+				Some((x0, y0, x1, y1)) => *x0 < px1 && *x1 > px0 && *y0 < py1 && *y1 > py0,
+				None => true,
+			}).map(|(i, _)| i).collect()
+		}).collect();
+
+		let (primitive_ids, bins) = (&primitive_ids, &bins);
+		self.run_partitions_indexed(scatter, |index, part| {
+			let (ox, oy) = (part.offset_x as i32, part.offset_y as i32);
+			for &i in &bins[index] {
+				part.primitive_id = primitive_ids[i];
+				commands.commands[i].draw(part, ox, oy);
 			}
-		}*/
+		});
 
-		match self.scheme {
-			PartitionScheme::Full => { self.partition_full(); },
-			PartitionScheme::Split1x2 => { self.partition_split_vertical(); },
-			PartitionScheme::Split2x1 => { self.partition_split_horizontal(); },
-			PartitionScheme::Split2x2 => { self.partition_split_2x2(); },
-			PartitionScheme::Split3x3 => { self.partition_split_3x3(); },
-			PartitionScheme::Split3x2 => { self.partition_split_3x2(); },
-			PartitionScheme::Split3x1 => { self.partition_split_3x1(); },
-			PartitionScheme::Split4x4 => { self.partition_split_4x4(); },
-			PartitionScheme::Split5x5 => { self.partition_split_5x5(); },
-			PartitionScheme::Split8x8 => { self.partition_split_8x8(); },
+		// State changes stay set afterwards, the same as drawing straight into the buffer
+		for command in &commands.commands {
+			if !command.is_primitive() && !matches!(command, DrawCommand::ClearColor { .. }) {
+				command.draw(&mut self.buffer, 0, 0);
+			}
+		}
+		self.buffer.primitive_id = first_id.wrapping_add(commands.primitive_count());
+	}
+
+	/// Runs a full screen effect over the whole buffer. Effects need to see past the edges of a partition,
+	/// so they run on the buffer and split the work across rows instead.
+	pub fn post_process(&mut self, effect: &dyn PostProcess) {
+		self.buffer.post_process(effect);
+	}
+
+	/// Sets the stencil test and operations for everything drawn after this. See StencilState.
+	pub fn set_stencil_state(&mut self, stencil_state: StencilState) {
+		self.buffer.set_stencil_state(stencil_state);
+	}
+
+	/// Limits drawing to where 'mask' lets it, or draws everywhere again with None. See BufferMask.
+	pub fn set_mask(&mut self, mask: Option<BufferMask>) {
+		self.buffer.set_mask(mask);
+	}
+
+	/// Sets every stencil value to 'value'.
+	pub fn clear_stencil(&mut self, value: u8) {
+		self.buffer.clear_stencil(value);
+	}
+
+	/// Starts or stops recording where the buffer is drawn to. While recording, parallel draws only copy
+	/// back the parts of each partition that changed. See Buffer::set_dirty_tracking.
+	pub fn set_dirty_tracking(&mut self, enabled: bool) {
+		self.buffer.set_dirty_tracking(enabled);
+	}
+
+	/// Rectangles that changed since the last begin_frame. See Buffer::dirty_rects.
+	pub fn dirty_rects(&self) -> Vec<DirtyRect> {
+		self.buffer.dirty_rects()
+	}
+
+	/// Sets the uniforms handed to the shader stack on the buffer and every partition.
+	pub fn set_uniforms(&mut self, uniforms: ShaderUniforms) {
+		self.buffer.uniforms = uniforms;
+		for part in &mut self.partitions {
+			part.uniforms = uniforms;
+		}
+	}
+
+	// Runs 'draw' on every partition in the pool, then copies them all back into the buffer.
+	// Partitions start from what's in the buffer, so draws made straight into it aren't lost.
+	fn run_partitions<F>(&mut self, draw: F) where F: Fn(&mut Buffer) + Sync {
+		self.run_partitions_indexed(true, |_, part| draw(part));
+	}
+
+	fn run_partitions_indexed<F>(&mut self, scatter: bool, draw: F) where F: Fn(usize, &mut Buffer) + Sync {
+		let started = Instant::now();
+		let buffer = &self.buffer;
+		let partitions = &mut self.partitions;
+		let times: Vec<f64> = self.pool.install(|| {
+			partitions.par_iter_mut().enumerate().map(|(index, part)| {
+				let part_started = Instant::now();
+				if scatter { scatter_partition(buffer, part); }
+				draw(index, part);
+				part_started.elapsed().as_secs_f64()
+			}).collect()
+		});
+		let drawn = Instant::now();
+
+		for part in &self.partitions {
+			gather_partition(&mut self.buffer, part);
+		}
+
+		let stats = &mut self.stats;
+		stats.parallel_calls += 1;
+		stats.parallel_time += (drawn - started).as_secs_f64();
+		stats.resolve_time += drawn.elapsed().as_secs_f64();
+		stats.threads = self.pool.current_num_threads();
+		if stats.partition_times.len() != times.len() { stats.partition_times = vec![0.0; times.len()]; }
+		for (total, time) in stats.partition_times.iter_mut().zip(times) {
+			*total += time;
+		}
+	}
+
+	// Partitions draw the same primitive as the buffer would, so they need its uniforms and primitive id.
+	// 'primitives' is how many primitives the buffer skips ahead by, as the partitions draw them instead.
+	// The stencil state, mask and stencil plane are copied in too, and the plane is copied back by gather_partition.
+	fn sync_partitions(&mut self, primitives: u32) {
+		for part in &mut self.partitions {
+			part.uniforms = self.buffer.uniforms;
+			part.primitive_id = self.buffer.primitive_id;
+			part.stencil_state = self.buffer.stencil_state;
+			part.mask = self.buffer.mask.clone();
+
+			// Partitions record their own changes per draw so only those get copied back
+			match (&self.buffer.dirty, &mut part.dirty) {
+				(Some(_), Some(dirty)) => dirty.clear(),
+				(Some(dirty), None) => part.dirty = Some(DirtyRegion::new(dirty.max_rects)),
+				(None, _) => part.dirty = None,
+			}
+
+			if self.buffer.stencil.is_empty() {
+				part.stencil.clear();
+			} else {
+				if part.stencil.len() != part.width * part.height { part.clear_stencil(0); }
+				for y in 0..part.height {
+					let from = (y + part.offset_y) * self.buffer.width + part.offset_x;
+					part.stencil[y * part.width..(y + 1) * part.width].copy_from_slice(&self.buffer.stencil[from..from + part.width]);
+				}
+			}
+		}
+		self.buffer.primitive_id = self.buffer.primitive_id.wrapping_add(primitives);
+	}
+
+	/// Changes how the buffer is cut into partitions and makes new ones.
+	pub fn set_scheme(&mut self, scheme: PartitionScheme) {
+		self.scheme = scheme;
+		self.generate_partitions();
+	}
+
+	fn generate_partitions(&mut self) {
+		let (width, height) = (self.buffer.width, self.buffer.height);
+		let rects = match self.scheme {
+			PartitionScheme::Full => grid_rects(width, height, 1, 1),
+			PartitionScheme::Split1x2 => grid_rects(width, height, 2, 1),
+			PartitionScheme::Split2x1 => grid_rects(width, height, 1, 2),
+			PartitionScheme::Split2x2 => grid_rects(width, height, 2, 2),
+			PartitionScheme::Split3x1 => grid_rects(width, height, 3, 1),
+			PartitionScheme::Split3x2 => grid_rects(width, height, 3, 2),
+			PartitionScheme::Split3x3 => grid_rects(width, height, 3, 3),
+			PartitionScheme::Split4x4 => grid_rects(width, height, 4, 4),
+			PartitionScheme::Split5x5 => grid_rects(width, height, 5, 5),
+			PartitionScheme::Split8x8 => grid_rects(width, height, 8, 8),
+			PartitionScheme::Tiles { width: tile_width, height: tile_height } => tile_rects(width, height, tile_width, tile_height),
+			PartitionScheme::Adaptive { partitions } => {
+				let mut rects = Vec::with_capacity(partitions);
+				split_balanced((0, 0, width, height), partitions.max(1), &|rect: PartitionRect| (rect.2 * rect.3) as f64, &mut rects);
+				rects
+			},
+		};
+		self.set_partition_rects(&rects);
+	}
+
+	// Re-cuts an adaptive scheme using how long each partition took last frame.
+	// The time a partition took is spread evenly over its pixels, then the buffer is split in two over and over
+	// along its longer side, at the point where both sides cost what their share of the partitions should.
+	fn rebalance_partitions(&mut self) {
+		let partitions = match self.scheme {
+			PartitionScheme::Adaptive { partitions } => partitions.max(1),
+			_ => { return; }
+		};
+
+		let times = &self.last_frame_stats.partition_times;
+		if times.len() != self.partitions.len() || times.iter().sum::<f64>() <= 0.0 { return; }
+
+		let total_area = (self.buffer.width * self.buffer.height) as f64;
+		let mean_density = times.iter().sum::<f64>() / total_area;
+
+		// Empty areas still cost a little, so they get split by size instead of collapsing into one huge tile
+		let weights: Vec<(PartitionRect, f64)> = self.partitions.iter().zip(times).map(|(part, time)| {
+			let rect = (part.offset_x, part.offset_y, part.width, part.height);
+			let area = (part.width * part.height).max(1) as f64;
+			(rect, time / area + mean_density * 0.05)
+		}).collect();
+
+		let cost = |rect: PartitionRect| -> f64 {
+			weights.iter().map(|(other, density)| overlap_area(rect, *other) as f64 * density).sum()
+		};
+
+		let mut rects: Vec<PartitionRect> = Vec::with_capacity(partitions);
+		split_balanced((0, 0, self.buffer.width, self.buffer.height), partitions, &cost, &mut rects);
+		self.set_partition_rects(&rects);
+	}
+
+	// Makes a partition for every rect, with the buffer's shaders and drawing state.
+	// Partitions whose rect and format haven't changed are kept, so rebalancing every frame only rebuilds what moved.
+	fn set_partition_rects(&mut self, rects: &[PartitionRect]) {
+		let format = self.buffer.format;
+		let unchanged = |part: &Buffer, rect: &PartitionRect| (part.offset_x, part.offset_y, part.width, part.height) == *rect && part.format == format;
+		if self.partitions.len() == rects.len() && self.partitions.iter().zip(rects).all(|(part, rect)| unchanged(part, rect)) { return; }
+
+		let mut previous = std::mem::take(&mut self.partitions);
+		for rect in rects {
+			if let Some(index) = previous.iter().position(|part| unchanged(part, rect)) {
+				let mut part = previous.swap_remove(index);
+				part.is_drawing = self.buffer.is_drawing;
+				self.partitions.push(part);
+				continue;
+			}
+
+			let &(x, y, width, height) = rect;
+			let mut part = Buffer::new_with_format(width, height, format);
+			part.offset_x = x;
+			part.offset_y = y;
+			part.shader_stack = self.buffer.shader_stack.clone();
+			part.is_drawing = self.buffer.is_drawing;
+			self.partitions.push(part);
 		}
 	}
 
@@ -501,137 +776,182 @@ impl PartitionedBuffer {
 		}
 	}
 
-	fn partition_full(&mut self) {
-		self.partitions.push(Buffer::new(self.buffer.width, self.buffer.height));
-	}
+}
 
-	fn partition_split_vertical(&mut self) {
-		let mut left_half: Buffer = Buffer::new(self.buffer.width / 2, self.buffer.height);
-		left_half.offset_x = 0;
-		left_half.offset_y = 0;
+// Left, top, width and height of a partition in the buffer
+type PartitionRect = (usize, usize, usize, usize);
 
-		let mut right_half: Buffer = Buffer::new(self.buffer.width / 2, self.buffer.height);
-		right_half.offset_x = self.buffer.width / 2;
-		right_half.offset_y = 0;
+// Splits the buffer into an even grid. Edges are rounded so the cells always meet and cover everything.
+fn grid_rects(width: usize, height: usize, columns: usize, rows: usize) -> Vec<PartitionRect> {
+	let (columns, rows) = (columns.clamp(1, width.max(1)), rows.clamp(1, height.max(1)));
 
-		self.partitions.push(left_half);
-		self.partitions.push(right_half);
+	let mut rects = Vec::with_capacity(columns * rows);
+	for row in 0..rows {
+		let (y0, y1) = (row * height / rows, (row + 1) * height / rows);
+		for column in 0..columns {
+			let (x0, x1) = (column * width / columns, (column + 1) * width / columns);
+			rects.push((x0, y0, x1 - x0, y1 - y0));
+		}
 	}
+	rects
+}
 
-	fn partition_split_horizontal(&mut self) {
-		let mut top_half: Buffer = Buffer::new(self.buffer.width, self.buffer.height / 2);
-		top_half.offset_x = 0;
-		top_half.offset_y = 0;
-
-		let mut bottom_half: Buffer = Buffer::new(self.buffer.width, self.buffer.height / 2);
-		bottom_half.offset_x = 0;
-		bottom_half.offset_y = self.buffer.height / 2;
+// Splits the buffer into fixed size tiles, cutting the last column and row short
+fn tile_rects(width: usize, height: usize, tile_width: usize, tile_height: usize) -> Vec<PartitionRect> {
+	let (tile_width, tile_height) = (tile_width.max(1), tile_height.max(1));
 
-		self.partitions.push(top_half);
-		self.partitions.push(bottom_half);
+	let mut rects = Vec::new();
+	for y in (0..height).step_by(tile_height) {
+		for x in (0..width).step_by(tile_width) {
+			rects.push((x, y, tile_width.min(width - x), tile_height.min(height - y)));
+		}
 	}
+	if rects.is_empty() { rects.push((0, 0, width, height)); }
+	rects
+}
 
-	fn partition_split_2x2(&mut self) {
-		let cell_x = self.buffer.width / 2;
-		let cell_y = self.buffer.height / 2;
+fn overlap_area(a: PartitionRect, b: PartitionRect) -> usize {
+	let w = (a.0 + a.2).min(b.0 + b.2).saturating_sub(a.0.max(b.0));
+	let h = (a.1 + a.3).min(b.1 + b.3).saturating_sub(a.1.max(b.1));
+	w * h
+}
 
-		for y in 0..2 {
-			for x in 0..2 {
-				let mut r = Buffer::new(cell_x, cell_y);
-				r.offset_x = cell_x * x;
-				r.offset_y = cell_y * y;
-				self.partitions.push(r);
-			}
-		}
+// Cuts 'rect' into 'count' pieces of roughly equal cost
+fn split_balanced(rect: PartitionRect, count: usize, cost: &dyn Fn(PartitionRect) -> f64, out: &mut Vec<PartitionRect>) {
+	const MIN_SIZE: usize = 8;
+
+	let (x, y, width, height) = rect;
+	let split_x = width >= height;
+	let length = if split_x { width } else { height };
+	if count <= 1 || length < MIN_SIZE * 2 {
+		out.push(rect);
+		return;
 	}
 
-	fn partition_split_3x1(&mut self) {
-		let cell_x = self.buffer.width / 3;
-		let cell_y = self.buffer.height;
+	let first_count = count / 2;
+	let target = cost(rect) * first_count as f64 / count as f64;
+	let first = |at: usize| if split_x { (x, y, at, height) } else { (x, y, width, at) };
 
-		let mut r = Buffer::new(cell_x, cell_y);
-		r.offset_x = 0;
-		r.offset_y = 0;
-		self.partitions.push(r);
+	let at = (MIN_SIZE..=length - MIN_SIZE).find(|at| cost(first(*at)) >= target).unwrap_or(length - MIN_SIZE);
+	let second = if split_x { (x + at, y, width - at, height) } else { (x, y + at, width, height - at) };
 
-		let mut r = Buffer::new(cell_x, cell_y);
-		r.offset_x = cell_x;
-		r.offset_y = 0;
-		self.partitions.push(r);
+	split_balanced(first(at), first_count, cost, out);
+	split_balanced(second, count - first_count, cost, out);
+}
 
-		let mut r = Buffer::new(cell_x, cell_y);
-		r.offset_x = cell_x * 2;
-		r.offset_y = 0;
-		self.partitions.push(r);
+fn build_pool(threads: usize) -> ThreadPool {
+	match ThreadPoolBuilder::new().num_threads(threads).thread_name(|i| format!("aftershock-partition-{}", i)).build() {
+		Ok(pool) => pool,
+		Err(reason) => {
+			println!("ERROR - THREAD POOL: Could not start {} threads, using one | {}", threads, reason);
+			ThreadPoolBuilder::new().num_threads(1).build().expect("ERROR - THREAD POOL: Could not start any threads")
+		}
 	}
+}
 
-	fn partition_split_3x2(&mut self) {
-		let cell_x = self.buffer.width / 3;
-		let cell_y = self.buffer.height / 2;
+// Copies the partition's part of the buffer into it
+fn scatter_partition(buffer: &Buffer, part: &mut Buffer) {
+	let stride = buffer.format.bytes_per_pixel();
+	let row = part.width * stride;
+	for y in 0..part.height {
+		let from = ((y + part.offset_y) * buffer.width + part.offset_x) * stride;
+		part.color[y * row..(y + 1) * row].copy_from_slice(&buffer.color[from..from + row]);
+	}
+}
 
-		for y in 0..2 {
-			for x in 0..3 {
-				let mut r = Buffer::new(cell_x, cell_y);
-				r.offset_x = cell_x * x;
-				r.offset_y = cell_y * y;
-				self.partitions.push(r);
+// Copies a finished partition back into the buffer, along with its stencil plane if it used one.
+// Partitions that recorded their changes only copy those.
+fn gather_partition(buffer: &mut Buffer, part: &Buffer) {
+	match &part.dirty {
+		Some(dirty) => {
+			let stride = buffer.format.bytes_per_pixel();
+			for rect in &dirty.rects {
+				for y in rect.y..rect.bottom() {
+					let from = (y * part.width + rect.x) * stride;
+					let to = ((y + part.offset_y) * buffer.width + rect.x + part.offset_x) * stride;
+					buffer.color[to..to + rect.width * stride].copy_from_slice(&part.color[from..from + rect.width * stride]);
+				}
+				buffer.mark_dirty((rect.x + part.offset_x) as i32, (rect.y + part.offset_y) as i32, rect.width as i32, rect.height as i32);
 			}
-		}
+		},
+		None => buffer.blit(part, part.offset_x as i32, part.offset_y as i32),
 	}
 
-	fn partition_split_3x3(&mut self) {
-		let cell_x = self.buffer.width / 3;
-		let cell_y = self.buffer.height / 3;
+	if part.stencil.is_empty() { return; }
+	if buffer.stencil.len() != buffer.width * buffer.height { buffer.clear_stencil(0); }
+	for y in 0..part.height {
+		let to = (y + part.offset_y) * buffer.width + part.offset_x;
+		buffer.stencil[to..to + part.width].copy_from_slice(&part.stencil[y * part.width..(y + 1) * part.width]);
+	}
+}
 
-		for y in 0..3 {
-			for x in 0..3 {
-				let mut r = Buffer::new(cell_x, cell_y);
-				r.offset_x = cell_x * x;
-				r.offset_y = cell_y * y;
-				self.partitions.push(r);
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Every pixel of the buffer has to be drawn by exactly one partition
+	fn assert_covers_once(rects: &[PartitionRect], width: usize, height: usize) {
+		let mut hits = vec![0u8; width * height];
+		for &(x, y, w, h) in rects {
+			assert!(w > 0 && h > 0, "empty partition {:?}", (x, y, w, h));
+			assert!(x + w <= width && y + h <= height, "partition {:?} is outside {}x{}", (x, y, w, h), width, height);
+			for py in y..y + h {
+				for px in x..x + w {
+					hits[py * width + px] += 1;
+				}
 			}
 		}
+		assert!(hits.iter().all(|h| *h == 1), "{} pixels missed or covered twice", hits.iter().filter(|h| **h != 1).count());
 	}
 
-	fn partition_split_4x4(&mut self) {
-		let cell_x = self.buffer.width / 4;
-		let cell_y = self.buffer.height / 4;
-
-		for y in 0..4 {
-			for x in 0..4 {
-				let mut r = Buffer::new(cell_x, cell_y);
-				r.offset_x = cell_x * x;
-				r.offset_y = cell_y * y;
-				self.partitions.push(r);
-			}
+	#[test]
+	fn grid_rects_cover_odd_sizes() {
+		for (columns, rows) in [(1, 1), (3, 3), (5, 5), (8, 8)] {
+			assert_covers_once(&grid_rects(961, 541, columns, rows), 961, 541);
 		}
+		assert_covers_once(&grid_rects(3, 2, 8, 8), 3, 2);
 	}
 
-	fn partition_split_5x5(&mut self) {
-		let cell_x = self.buffer.width / 5;
-		let cell_y = self.buffer.height / 5;
+	#[test]
+	fn tile_rects_cover_odd_sizes() {
+		assert_covers_once(&tile_rects(961, 541, 64, 64), 961, 541);
+		assert_covers_once(&tile_rects(961, 541, 7, 300), 961, 541);
+	}
 
-		for y in 0..5 {
-			for x in 0..5 {
-				let mut r = Buffer::new(cell_x, cell_y);
-				r.offset_x = cell_x * x;
-				r.offset_y = cell_y * y;
-				self.partitions.push(r);
-			}
+	#[test]
+	fn split_balanced_covers_odd_sizes() {
+		let even = |rect: PartitionRect| (rect.2 * rect.3) as f64;
+		// Most of the cost in the top left corner, like a busy part of the screen
+		let skewed = |rect: PartitionRect| {
+			let (x0, y0, x1, y1) = (rect.0.min(100), rect.1.min(80), (rect.0 + rect.2).min(100), (rect.1 + rect.3).min(80));
+			(rect.2 * rect.3) as f64 + (x1.saturating_sub(x0) * y1.saturating_sub(y0)) as f64 * 50.0
+		};
+
+		for count in [1, 2, 3, 7, 12, 16] {
+			let mut rects = Vec::new();
+			split_balanced((0, 0, 961, 541), count, &even, &mut rects);
+			assert_covers_once(&rects, 961, 541);
+
+			let mut rects = Vec::new();
+			split_balanced((0, 0, 961, 541), count, &skewed, &mut rects);
+			assert_covers_once(&rects, 961, 541);
 		}
 	}
 
-	fn partition_split_8x8(&mut self) {
-		let cell_x = self.buffer.width / 8;
-		let cell_y = self.buffer.height / 8;
+	#[test]
+	fn rebalanced_partitions_cover_odd_sizes() {
+		let mut buffer = PartitionedBuffer::new(961, 541, 2, 0);
+		buffer.set_scheme(PartitionScheme::Adaptive { partitions: 12 });
 
-		for y in 0..8 {
-			for x in 0..8 {
-				let mut r = Buffer::new(cell_x, cell_y);
-				r.offset_x = cell_x * x;
-				r.offset_y = cell_y * y;
-				self.partitions.push(r);
-			}
+		for frame in 0..4 {
+			// One partition far slower than the rest, moving around between frames
+			let count = buffer.partitions.len();
+			buffer.last_frame_stats.partition_times = (0..count).map(|i| if i == (frame * 5) % count { 40.0 } else { 1.0 }).collect();
+			buffer.rebalance_partitions();
+
+			let rects: Vec<PartitionRect> = buffer.partitions.iter().map(|p| (p.offset_x, p.offset_y, p.width, p.height)).collect();
+			assert_eq!(rects.len(), 12);
+			assert_covers_once(&rects, 961, 541);
 		}
 	}
-}
\ No newline at end of file
+}
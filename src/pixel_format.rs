@@ -0,0 +1,231 @@
+use crate::color::Color;
+
+/// How a Buffer stores its pixels in 'color'. Primitives, shaders and effects work the same in every format,
+/// colors are converted as they're written and read back.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    /// Red, green, blue and alpha, one byte each. What images load as and the fastest to draw into.
+    #[default]
+    Rgba8,
+    /// Blue, green, red and alpha, one byte each. Matches X11 and Wayland shared memory so it can be presented without converting.
+    Bgra8,
+    /// 5 bits red, 6 bits green and 5 bits blue packed into a little endian u16. Has no alpha, reads back as opaque.
+    Rgb565,
+    /// One byte of brightness. Colors written are turned into their luminance (see Color::luminance) and read back as opaque grey.
+    Gray8,
+    /// Red, green, blue and alpha as little endian f32, where 1.0 is full. Values above 1.0 are kept for HDR.
+    RgbaF32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Gray8 => 1,
+            PixelFormat::RgbaF32 => 16,
+        }
+    }
+
+    /// Writes a color into 'bytes', which must be exactly one pixel long.
+    pub fn encode(&self, color: Color, bytes: &mut [u8]) {
+        match self {
+            PixelFormat::Rgba8 => bytes.copy_from_slice(&[color.r, color.g, color.b, color.a]),
+            PixelFormat::Bgra8 => bytes.copy_from_slice(&[color.b, color.g, color.r, color.a]),
+            PixelFormat::Rgb565 => {
+                let packed = ((color.r as u16 >> 3) << 11) | ((color.g as u16 >> 2) << 5) | (color.b as u16 >> 3);
+                bytes.copy_from_slice(&packed.to_le_bytes());
+            },
+            PixelFormat::Gray8 => bytes[0] = color.luminance(),
+            PixelFormat::RgbaF32 => {
+                for (i, channel) in [color.r, color.g, color.b, color.a].iter().enumerate() {
+                    bytes[i * 4..i * 4 + 4].copy_from_slice(&(*channel as f32 / 255.0).to_le_bytes());
+                }
+            },
+        }
+    }
+
+    /// Reads a color from 'bytes', which must be exactly one pixel long. HDR values are clamped.
+    pub fn decode(&self, bytes: &[u8]) -> Color {
+        match self {
+            PixelFormat::Rgba8 => Color::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+            PixelFormat::Bgra8 => Color::new(bytes[2], bytes[1], bytes[0], bytes[3]),
+            PixelFormat::Rgb565 => {
+                let packed = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let (r, g, b) = ((packed >> 11) as u8, ((packed >> 5) & 0x3F) as u8, (packed & 0x1F) as u8);
+
+                // Copy the top bits into the empty low bits so full brightness stays 255
+                Color::new((r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2), 255)
+            },
+            PixelFormat::Gray8 => Color::new(bytes[0], bytes[0], bytes[0], 255),
+            PixelFormat::RgbaF32 => {
+                let channel = |i: usize| {
+                    let value = f32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+                    (value * 255.0).round().clamp(0.0, 255.0) as u8
+                };
+                Color::new(channel(0), channel(1), channel(2), channel(3))
+            },
+        }
+    }
+
+    /// Writes red, green, blue and alpha as floats where 1.0 is full. Only RgbaF32 keeps values outside 0.0 to 1.0.
+    pub fn encode_f32(&self, value: [f32; 4], bytes: &mut [u8]) {
+        match self {
+            PixelFormat::RgbaF32 => {
+                for (i, channel) in value.iter().enumerate() {
+                    bytes[i * 4..i * 4 + 4].copy_from_slice(&channel.to_le_bytes());
+                }
+            },
+            _ => {
+                let channel = |v: f32| (v * 255.0).round().clamp(0.0, 255.0) as u8;
+                self.encode(Color::new(channel(value[0]), channel(value[1]), channel(value[2]), channel(value[3])), bytes);
+            },
+        }
+    }
+
+    /// Reads red, green, blue and alpha as floats where 1.0 is full.
+    pub fn decode_f32(&self, bytes: &[u8]) -> [f32; 4] {
+        match self {
+            PixelFormat::RgbaF32 => {
+                let channel = |i: usize| f32::from_le_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+                [channel(0), channel(1), channel(2), channel(3)]
+            },
+            _ => {
+                let color = self.decode(bytes);
+                [color.r as f32 / 255.0, color.g as f32 / 255.0, color.b as f32 / 255.0, color.a as f32 / 255.0]
+            },
+        }
+    }
+
+    /// Reads the pixel at x, y of an image 'width' pixels wide stored in this format. The pixel must be inside the image.
+    pub fn read(&self, pixels: &[u8], width: usize, x: i32, y: i32) -> Color {
+        let bpp = self.bytes_per_pixel();
+        let idx = (y as usize * width + x as usize) * bpp;
+        self.decode(&pixels[idx..idx + bpp])
+    }
+
+    /// Converts a whole image from one format to another.
+    pub fn convert(pixels: &[u8], from: PixelFormat, to: PixelFormat) -> Vec<u8> {
+        if from == to { return pixels.to_vec(); }
+
+        let count = pixels.len() / from.bytes_per_pixel();
+        let mut converted = vec![0; count * to.bytes_per_pixel()];
+        for (src, dst) in pixels.chunks_exact(from.bytes_per_pixel()).zip(converted.chunks_exact_mut(to.bytes_per_pixel())) {
+            to.encode(from.decode(src), dst);
+        }
+        converted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::Buffer;
+
+    const FORMATS: [PixelFormat; 5] = [PixelFormat::Rgba8, PixelFormat::Bgra8, PixelFormat::Rgb565, PixelFormat::Gray8, PixelFormat::RgbaF32];
+
+    fn round_trip(format: PixelFormat, color: Color) -> Color {
+        let mut bytes = vec![0; format.bytes_per_pixel()];
+        format.encode(color, &mut bytes);
+        format.decode(&bytes)
+    }
+
+    fn sample_colors() -> Vec<Color> {
+        let mut colors = vec![Color::TRANSPARENT, Color::WHITE, Color::BLACK, Color::RED, Color::GREEN, Color::BLUE];
+        for i in 0..64u32 {
+            colors.push(Color::new((i * 37 % 256) as u8, (i * 101 % 256) as u8, (i * 7 % 256) as u8, (i * 53 % 256) as u8));
+        }
+        colors
+    }
+
+    #[test]
+    fn byte_and_float_formats_round_trip_exactly() {
+        for color in sample_colors() {
+            assert_eq!(round_trip(PixelFormat::Rgba8, color), color);
+            assert_eq!(round_trip(PixelFormat::Bgra8, color), color);
+            assert_eq!(round_trip(PixelFormat::RgbaF32, color), color);
+        }
+    }
+
+    #[test]
+    fn bgra8_swaps_red_and_blue() {
+        let mut bytes = [0; 4];
+        PixelFormat::Bgra8.encode(Color::new(1, 2, 3, 4), &mut bytes);
+        assert_eq!(bytes, [3, 2, 1, 4]);
+    }
+
+    #[test]
+    fn rgb565_expands_to_full_range() {
+        assert_eq!(round_trip(PixelFormat::Rgb565, Color::WHITE), Color::WHITE);
+        assert_eq!(round_trip(PixelFormat::Rgb565, Color::new(0, 0, 0, 0)), Color::BLACK);
+
+        // The top bits are copied into the bits the format has no room for
+        assert_eq!(round_trip(PixelFormat::Rgb565, Color::new(0b1000_0111, 0b1000_0011, 0b0100_0111, 9)), Color::new(0b1000_0100, 0b1000_0010, 0b0100_0010, 255));
+
+        for color in sample_colors() {
+            let once = round_trip(PixelFormat::Rgb565, color);
+            assert_eq!(round_trip(PixelFormat::Rgb565, once), once);
+            assert!(color.r.abs_diff(once.r) <= 7 && color.g.abs_diff(once.g) <= 3 && color.b.abs_diff(once.b) <= 7);
+        }
+    }
+
+    #[test]
+    fn gray8_stores_luminance() {
+        for color in sample_colors() {
+            let l = color.luminance();
+            assert_eq!(round_trip(PixelFormat::Gray8, color), Color::new(l, l, l, 255));
+        }
+        assert_eq!(Color::WHITE.luminance(), 255);
+    }
+
+    #[test]
+    fn convert_matches_encoding_each_pixel() {
+        let colors = sample_colors();
+        let rgba: Vec<u8> = colors.iter().flat_map(|c| [c.r, c.g, c.b, c.a]).collect();
+
+        for to in FORMATS {
+            let converted = PixelFormat::convert(&rgba, PixelFormat::Rgba8, to);
+            assert_eq!(converted.len(), colors.len() * to.bytes_per_pixel());
+
+            for (pixel, color) in converted.chunks_exact(to.bytes_per_pixel()).zip(&colors) {
+                assert_eq!(to.decode(pixel), round_trip(to, *color), "{:?}", to);
+            }
+
+            let back = PixelFormat::convert(&converted, to, PixelFormat::Rgba8);
+            let expected: Vec<u8> = colors.iter().map(|c| round_trip(to, *c)).flat_map(|c| [c.r, c.g, c.b, c.a]).collect();
+            assert_eq!(back, expected, "{:?}", to);
+        }
+    }
+
+    #[test]
+    fn convert_clamps_hdr_values() {
+        let mut bytes = vec![0; 16];
+        PixelFormat::RgbaF32.encode_f32([4.0, 0.5, -1.0, 1.0], &mut bytes);
+
+        let converted = PixelFormat::convert(&bytes, PixelFormat::RgbaF32, PixelFormat::Rgba8);
+        assert_eq!(converted, vec![255, 128, 0, 255]);
+    }
+
+    #[test]
+    fn primitives_draw_the_same_in_every_format() {
+        let draw = |buffer: &mut Buffer| {
+            buffer.clear_color(Color::new(20, 40, 60, 255));
+            buffer.ptriangle(true, 2, 30, 28, 3, 30, 29, Color::new(200, 120, 40, 255));
+            buffer.pcircle(false, 16, 16, 9, Color::new(10, 250, 130, 255));
+        };
+
+        let mut reference = Buffer::new(32, 32);
+        draw(&mut reference);
+
+        for format in FORMATS {
+            let mut buffer = Buffer::new_with_format(32, 32, format);
+            draw(&mut buffer);
+
+            for y in 0..32 {
+                for x in 0..32 {
+                    assert_eq!(buffer.pget(x, y), round_trip(format, reference.pget(x, y)), "{:?} at {}, {}", format, x, y);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use rayon::prelude::*;
+
+use crate::buffer::Buffer;
+use crate::color::Color;
+
+/// A pass over a whole finished frame, like a blur or a CRT filter.
+/// Run one with Buffer::post_process or PartitionedBuffer::post_process. Every pass runs across rows in parallel.
+/// Passes can assume the buffer is Rgba8, Buffer::post_process converts other formats around them.
+pub trait PostProcess: Send + Sync {
+    fn apply(&self, buffer: &mut Buffer);
+}
+
+/// Averages every pixel with its neighbours in a square 'radius' pixels out. Cheaper than PostGaussianBlur.
+#[derive(Debug, Clone)]
+pub struct PostBoxBlur { pub radius: usize }
+
+impl PostProcess for PostBoxBlur {
+    fn apply(&self, buffer: &mut Buffer) {
+        if self.radius == 0 { return; }
+
+        let kernel = vec![1.0; self.radius * 2 + 1];
+        convolve_separable(buffer, &kernel);
+    }
+}
+
+/// Blurs with a gaussian falloff. 'sigma' is the spread in pixels, the blur reaches three times as far.
+#[derive(Debug, Clone)]
+pub struct PostGaussianBlur { pub sigma: f32 }
+
+impl PostProcess for PostGaussianBlur {
+    fn apply(&self, buffer: &mut Buffer) {
+        if self.sigma <= 0.0 { return; }
+        convolve_separable(buffer, &gaussian_kernel(self.sigma));
+    }
+}
+
+/// Makes bright parts of the frame glow. Pixels brighter than 'threshold' (0.0 - 1.0) are blurred
+/// by 'sigma' and added back on top, scaled by 'intensity'.
+#[derive(Debug, Clone)]
+pub struct PostBloom {
+    pub threshold: f32,
+    pub intensity: f32,
+    pub sigma: f32,
+}
+
+impl PostProcess for PostBloom {
+    fn apply(&self, buffer: &mut Buffer) {
+        let mut bright = Buffer::new(buffer.width, buffer.height);
+        bright.color.copy_from_slice(&buffer.color);
+
+        let threshold = self.threshold;
+        map_pixels(&mut bright, |_, _, c| {
+            if luminance(c) > threshold { c } else { Color::new(0, 0, 0, c.a) }
+        });
+
+        if self.sigma > 0.0 {
+            convolve_separable(&mut bright, &gaussian_kernel(self.sigma));
+        }
+
+        let intensity = self.intensity;
+        let width = buffer.width;
+        buffer.color.par_chunks_exact_mut(width.max(1) * 4).zip(bright.color.par_chunks_exact(width.max(1) * 4)).for_each(|(row, glow)| {
+            for (pixel, light) in row.chunks_exact_mut(4).zip(glow.chunks_exact(4)) {
+                for c in 0..3 {
+                    pixel[c] = (pixel[c] as f32 + light[c] as f32 * intensity).clamp(0.0, 255.0) as u8;
+                }
+            }
+        });
+    }
+}
+
+/// Imitates an old CRT screen. 'curvature' bends the picture like the glass of the tube (0.0 is flat),
+/// 'scanline_intensity' darkens every other row and 'chromatic_aberration' splits the red and blue
+/// channels apart by that many pixels towards the edges of the screen.
+#[derive(Debug, Clone)]
+pub struct PostCrt {
+    pub curvature: f32,
+    pub scanline_intensity: f32,
+    pub chromatic_aberration: f32,
+}
+
+impl PostProcess for PostCrt {
+    fn apply(&self, buffer: &mut Buffer) {
+        let (width, height) = (buffer.width as f32, buffer.height as f32);
+        if width < 1.0 || height < 1.0 { return; }
+
+        let curvature = self.curvature;
+        let aberration = self.chromatic_aberration;
+        let scanlines = self.scanline_intensity.clamp(0.0, 1.0);
+
+        map_from_source(buffer, |source, x, y| {
+            // Centered coordinates from -1.0 to 1.0, pushed outwards the further they are from the middle
+            let cx = (x as f32 + 0.5) / width * 2.0 - 1.0;
+            let cy = (y as f32 + 0.5) / height * 2.0 - 1.0;
+            let bend = 1.0 + curvature * (cx * cx + cy * cy);
+            let (ux, uy) = (cx * bend, cy * bend);
+
+            if ux.abs() > 1.0 || uy.abs() > 1.0 { return Color::new(0, 0, 0, 255); }
+
+            let to_pixel = |u: f32, v: f32| -> (i32, i32) {
+                (((u + 1.0) * 0.5 * width) as i32, ((v + 1.0) * 0.5 * height) as i32)
+            };
+
+            // Red and blue drift apart along the direction from the center
+            let (px, py) = to_pixel(ux, uy);
+            let shift_x = ux * aberration / width * 2.0;
+            let shift_y = uy * aberration / height * 2.0;
+            let (rx, ry) = to_pixel(ux + shift_x, uy + shift_y);
+            let (bx, by) = to_pixel(ux - shift_x, uy - shift_y);
+
+            let center = sample_clamped(source, px, py);
+            let mut color = Color::new(
+                sample_clamped(source, rx, ry).r,
+                center.g,
+                sample_clamped(source, bx, by).b,
+                center.a,
+            );
+
+            if y % 2 == 1 {
+                color = scale_rgb(color, 1.0 - scanlines);
+            }
+            color
+        });
+    }
+}
+
+/// Darkens the frame towards 'color' around the edges. 'radius' is where the darkening starts and
+/// 'softness' how far it takes to fade in, both as a fraction of the distance from the center to a corner.
+#[derive(Debug, Clone)]
+pub struct PostVignette {
+    pub intensity: f32,
+    pub radius: f32,
+    pub softness: f32,
+    pub color: Color,
+}
+
+impl PostProcess for PostVignette {
+    fn apply(&self, buffer: &mut Buffer) {
+        let (half_w, half_h) = (buffer.width as f32 * 0.5, buffer.height as f32 * 0.5);
+        let corner = (half_w * half_w + half_h * half_h).sqrt().max(1.0);
+
+        let (radius, softness, intensity, tint) = (self.radius, self.softness.max(0.0001), self.intensity.clamp(0.0, 1.0), self.color);
+        map_pixels(buffer, |x, y, c| {
+            let (dx, dy) = (x as f32 + 0.5 - half_w, y as f32 + 0.5 - half_h);
+            let distance = (dx * dx + dy * dy).sqrt() / corner;
+
+            let t = smoothstep(radius, radius + softness, distance) * intensity;
+            Color::new(
+                lerp_u8(c.r, tint.r, t),
+                lerp_u8(c.g, tint.g, t),
+                lerp_u8(c.b, tint.b, t),
+                c.a,
+            )
+        });
+    }
+}
+
+/// Turns the frame into big square pixels 'size' pixels wide, each one the average of the pixels it covers.
+#[derive(Debug, Clone)]
+pub struct PostPixelate { pub size: usize }
+
+impl PostProcess for PostPixelate {
+    fn apply(&self, buffer: &mut Buffer) {
+        let size = self.size;
+        let width = buffer.width;
+        if size <= 1 || width == 0 { return; }
+
+        // Each band of rows is as tall as a block, so blocks never cross between threads
+        buffer.color.par_chunks_mut(width * 4 * size).for_each(|band| {
+            let rows = band.len() / (width * 4);
+
+            for bx in (0..width).step_by(size) {
+                let bw = usize::min(size, width - bx);
+
+                let mut sum = [0u32; 4];
+                for y in 0..rows {
+                    for x in bx..bx + bw {
+                        let idx = (y * width + x) * 4;
+                        for c in 0..4 { sum[c] += band[idx + c] as u32; }
+                    }
+                }
+
+                let count = (bw * rows) as u32;
+                let average = [(sum[0] / count) as u8, (sum[1] / count) as u8, (sum[2] / count) as u8, (sum[3] / count) as u8];
+                for y in 0..rows {
+                    for x in bx..bx + bw {
+                        let idx = (y * width + x) * 4;
+                        band[idx..idx + 4].copy_from_slice(&average);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Cuts each color channel down to 'levels' steps.
+#[derive(Debug, Clone)]
+pub struct PostPosterize { pub levels: u8 }
+
+impl PostProcess for PostPosterize {
+    fn apply(&self, buffer: &mut Buffer) {
+        if self.levels < 2 { return; }
+
+        let steps = (self.levels - 1) as f32;
+        let quantize = |v: u8| -> u8 { ((v as f32 / 255.0 * steps).round() / steps * 255.0) as u8 };
+        map_pixels(buffer, |_, _, c| Color::new(quantize(c.r), quantize(c.g), quantize(c.b), c.a));
+    }
+}
+
+/// Remaps every color through a 3D lookup table, blended with the original by 'strength' (0.0 - 1.0).
+#[derive(Debug, Clone)]
+pub struct PostColorGrade {
+    pub lut: ColorLut,
+    pub strength: f32,
+}
+
+impl PostProcess for PostColorGrade {
+    fn apply(&self, buffer: &mut Buffer) {
+        let strength = self.strength.clamp(0.0, 1.0);
+        map_pixels(buffer, |_, _, c| {
+            let graded = self.lut.sample(c);
+            Color::new(
+                lerp_u8(c.r, graded.r, strength),
+                lerp_u8(c.g, graded.g, strength),
+                lerp_u8(c.b, graded.b, strength),
+                c.a,
+            )
+        });
+    }
+}
+
+/// Makes edges crisper by pushing each pixel away from the average of its neighbours.
+#[derive(Debug, Clone)]
+pub struct PostSharpen { pub amount: f32 }
+
+impl PostProcess for PostSharpen {
+    fn apply(&self, buffer: &mut Buffer) {
+        let amount = self.amount;
+        map_from_source(buffer, |source, x, y| {
+            let center = sample_clamped(source, x, y);
+
+            let mut sum = [0.0f32; 3];
+            for oy in -1..=1 {
+                for ox in -1..=1 {
+                    let c = sample_clamped(source, x + ox, y + oy);
+                    sum[0] += c.r as f32;
+                    sum[1] += c.g as f32;
+                    sum[2] += c.b as f32;
+                }
+            }
+
+            let sharpen = |v: u8, blurred: f32| -> u8 { (v as f32 + (v as f32 - blurred / 9.0) * amount).clamp(0.0, 255.0) as u8 };
+            Color::new(sharpen(center.r, sum[0]), sharpen(center.g, sum[1]), sharpen(center.b, sum[2]), center.a)
+        });
+    }
+}
+
+/// Adds random noise over the frame. Change 'seed' every frame for moving grain.
+#[derive(Debug, Clone)]
+pub struct PostFilmGrain {
+    pub intensity: f32,
+    pub seed: u32,
+}
+
+impl PostProcess for PostFilmGrain {
+    fn apply(&self, buffer: &mut Buffer) {
+        let (intensity, seed) = (self.intensity * 255.0, self.seed);
+        map_pixels(buffer, |x, y, c| {
+            let noise = (hash(x as u32, y as u32, seed) as f32 / u32::MAX as f32 - 0.5) * intensity;
+            let grain = |v: u8| -> u8 { (v as f32 + noise).clamp(0.0, 255.0) as u8 };
+            Color::new(grain(c.r), grain(c.g), grain(c.b), c.a)
+        });
+    }
+}
+
+/// A 3D color lookup table, 'size' entries along each of the red, green and blue axis.
+/// The fields are private so the table always holds size * size * size colors.
+#[derive(Debug, Clone)]
+pub struct ColorLut {
+    size: usize,
+    colors: Vec<Color>,
+}
+
+impl ColorLut {
+    /// A table that maps every color to itself, as a starting point for grading.
+    pub fn new_identity(size: usize) -> ColorLut {
+        let size = size.max(2);
+        let step = 255.0 / (size - 1) as f32;
+
+        let mut colors: Vec<Color> = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    colors.push(Color::new((r as f32 * step).round() as u8, (g as f32 * step).round() as u8, (b as f32 * step).round() as u8, 255));
+                }
+            }
+        }
+
+        ColorLut { size, colors }
+    }
+
+    /// Reads a table laid out as a strip of square slices, the usual format LUT images are exported in.
+    /// The image is 'size' * 'size' wide and 'size' tall, red goes right inside a slice, green goes down
+    /// and blue goes up with each slice.
+    pub fn new_from_strip(image: &Buffer) -> Result<ColorLut, String> {
+        let size = image.height;
+        if size < 2 || image.width != size * size {
+            return Err(format!("ERROR - COLOR LUT: A {} x {} image is not a LUT strip, it should be size * size wide and size tall", image.width, image.height));
+        }
+
+        let mut colors: Vec<Color> = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    colors.push(image.pget((b * size + r) as i32, g as i32));
+                }
+            }
+        }
+
+        Ok(ColorLut { size, colors })
+    }
+
+    pub fn new_from_image(path_to: &str) -> Result<ColorLut, String> {
+        let image = Buffer::new_from_image(path_to)?;
+        ColorLut::new_from_strip(&image)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Every entry with red changing fastest, then green, then blue.
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    /// Lets entries be graded in place. The number of entries can't change.
+    pub fn colors_mut(&mut self) -> &mut [Color] {
+        &mut self.colors
+    }
+
+    /// Looks up a color, blending between the nearest entries of the table.
+    pub fn sample(&self, color: Color) -> Color {
+        let scale = (self.size - 1) as f32 / 255.0;
+        let (r, g, b) = (color.r as f32 * scale, color.g as f32 * scale, color.b as f32 * scale);
+
+        let (r0, g0, b0) = (r as usize, g as usize, b as usize);
+        let (r1, g1, b1) = (usize::min(r0 + 1, self.size - 1), usize::min(g0 + 1, self.size - 1), usize::min(b0 + 1, self.size - 1));
+        let (tr, tg, tb) = (r - r0 as f32, g - g0 as f32, b - b0 as f32);
+
+        let at = |r: usize, g: usize, b: usize| -> [f32; 3] {
+            let c = self.colors[(b * self.size + g) * self.size + r];
+            [c.r as f32, c.g as f32, c.b as f32]
+        };
+        let mix = |a: [f32; 3], b: [f32; 3], t: f32| -> [f32; 3] {
+            [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+        };
+
+        let c00 = mix(at(r0, g0, b0), at(r1, g0, b0), tr);
+        let c10 = mix(at(r0, g1, b0), at(r1, g1, b0), tr);
+        let c01 = mix(at(r0, g0, b1), at(r1, g0, b1), tr);
+        let c11 = mix(at(r0, g1, b1), at(r1, g1, b1), tr);
+        let c = mix(mix(c00, c10, tg), mix(c01, c11, tg), tb);
+
+        Color::new(c[0].round() as u8, c[1].round() as u8, c[2].round() as u8, color.a)
+    }
+}
+
+// Runs 'function' on every pixel in parallel. Pixels only see their own old value.
+fn map_pixels<F>(buffer: &mut Buffer, function: F) where F: Fn(i32, i32, Color) -> Color + Sync {
+    let width = buffer.width;
+    if width == 0 { return; }
+
+    buffer.color.par_chunks_exact_mut(width * 4).enumerate().for_each(|(y, row)| {
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            let c = function(x as i32, y as i32, Color::new(pixel[0], pixel[1], pixel[2], pixel[3]));
+            pixel.copy_from_slice(&c.into_chunk());
+        }
+    });
+}
+
+// Runs 'function' on every pixel in parallel, with a copy of the frame from before the pass to read neighbours from
+fn map_from_source<F>(buffer: &mut Buffer, function: F) where F: Fn(&Source, i32, i32) -> Color + Sync {
+    let width = buffer.width;
+    if width == 0 { return; }
+
+    let source = Source { color: buffer.color.clone(), width: buffer.width as i32, height: buffer.height as i32 };
+    buffer.color.par_chunks_exact_mut(width * 4).enumerate().for_each(|(y, row)| {
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            pixel.copy_from_slice(&function(&source, x as i32, y as i32).into_chunk());
+        }
+    });
+}
+
+struct Source {
+    color: Vec<u8>,
+    width: i32,
+    height: i32,
+}
+
+fn sample_clamped(source: &Source, x: i32, y: i32) -> Color {
+    let x = x.clamp(0, source.width - 1);
+    let y = y.clamp(0, source.height - 1);
+    let idx = ((y * source.width + x) * 4) as usize;
+    Color::new(source.color[idx], source.color[idx + 1], source.color[idx + 2], source.color[idx + 3])
+}
+
+// Blurs rows then columns with the same kernel, which is the same as a 2D blur for box and gaussian kernels
+fn convolve_separable(buffer: &mut Buffer, kernel: &[f32]) {
+    let (width, height) = (buffer.width, buffer.height);
+    if width == 0 || height == 0 { return; }
+
+    let total: f32 = kernel.iter().sum();
+    let weights: Vec<f32> = kernel.iter().map(|k| k / total).collect();
+    let radius = (weights.len() / 2) as i32;
+
+    // Horizontal pass into floats so rounding only happens once
+    let mut horizontal: Vec<f32> = vec![0.0; width * height * 4];
+    horizontal.par_chunks_exact_mut(width * 4).zip(buffer.color.par_chunks_exact(width * 4)).for_each(|(out, row)| {
+        for x in 0..width as i32 {
+            let mut sum = [0.0f32; 4];
+            for (i, weight) in weights.iter().enumerate() {
+                let sx = (x + i as i32 - radius).clamp(0, width as i32 - 1) as usize;
+                for c in 0..4 { sum[c] += row[sx * 4 + c] as f32 * weight; }
+            }
+            out[x as usize * 4..x as usize * 4 + 4].copy_from_slice(&sum);
+        }
+    });
+
+    buffer.color.par_chunks_exact_mut(width * 4).enumerate().for_each(|(y, out)| {
+        for x in 0..width {
+            let mut sum = [0.0f32; 4];
+            for (i, weight) in weights.iter().enumerate() {
+                let sy = (y as i32 + i as i32 - radius).clamp(0, height as i32 - 1) as usize;
+                let idx = (sy * width + x) * 4;
+                for c in 0..4 { sum[c] += horizontal[idx + c] * weight; }
+            }
+            for c in 0..4 { out[x * 4 + c] = sum[c].round().clamp(0.0, 255.0) as u8; }
+        }
+    });
+}
+
+pub(crate) fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil() as i32;
+    (-radius..=radius).map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp()).collect()
+}
+
+fn luminance(c: Color) -> f32 {
+    (0.2126 * c.r as f32 + 0.7152 * c.g as f32 + 0.0722 * c.b as f32) / 255.0
+}
+
+fn scale_rgb(c: Color, scale: f32) -> Color {
+    Color::new((c.r as f32 * scale) as u8, (c.g as f32 * scale) as u8, (c.b as f32 * scale) as u8, c.a)
+}
+
+pub(crate) fn lerp_u8(a: u8, b: u8, t: f32) -> u8 {
+    (a as f32 + (b as f32 - a as f32) * t).round().clamp(0.0, 255.0) as u8
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Cheap integer hash for noise that doesn't need to be good, just different for every pixel and seed
+fn hash(x: u32, y: u32, seed: u32) -> u32 {
+    let mut h = x.wrapping_mul(0x8da6_b343) ^ y.wrapping_mul(0xd816_3841) ^ seed.wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^ (h >> 15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_lut_leaves_colors_alone() {
+        let lut = ColorLut::new_identity(17);
+        assert_eq!(lut.colors().len(), 17 * 17 * 17);
+
+        for color in [Color::new(0, 0, 0, 255), Color::new(255, 255, 255, 9), Color::new(12, 200, 97, 128)] {
+            assert_eq!(lut.sample(color), color);
+        }
+    }
+
+    #[test]
+    fn graded_entries_are_sampled() {
+        let mut lut = ColorLut::new_identity(2);
+        let last = lut.colors().len() - 1;
+        lut.colors_mut()[last] = Color::new(255, 0, 0, 255);
+
+        assert_eq!(lut.sample(Color::WHITE), Color::new(255, 0, 0, 255));
+        assert_eq!(lut.size(), 2);
+    }
+}
@@ -1,36 +1,210 @@
 use std::ops::DerefMut;
+use std::collections::HashMap;
+use std::sync::Arc;
 use crate::buffer::*;
+use crate::pixel_format::PixelFormat;
 
 use dyn_clone::DynClone;
 dyn_clone::clone_trait_object!(Shader);
+dyn_clone::clone_trait_object!(SpanShader);
 
 use crate::color::*;
 
+/// Values interpolated across the primitive being drawn, different for every pixel.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShaderVaryings {
+    /// Weights of the three corners of the triangle at this pixel. All zero for primitives that aren't triangles.
+    pub barycentric: [f32; 3],
+
+    /// Where the pixel is across the image or shape being drawn, from 0.0 to 1.0.
+    /// Images and textured triangles give the texture coordinate sampled, rectangles and circles give the position
+    /// inside their bounds and lines give how far along the line the pixel is in 'uv[0]'.
+    pub uv: [f32; 2],
+
+    /// Interpolated w of the corners of a ptritex_uvw triangle, lower is closer. Zero for everything else.
+    pub depth: f32,
+}
+
+/// Values set by the user that stay the same across a whole primitive.
+/// Set Buffer::uniforms before drawing, every primitive after that hands them to the shader stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShaderUniforms {
+    pub floats: [f32; 8],
+    pub ints: [i32; 8],
+    pub colors: [Color; 8],
+}
+
+impl Default for ShaderUniforms {
+    fn default() -> Self {
+        ShaderUniforms { floats: [0.0; 8], ints: [0; 8], colors: [Color::TRANSPARENT; 8] }
+    }
+}
+
+/// Everything a shader knows about the pixel being drawn.
 #[derive(Debug, Clone, Copy)]
 pub struct ShaderParams {
     pub x: i32,
     pub y: i32,
     pub color: Color,
-    pub p_f32: [f32; 8],
-    pub p_i32: [i32; 8],
-    pub p_color: [Color; 8]
+    pub varyings: ShaderVaryings,
+
+    /// Counts up by one with every primitive drawn into the buffer. See Buffer::primitive_id.
+    pub primitive_id: u32,
+    pub uniforms: ShaderUniforms,
+
+    /// How the buffer handed to the shader stores its pixels. Read it with PixelFormat::read.
+    pub format: PixelFormat,
 }
 
 impl ShaderParams {
     pub fn new(x: i32, y: i32, color: Color) -> ShaderParams {
-        ShaderParams { x, y, color, p_f32: [0.0; 8], p_i32: [0; 8], p_color: [Color::CLEAR; 8] }
+        ShaderParams { x, y, color, varyings: ShaderVaryings::default(), primitive_id: 0, uniforms: ShaderUniforms::default(), format: PixelFormat::Rgba8 }
     }
+
+    /// The old user values, now kept in 'uniforms'. Texture coordinates and depth from ptritex_uvw moved to 'varyings'.
+    #[deprecated(note = "Use uniforms.floats, or varyings.uv and varyings.depth for ptritex_uvw texels and depth")]
+    pub fn p_f32(&self) -> [f32; 8] {
+        self.uniforms.floats
+    }
+
+    #[deprecated(note = "Use uniforms.ints")]
+    pub fn p_i32(&self) -> [i32; 8] {
+        self.uniforms.ints
+    }
+
+    #[deprecated(note = "Use uniforms.colors")]
+    pub fn p_color(&self) -> [Color; 8] {
+        self.uniforms.colors
+    }
+}
+
+/// A row of pixels being shaded by a SpanShader.
+pub struct ShaderSpan<'a> {
+    pub colors: &'a mut [Color],
+
+    /// Set a pixel's entry to drop it. Pixels already discarded by an earlier shader can be skipped.
+    pub discarded: &'a mut [bool],
+
+    /// The varyings of each pixel, or empty if the primitive doesn't have any.
+    pub varyings: &'a [ShaderVaryings],
 }
+
+/// A shader in a Buffer's shader stack. The stack runs in order on every pixel a primitive draws, each shader
+/// seeing the color left by the one before it, and the pixel is written once after the last shader.
+///
+/// Returning Some passes the pixel on, moved or recolored. Returning None discards it: the rest of the stack
+/// is skipped and nothing is written. To leave a pixel as it is, return it unchanged.
 pub trait Shader: DynClone + Send + Sync {
     fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)>;
+
+    /// Clears anything the shader built up over a frame, like a depth buffer. Called by Buffer::begin_frame.
+    /// Settings given by the user should be kept.
+    fn reset(&mut self);
+}
+
+/// Shades a whole row of pixels per call instead of one pixel at a time, so the shader stack is only
+/// dispatched once per span and the loop over 'colors' can be vectorised.
+///
+/// Every Shader is a SpanShader already, shading each pixel of the span in turn.
+/// Pixels can't be moved inside a span, so positions returned by a Shader are ignored when it runs this way.
+pub trait SpanShader: DynClone + Send + Sync {
+    /// Shades the span's colors in place. The first color is at the x and y in 'params' and the rest follow it to the right.
+    fn shade_span(&mut self, buffer: &[u8], width: usize, height: usize, span: ShaderSpan, params: ShaderParams);
+
+    /// Shades a single pixel, for primitives that don't draw in spans. Defaults to a span one pixel long.
+    /// None discards the pixel, the same as for Shader::shade.
+    fn shade_pixel(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+        let mut colors = [params.color];
+        let mut discarded = [false];
+        self.shade_span(buffer, width, height, ShaderSpan { colors: &mut colors, discarded: &mut discarded, varyings: &[params.varyings] }, params);
+        if discarded[0] { None } else { Some((params.x, params.y, colors[0])) }
+    }
+
+    /// See Shader::reset.
     fn reset(&mut self);
 }
 
+impl<T: Shader> SpanShader for T {
+    fn shade_span(&mut self, buffer: &[u8], width: usize, height: usize, span: ShaderSpan, params: ShaderParams) {
+        let mut params = params;
+        let x_start = params.x;
+
+        for (i, (color, discard)) in span.colors.iter_mut().zip(span.discarded.iter_mut()).enumerate() {
+            if *discard { continue; }
+
+            params.x = x_start + i as i32;
+            params.color = *color;
+            if let Some(pixel_varyings) = span.varyings.get(i) {
+                params.varyings = *pixel_varyings;
+            }
+            match self.shade(buffer, width, height, params) {
+                Some((_, _, shaded)) => *color = shaded,
+                None => *discard = true,
+            }
+        }
+    }
+
+    fn shade_pixel(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+        self.shade(buffer, width, height, params)
+    }
+
+    fn reset(&mut self) {
+        Shader::reset(self);
+    }
+}
+
+
+
+/// What a closure shader can see of the pixel being drawn.
+pub struct ShaderContext<'a> {
+    pub buffer: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    pub params: ShaderParams,
+}
 
+impl<'a> ShaderContext<'a> {
+    pub fn x(&self) -> i32 { self.params.x }
+    pub fn y(&self) -> i32 { self.params.y }
+    pub fn color(&self) -> Color { self.params.color }
+
+    /// The color already in the buffer under the pixel. Transparent if the pixel is outside the buffer.
+    pub fn background(&self) -> Color {
+        let (x, y) = (self.params.x, self.params.y);
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 { return Color::TRANSPARENT; }
+
+        self.params.format.read(self.buffer, self.width, x, y)
+    }
+}
+
+pub type ShaderClosure = dyn Fn(&ShaderContext) -> Option<Color> + Send + Sync;
+
+/// A closure used as a shader, for one-off effects that don't need their own struct.
+/// Returning None discards the pixel, leaving what was already in the buffer. Clones share the same closure, so it can be handed to every
+/// partition of a PartitionedBuffer.
+#[derive(Clone)]
+pub struct ShaderFn {
+    pub function: Arc<ShaderClosure>,
+}
+
+impl ShaderFn {
+    pub fn new<F>(function: F) -> ShaderFn where F: Fn(&ShaderContext) -> Option<Color> + Send + Sync + 'static {
+        ShaderFn { function: Arc::new(function) }
+    }
+}
+
+impl Shader for ShaderFn {
+    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+        let context = ShaderContext { buffer, width, height, params };
+        (self.function)(&context).map(|color| (params.x, params.y, color))
+    }
+
+    fn reset(&mut self) {}
+}
 
 #[derive(Debug, Clone)]
 pub struct ShaderNoOp; impl Shader for ShaderNoOp {
-    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+    fn shade(&mut self, _buffer: &[u8], _width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
         Some((params.x, params.y, params.color))
     }
 
@@ -39,7 +213,7 @@ pub struct ShaderNoOp; impl Shader for ShaderNoOp {
 
 #[derive(Debug, Clone)]
 pub struct ShaderOpaque; impl Shader for ShaderOpaque {
-    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+    fn shade(&mut self, _buffer: &[u8], _width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
 
         if params.color.a < 255 { return None; } else { return Some((params.x, params.y, params.color)) }
     }
@@ -49,25 +223,18 @@ pub struct ShaderOpaque; impl Shader for ShaderOpaque {
 
 #[derive(Debug, Clone)]
 pub struct ShaderForceColor { pub color: Color } impl Shader for ShaderForceColor {
-    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+    fn shade(&mut self, _buffer: &[u8], _width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
 
         Some((params.x, params.y, self.color))
     }
 
-    fn reset(&mut self) {self.color = Color::CLEAR; }
+    fn reset(&mut self) {}
 }
 
 #[derive(Debug, Clone)]
 pub struct ShaderMultiply; impl Shader for ShaderMultiply {
-    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
-        let idx: usize = ((params.y * (width as i32) + params.x) * 4) as usize;
-
-        let bg = Color::new(
-            buffer[idx],
-            buffer[idx + 1],
-            buffer[idx + 2],
-            255
-        );
+    fn shade(&mut self, buffer: &[u8], width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+        let bg = Color { a: 255, ..params.format.read(buffer, width, params.x, params.y) };
 
         Some((params.x, params.y, bg * params.color))
     }
@@ -77,7 +244,7 @@ pub struct ShaderMultiply; impl Shader for ShaderMultiply {
 
 #[derive(Debug, Clone)]
 pub struct ShaderTint {pub tint: Color} impl Shader for ShaderTint {
-    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+    fn shade(&mut self, _buffer: &[u8], _width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
         Some((params.x, params.y, self.tint * params.color))
     }
 
@@ -86,16 +253,9 @@ pub struct ShaderTint {pub tint: Color} impl Shader for ShaderTint {
 
 #[derive(Debug, Clone)]
 pub struct ShaderAddition; impl Shader for ShaderAddition {
-    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+    fn shade(&mut self, buffer: &[u8], width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
 
-        let idx: usize = ((params.y * (width as i32) + params.x) * 4) as usize;
-
-        let bg = Color::new(
-            buffer[idx],
-            buffer[idx + 1],
-            buffer[idx + 2],
-            255
-        );
+        let bg = Color { a: 255, ..params.format.read(buffer, width, params.x, params.y) };
 
         Some((params.x, params.y, bg + params.color))
     }
@@ -105,20 +265,121 @@ pub struct ShaderAddition; impl Shader for ShaderAddition {
 
 #[derive(Debug, Clone)]
 pub struct ShaderAlpha { pub opacity: u8 } impl Shader for ShaderAlpha {
-    fn shade(&mut self, buffer: &[u8], width: usize, height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
-        let idx: usize = ((params.y * (width as i32) + params.x) * 4) as usize;
-
-        let bg = Color::new(
-            buffer[idx],
-            buffer[idx + 1],
-            buffer[idx + 2],
-            255
-        );
+    fn shade(&mut self, buffer: &[u8], width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+        let bg = Color { a: 255, ..params.format.read(buffer, width, params.x, params.y) };
 
         let c = Color::blend_fast(params.color, bg, self.opacity);
 
         Some((params.x, params.y, c))
     }
 
-    fn reset(&mut self) {self.opacity = 255; }
-}
\ No newline at end of file
+    fn reset(&mut self) {}
+}
+
+/// Swaps exact colors for others, for recolouring one sprite into enemy variants or team colours.
+/// Colors not in the palette pass through. Holds several palettes, pick one with 'palette', or per draw with
+/// 'palette_uniform' which reads the palette index from that slot of ShaderUniforms::ints.
+#[derive(Debug, Clone)]
+pub struct ShaderPaletteSwap {
+    pub palettes: Vec<HashMap<Color, Color>>,
+    pub palette: usize,
+    pub palette_uniform: Option<usize>,
+}
+
+impl ShaderPaletteSwap {
+    /// Makes a palette swap with a single palette.
+    pub fn new(map: HashMap<Color, Color>) -> ShaderPaletteSwap {
+        ShaderPaletteSwap { palettes: vec![map], palette: 0, palette_uniform: None }
+    }
+
+    /// Makes a palette swap from a lookup image. The top row holds the colors to replace and every row under
+    /// it is one palette, so palette 0 is the second row.
+    pub fn new_from_lut(lut: &Buffer) -> Result<ShaderPaletteSwap, String> {
+        if lut.height < 2 { return Err(String::from("ERROR - ShaderPaletteSwap: Lookup image needs a source row and at least one palette row")); }
+
+        let mut palettes: Vec<HashMap<Color, Color>> = Vec::new();
+        for row in 1..lut.height as i32 {
+            let mut map = HashMap::new();
+            for x in 0..lut.width as i32 {
+                map.insert(lut.pget(x, 0), lut.pget(x, row));
+            }
+            palettes.push(map);
+        }
+
+        Ok(ShaderPaletteSwap { palettes, palette: 0, palette_uniform: None })
+    }
+}
+
+impl Shader for ShaderPaletteSwap {
+    fn shade(&mut self, _buffer: &[u8], _width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+        let palette = match self.palette_uniform {
+            Some(slot) => params.uniforms.ints.get(slot).map_or(self.palette, |i| (*i).max(0) as usize),
+            None => self.palette,
+        };
+
+        let color = self.palettes.get(palette).and_then(|map| map.get(&params.color)).copied().unwrap_or(params.color);
+        Some((params.x, params.y, color))
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Maps the brightness of each pixel onto a color ramp, dark to the first color and bright to the last.
+/// Alpha is kept from the pixel. The ramp is baked into 256 steps so shading is a single lookup.
+#[derive(Debug, Clone)]
+pub struct ShaderGradientMap {
+    pub colors: Vec<Color>,
+}
+
+impl ShaderGradientMap {
+    /// Makes a gradient map from evenly spaced color stops.
+    pub fn new(stops: &[Color]) -> Result<ShaderGradientMap, String> {
+        if stops.is_empty() { return Err(String::from("ERROR - ShaderGradientMap: Needs at least one color stop")); }
+
+        let colors = (0..256).map(|i| {
+            let t = i as f32 / 255.0 * (stops.len() - 1) as f32;
+            let (a, b) = (stops[t.floor() as usize], stops[t.ceil() as usize]);
+            let f = t.fract();
+            let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * f).round() as u8;
+            Color::new(lerp(a.r, b.r), lerp(a.g, b.g), lerp(a.b, b.b), lerp(a.a, b.a))
+        }).collect();
+
+        Ok(ShaderGradientMap { colors })
+    }
+
+    /// Makes a gradient map from the top row of an image, left is dark and right is bright.
+    pub fn new_from_image(ramp: &Buffer) -> Result<ShaderGradientMap, String> {
+        let stops: Vec<Color> = (0..ramp.width as i32).map(|x| ramp.pget(x, 0)).collect();
+        ShaderGradientMap::new(&stops)
+    }
+}
+
+impl Shader for ShaderGradientMap {
+    fn shade(&mut self, _buffer: &[u8], _width: usize, _height: usize, params: ShaderParams) -> Option<(i32, i32, Color)> {
+        let c = params.color;
+        let luminance = (c.r as u32 * 54 + c.g as u32 * 183 + c.b as u32 * 19) >> 8;
+        let mapped = self.colors[luminance as usize];
+
+        Some((params.x, params.y, Color::new(mapped.r, mapped.g, mapped.b, c.a)))
+    }
+
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(deprecated)]
+    fn old_user_values_read_the_uniforms() {
+        let mut params = ShaderParams::new(0, 0, Color::WHITE);
+        params.uniforms.floats[2] = 0.5;
+        params.uniforms.ints[1] = 7;
+        params.uniforms.colors[0] = Color::RED;
+
+        assert_eq!(params.p_f32()[2], 0.5);
+        assert_eq!(params.p_i32()[1], 7);
+        assert_eq!(params.p_color()[0], Color::RED);
+    }
+}
@@ -0,0 +1,284 @@
+//! Vectorised loops over raw RGBA bytes, used by Buffer for whole-buffer work like clears, tints and composites.
+//! The best instruction set the CPU has is picked at runtime, with a plain loop for everything else.
+//! Every level gives exactly the same bytes.
+
+use crate::color::{mul_byte, Color};
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Instruction sets the loops can run with, from slowest to fastest.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    Scalar,
+    Sse2,
+    Avx2,
+}
+
+impl SimdLevel {
+    /// The fastest level this CPU supports.
+    pub fn detect() -> SimdLevel {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") { return SimdLevel::Avx2; }
+            if is_x86_feature_detected!("sse2") { return SimdLevel::Sse2; }
+        }
+        SimdLevel::Scalar
+    }
+}
+
+/// Sets every pixel to 'color'.
+pub fn fill(pixels: &mut [u8], color: Color) {
+    fill_with(SimdLevel::detect(), pixels, color);
+}
+
+/// Multiplies every pixel's RGB by 'color', keeping its alpha. Matches Color * Color exactly.
+pub fn tint(pixels: &mut [u8], color: Color) {
+    tint_with(SimdLevel::detect(), pixels, color);
+}
+
+/// Mixes 'src' into 'dst' by each 'dst' pixel's alpha less 'opacity', leaving 'dst' opaque.
+/// Matches Buffer::pcomposite_alpha, with the alpha stopping at 0 instead of wrapping.
+pub fn blend_alpha(dst: &mut [u8], src: &[u8], opacity: u8) {
+    blend_alpha_with(SimdLevel::detect(), dst, src, opacity);
+}
+
+/// Same as fill, at a set level. Levels the CPU doesn't support fall back to the best one it does.
+pub fn fill_with(level: SimdLevel, pixels: &mut [u8], color: Color) {
+    match level.min(SimdLevel::detect()) {
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => unsafe { fill_avx2(pixels, color) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Sse2 => unsafe { fill_sse2(pixels, color) },
+        _ => fill_scalar(pixels, color),
+    }
+}
+
+/// Same as tint, at a set level. Levels the CPU doesn't support fall back to the best one it does.
+pub fn tint_with(level: SimdLevel, pixels: &mut [u8], color: Color) {
+    match level.min(SimdLevel::detect()) {
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => unsafe { tint_avx2(pixels, color) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Sse2 => unsafe { tint_sse2(pixels, color) },
+        _ => tint_scalar(pixels, color),
+    }
+}
+
+/// Same as blend_alpha, at a set level. Levels the CPU doesn't support fall back to the best one it does.
+pub fn blend_alpha_with(level: SimdLevel, dst: &mut [u8], src: &[u8], opacity: u8) {
+    let len = dst.len().min(src.len());
+    let (dst, src) = (&mut dst[..len], &src[..len]);
+
+    match level.min(SimdLevel::detect()) {
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Avx2 => unsafe { blend_alpha_avx2(dst, src, opacity) },
+        #[cfg(target_arch = "x86_64")]
+        SimdLevel::Sse2 => unsafe { blend_alpha_sse2(dst, src, opacity) },
+        _ => blend_alpha_scalar(dst, src, opacity),
+    }
+}
+
+fn fill_scalar(pixels: &mut [u8], color: Color) {
+    let chunk = color.into_chunk();
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel.copy_from_slice(&chunk);
+    }
+}
+
+fn tint_scalar(pixels: &mut [u8], color: Color) {
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel[0] = mul_byte(pixel[0], color.r);
+        pixel[1] = mul_byte(pixel[1], color.g);
+        pixel[2] = mul_byte(pixel[2], color.b);
+    }
+}
+
+fn blend_alpha_scalar(dst: &mut [u8], src: &[u8], opacity: u8) {
+    for (d, s) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        let alpha = d[3].saturating_sub(opacity) as u32;
+        d[0] = ((d[0] as u32 * alpha + s[0] as u32 * (255 - alpha)) >> 8) as u8;
+        d[1] = ((d[1] as u32 * alpha + s[1] as u32 * (255 - alpha)) >> 8) as u8;
+        d[2] = ((d[2] as u32 * alpha + s[2] as u32 * (255 - alpha)) >> 8) as u8;
+        d[3] = 255;
+    }
+}
+
+// The vector versions work on whole registers of pixels and hand whatever is left over to the scalar loop.
+// Channels are widened to 16 bits for the multiplies, which never overflow as every product is at most 255 * 256.
+// Tints round the same way as mul_byte: x = a * b + 128, then (x + (x >> 8)) >> 8, which stays below 65536.
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn fill_sse2(pixels: &mut [u8], color: Color) {
+    let value = _mm_set1_epi32(i32::from_le_bytes(color.into_chunk()));
+    let mut chunks = pixels.chunks_exact_mut(16);
+    for chunk in &mut chunks {
+        _mm_storeu_si128(chunk.as_mut_ptr() as *mut __m128i, value);
+    }
+    fill_scalar(chunks.into_remainder(), color);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn fill_avx2(pixels: &mut [u8], color: Color) {
+    let value = _mm256_set1_epi32(i32::from_le_bytes(color.into_chunk()));
+    let mut chunks = pixels.chunks_exact_mut(32);
+    for chunk in &mut chunks {
+        _mm256_storeu_si256(chunk.as_mut_ptr() as *mut __m256i, value);
+    }
+    fill_scalar(chunks.into_remainder(), color);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn tint_sse2(pixels: &mut [u8], color: Color) {
+    // Alpha is multiplied by 255, which the rounding hands back unchanged
+    let (r, g, b) = (color.r as i16, color.g as i16, color.b as i16);
+    let tint = _mm_setr_epi16(r, g, b, 255, r, g, b, 255);
+    let zero = _mm_setzero_si128();
+
+    let mut chunks = pixels.chunks_exact_mut(16);
+    for chunk in &mut chunks {
+        let v = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+        let lo = mul_bytes_sse2(_mm_unpacklo_epi8(v, zero), tint);
+        let hi = mul_bytes_sse2(_mm_unpackhi_epi8(v, zero), tint);
+        _mm_storeu_si128(chunk.as_mut_ptr() as *mut __m128i, _mm_packus_epi16(lo, hi));
+    }
+    tint_scalar(chunks.into_remainder(), color);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn tint_avx2(pixels: &mut [u8], color: Color) {
+    let (r, g, b) = (color.r as i16, color.g as i16, color.b as i16);
+    let tint = _mm256_setr_epi16(r, g, b, 255, r, g, b, 255, r, g, b, 255, r, g, b, 255);
+    let zero = _mm256_setzero_si256();
+
+    let mut chunks = pixels.chunks_exact_mut(32);
+    for chunk in &mut chunks {
+        let v = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+        let lo = mul_bytes_avx2(_mm256_unpacklo_epi8(v, zero), tint);
+        let hi = mul_bytes_avx2(_mm256_unpackhi_epi8(v, zero), tint);
+        _mm256_storeu_si256(chunk.as_mut_ptr() as *mut __m256i, _mm256_packus_epi16(lo, hi));
+    }
+    tint_scalar(chunks.into_remainder(), color);
+}
+
+// mul_byte on eight 16 bit channels
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+#[inline]
+unsafe fn mul_bytes_sse2(channels: __m128i, by: __m128i) -> __m128i {
+    let x = _mm_add_epi16(_mm_mullo_epi16(channels, by), _mm_set1_epi16(128));
+    _mm_srli_epi16::<8>(_mm_add_epi16(x, _mm_srli_epi16::<8>(x)))
+}
+
+// mul_byte on sixteen 16 bit channels
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn mul_bytes_avx2(channels: __m256i, by: __m256i) -> __m256i {
+    let x = _mm256_add_epi16(_mm256_mullo_epi16(channels, by), _mm256_set1_epi16(128));
+    _mm256_srli_epi16::<8>(_mm256_add_epi16(x, _mm256_srli_epi16::<8>(x)))
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn blend_alpha_sse2(dst: &mut [u8], src: &[u8], opacity: u8) {
+    let opacity16 = _mm_set1_epi16(opacity as i16);
+    let full = _mm_set1_epi16(255);
+    let opaque = _mm_set1_epi32(0xFF000000u32 as i32);
+    let zero = _mm_setzero_si128();
+
+    // Two pixels per 16-bit half, with each pixel's alpha copied across its four channels
+    let blend = |d: __m128i, s: __m128i| {
+        let alpha = _mm_subs_epu16(_mm_shufflehi_epi16::<0xFF>(_mm_shufflelo_epi16::<0xFF>(d)), opacity16);
+        let inverse = _mm_sub_epi16(full, alpha);
+        _mm_srli_epi16::<8>(_mm_add_epi16(_mm_mullo_epi16(d, alpha), _mm_mullo_epi16(s, inverse)))
+    };
+
+    let mut dst_chunks = dst.chunks_exact_mut(16);
+    let mut src_chunks = src.chunks_exact(16);
+    for (d_chunk, s_chunk) in (&mut dst_chunks).zip(&mut src_chunks) {
+        let d = _mm_loadu_si128(d_chunk.as_ptr() as *const __m128i);
+        let s = _mm_loadu_si128(s_chunk.as_ptr() as *const __m128i);
+        let lo = blend(_mm_unpacklo_epi8(d, zero), _mm_unpacklo_epi8(s, zero));
+        let hi = blend(_mm_unpackhi_epi8(d, zero), _mm_unpackhi_epi8(s, zero));
+        _mm_storeu_si128(d_chunk.as_mut_ptr() as *mut __m128i, _mm_or_si128(_mm_packus_epi16(lo, hi), opaque));
+    }
+    blend_alpha_scalar(dst_chunks.into_remainder(), src_chunks.remainder(), opacity);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn blend_alpha_avx2(dst: &mut [u8], src: &[u8], opacity: u8) {
+    let opacity16 = _mm256_set1_epi16(opacity as i16);
+    let full = _mm256_set1_epi16(255);
+    let opaque = _mm256_set1_epi32(0xFF000000u32 as i32);
+    let zero = _mm256_setzero_si256();
+
+    let blend = |d: __m256i, s: __m256i| {
+        let alpha = _mm256_subs_epu16(_mm256_shufflehi_epi16::<0xFF>(_mm256_shufflelo_epi16::<0xFF>(d)), opacity16);
+        let inverse = _mm256_sub_epi16(full, alpha);
+        _mm256_srli_epi16::<8>(_mm256_add_epi16(_mm256_mullo_epi16(d, alpha), _mm256_mullo_epi16(s, inverse)))
+    };
+
+    let mut dst_chunks = dst.chunks_exact_mut(32);
+    let mut src_chunks = src.chunks_exact(32);
+    for (d_chunk, s_chunk) in (&mut dst_chunks).zip(&mut src_chunks) {
+        let d = _mm256_loadu_si256(d_chunk.as_ptr() as *const __m256i);
+        let s = _mm256_loadu_si256(s_chunk.as_ptr() as *const __m256i);
+        let lo = blend(_mm256_unpacklo_epi8(d, zero), _mm256_unpacklo_epi8(s, zero));
+        let hi = blend(_mm256_unpackhi_epi8(d, zero), _mm256_unpackhi_epi8(s, zero));
+        _mm256_storeu_si256(d_chunk.as_mut_ptr() as *mut __m256i, _mm256_or_si256(_mm256_packus_epi16(lo, hi), opaque));
+    }
+    blend_alpha_scalar(dst_chunks.into_remainder(), src_chunks.remainder(), opacity);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVELS: [SimdLevel; 3] = [SimdLevel::Scalar, SimdLevel::Sse2, SimdLevel::Avx2];
+
+    #[test]
+    fn tint_matches_color_mul() {
+        // Every byte once per channel, with a few pixels over so the scalar tail is covered too
+        let original: Vec<u8> = (0..256 * 4 + 12).map(|i| (i / 4 % 256) as u8).collect();
+        for level in LEVELS {
+            for tint_value in 0..=255u8 {
+                let color = Color::new(tint_value, 255 - tint_value, tint_value / 2, 0);
+                let mut pixels = original.clone();
+                tint_with(level, &mut pixels, color);
+
+                for (tinted, source) in pixels.chunks_exact(4).zip(original.chunks_exact(4)) {
+                    let expected = Color::new(source[0], source[1], source[2], source[3]) * color;
+                    assert_eq!(tinted, expected.into_chunk(), "{:?} {:?}", level, color);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_level_matches_scalar() {
+        let dst: Vec<u8> = (0..4096 + 28).map(|i| (i * 7 % 256) as u8).collect();
+        let src: Vec<u8> = (0..4096 + 28).map(|i| (i * 13 % 256) as u8).collect();
+        let color = Color::new(200, 150, 100, 50);
+
+        let mut expected_fill = dst.clone();
+        fill_with(SimdLevel::Scalar, &mut expected_fill, color);
+        let mut expected_blend = dst.clone();
+        blend_alpha_with(SimdLevel::Scalar, &mut expected_blend, &src, 30);
+
+        for level in LEVELS {
+            let mut filled = dst.clone();
+            fill_with(level, &mut filled, color);
+            assert_eq!(filled, expected_fill, "{:?}", level);
+
+            let mut blended = dst.clone();
+            blend_alpha_with(level, &mut blended, &src, 30);
+            assert_eq!(blended, expected_blend, "{:?}", level);
+        }
+    }
+}
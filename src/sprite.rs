@@ -0,0 +1,389 @@
+use crate::buffer::Buffer;
+
+/// A single frame inside a SpriteSheet. Positions and sizes are in pixels of the sheet image.
+/// The pivot is relative to the top-left corner of the frame, and is the point placed at the
+/// draw position. It is also the point the frame rotates and scales around in draw_mtx.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpriteFrame {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+    pub pivot_x: f32,
+    pub pivot_y: f32,
+}
+
+impl SpriteFrame {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> SpriteFrame {
+        SpriteFrame { x, y, w, h, pivot_x: 0.0, pivot_y: 0.0 }
+    }
+
+    pub fn new_with_pivot(x: i32, y: i32, w: i32, h: i32, pivot_x: f32, pivot_y: f32) -> SpriteFrame {
+        SpriteFrame { x, y, w, h, pivot_x, pivot_y }
+    }
+}
+
+/// An image split into frames, either from a uniform grid or from a list of rects.
+/// Frames are also kept as their own Buffers so they can be drawn rotated and scaled with pimgmtx.
+#[derive(Clone)]
+pub struct SpriteSheet {
+    pub image: Buffer,
+    pub frames: Vec<SpriteFrame>,
+    pub frame_images: Vec<Buffer>,
+}
+
+impl SpriteSheet {
+
+    /// Splits an image into a grid of equally sized frames, ordered left-to-right, top-to-bottom.
+    ///
+    /// # Arguments
+    /// * 'margin' - Pixels between the edge of the image and the first frame
+    /// * 'spacing' - Pixels between each frame
+    pub fn new_grid(image: Buffer, frame_width: usize, frame_height: usize, margin: usize, spacing: usize) -> SpriteSheet {
+        let mut frames: Vec<SpriteFrame> = Vec::new();
+
+        if frame_width > 0 && frame_height > 0 {
+            let mut y = margin;
+            while y + frame_height <= image.height {
+                let mut x = margin;
+                while x + frame_width <= image.width {
+                    frames.push(SpriteFrame::new(x as i32, y as i32, frame_width as i32, frame_height as i32));
+                    x += frame_width + spacing;
+                }
+                y += frame_height + spacing;
+            }
+        }
+
+        SpriteSheet::new_from_rects(image, frames)
+    }
+
+    /// Uses an explicit list of frames. Useful for packed sheets where frames are different sizes.
+    pub fn new_from_rects(image: Buffer, frames: Vec<SpriteFrame>) -> SpriteSheet {
+        let frame_images: Vec<Buffer> = frames.iter().map(|frame| {
+            let mut frame_image = Buffer::new(frame.w.max(0) as usize, frame.h.max(0) as usize);
+            frame_image.pimgrect(&image, 0, 0, frame.x, frame.y, frame.w, frame.h);
+            frame_image
+        }).collect();
+
+        SpriteSheet { image, frames, frame_images }
+    }
+
+    /// Loads an image from disk and splits it into a grid with no margin or spacing.
+    pub fn new_from_image_grid(path_to: &str, frame_width: usize, frame_height: usize) -> Result<SpriteSheet, String> {
+        let image = Buffer::new_from_image(path_to)?;
+        Ok(SpriteSheet::new_grid(image, frame_width, frame_height, 0, 0))
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Sets the same pivot on every frame, in pixels.
+    pub fn set_pivot(&mut self, pivot_x: f32, pivot_y: f32) {
+        for frame in &mut self.frames {
+            frame.pivot_x = pivot_x;
+            frame.pivot_y = pivot_y;
+        }
+    }
+
+    /// Sets the same pivot on every frame, as a percentage of each frame's size. (0.5, 0.5) is the center.
+    pub fn set_pivot_normalized(&mut self, pivot_x: f32, pivot_y: f32) {
+        for frame in &mut self.frames {
+            frame.pivot_x = frame.w as f32 * pivot_x;
+            frame.pivot_y = frame.h as f32 * pivot_y;
+        }
+    }
+
+    /// Draws a frame with its pivot placed at x and y. Frames outside the sheet are ignored.
+    pub fn draw(&self, buffer: &mut Buffer, frame: usize, x: i32, y: i32) {
+        if let Some(f) = self.frames.get(frame) {
+            buffer.pimgrect(&self.image,
+                x - f.pivot_x as i32, y - f.pivot_y as i32,
+                f.x, f.y, f.w, f.h
+            );
+        }
+    }
+
+    /// Draws a rotated and scaled frame, turning around the frame's pivot. 'scale' is (x, y).
+    pub fn draw_mtx(&self, buffer: &mut Buffer, frame: usize, x: f32, y: f32, rotation: f32, scale: (f32, f32)) {
+        if let (Some(f), Some(image)) = (self.frames.get(frame), self.frame_images.get(frame)) {
+            if f.w <= 0 || f.h <= 0 { return; }
+
+            let offset_x = f.pivot_x / f.w as f32;
+            let offset_y = f.pivot_y / f.h as f32;
+            buffer.pimgmtx(image, x, y, rotation, scale.0, scale.1, offset_x, offset_y);
+        }
+    }
+}
+
+/// How an Animation behaves when it reaches its last frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnimationMode {
+    /// Jump back to the first frame.
+    Loop,
+    /// Play backwards to the first frame, then forwards again.
+    PingPong,
+    /// Stop on the last frame.
+    Once,
+}
+
+/// A step in an Animation. 'frame' indexes into a SpriteSheet, 'duration' is in seconds.
+/// When the frame becomes current its event name is reported by Animation::update.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationFrame {
+    pub frame: usize,
+    pub duration: f32,
+    pub event: Option<String>,
+}
+
+impl AnimationFrame {
+    pub fn new(frame: usize, duration: f32) -> AnimationFrame {
+        AnimationFrame { frame, duration, event: None }
+    }
+}
+
+/// Plays a list of SpriteSheet frames over time.
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub name: String,
+    pub frames: Vec<AnimationFrame>,
+    pub mode: AnimationMode,
+
+    /// Multiplier for time passed into update. 1.0 is normal speed.
+    pub speed: f32,
+    pub is_playing: bool,
+    pub is_finished: bool,
+
+    /// Index into 'frames', not into the SpriteSheet.
+    pub current: usize,
+    pub time: f32,
+
+    direction: i32,
+    is_started: bool,
+}
+
+impl Animation {
+    pub fn new(name: &str, mode: AnimationMode) -> Animation {
+        Animation {
+            name: name.to_string(),
+            frames: Vec::new(),
+            mode,
+
+            speed: 1.0,
+            is_playing: true,
+            is_finished: false,
+
+            current: 0,
+            time: 0.0,
+
+            direction: 1,
+            is_started: false,
+        }
+    }
+
+    /// Makes an animation out of a range of sheet frames that all last the same amount of time.
+    /// The range is inclusive, and plays backwards if 'last' is before 'first'.
+    pub fn new_from_range(name: &str, first: usize, last: usize, duration: f32, mode: AnimationMode) -> Animation {
+        let mut animation = Animation::new(name, mode);
+
+        if first <= last {
+            for frame in first..=last { animation.add_frame(frame, duration); }
+        } else {
+            for frame in (last..=first).rev() { animation.add_frame(frame, duration); }
+        }
+
+        animation
+    }
+
+    pub fn add_frame(&mut self, frame: usize, duration: f32) {
+        self.frames.push(AnimationFrame::new(frame, duration));
+    }
+
+    /// Attaches an event to a step of the animation. Does nothing if the step doesn't exist.
+    pub fn set_event(&mut self, index: usize, event: &str) {
+        if let Some(frame) = self.frames.get_mut(index) {
+            frame.event = Some(event.to_string());
+        }
+    }
+
+    /// Total length of one pass through the animation in seconds.
+    pub fn duration(&self) -> f32 {
+        self.frames.iter().map(|f| f.duration).sum()
+    }
+
+    pub fn play(&mut self) {
+        self.is_playing = true;
+    }
+
+    pub fn pause(&mut self) {
+        self.is_playing = false;
+    }
+
+    /// Rewinds to the first frame and starts playing again.
+    pub fn restart(&mut self) {
+        self.current = 0;
+        self.time = 0.0;
+        self.direction = 1;
+        self.is_playing = true;
+        self.is_finished = false;
+        self.is_started = false;
+    }
+
+    /// The SpriteSheet frame that should be drawn right now.
+    pub fn sheet_frame(&self) -> usize {
+        match self.frames.get(self.current) {
+            Some(f) => f.frame,
+            None => 0,
+        }
+    }
+
+    /// Advances the animation by dt seconds and returns the events of every frame that was entered.
+    /// The first update after starting also reports the event of the first frame.
+    pub fn update(&mut self, dt: f32) -> Vec<String> {
+        let mut events: Vec<String> = Vec::new();
+
+        if !self.is_playing || self.is_finished || self.frames.is_empty() {
+            return events;
+        }
+
+        if !self.is_started {
+            self.is_started = true;
+            self.push_event(&mut events);
+        }
+
+        self.time += dt * self.speed;
+
+        // Frames with no duration would spin forever, so at most one full ping pong cycle, there and back, per update
+        let mut steps_left = self.frames.len() * 2;
+        while self.time >= self.frames[self.current].duration && steps_left > 0 {
+            self.time -= f32::max(self.frames[self.current].duration, 0.0);
+            steps_left -= 1;
+
+            if !self.advance() {
+                self.time = 0.0;
+                break;
+            }
+            self.push_event(&mut events);
+        }
+
+        events
+    }
+
+    pub fn draw(&self, buffer: &mut Buffer, sheet: &SpriteSheet, x: i32, y: i32) {
+        sheet.draw(buffer, self.sheet_frame(), x, y);
+    }
+
+    pub fn draw_mtx(&self, buffer: &mut Buffer, sheet: &SpriteSheet, x: f32, y: f32, rotation: f32, scale: (f32, f32)) {
+        sheet.draw_mtx(buffer, self.sheet_frame(), x, y, rotation, scale);
+    }
+
+    fn push_event(&self, events: &mut Vec<String>) {
+        if let Some(event) = &self.frames[self.current].event {
+            events.push(event.clone());
+        }
+    }
+
+    // Moves to the next frame depending on the mode. Returns false once a Once animation ends.
+    fn advance(&mut self) -> bool {
+        let last = self.frames.len() - 1;
+
+        match self.mode {
+            AnimationMode::Loop => {
+                self.current = if self.current >= last { 0 } else { self.current + 1 };
+            },
+            AnimationMode::Once => {
+                if self.current >= last {
+                    self.is_finished = true;
+                    return false;
+                }
+                self.current += 1;
+            },
+            AnimationMode::PingPong => {
+                if last == 0 { return true; }
+
+                if self.direction > 0 && self.current >= last { self.direction = -1; }
+                if self.direction < 0 && self.current == 0 { self.direction = 1; }
+
+                self.current = (self.current as i32 + self.direction) as usize;
+            },
+        }
+
+        true
+    }
+}
+
+/// Holds a set of named animations and plays one at a time. Handy for characters with idle, run, jump, etc.
+#[derive(Debug, Clone)]
+pub struct AnimationPlayer {
+    pub animations: Vec<Animation>,
+    pub current: usize,
+}
+
+impl AnimationPlayer {
+    pub fn new() -> AnimationPlayer {
+        AnimationPlayer { animations: Vec::new(), current: 0 }
+    }
+
+    pub fn add(&mut self, animation: Animation) {
+        self.animations.push(animation);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Animation> {
+        self.animations.iter().find(|a| a.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Animation> {
+        self.animations.iter_mut().find(|a| a.name == name)
+    }
+
+    pub fn current_animation(&self) -> Option<&Animation> {
+        self.animations.get(self.current)
+    }
+
+    pub fn current_name(&self) -> Option<&str> {
+        self.current_animation().map(|a| a.name.as_str())
+    }
+
+    /// Switches to the named animation and restarts it. Playing the animation that is already playing does nothing,
+    /// so this is safe to call every frame. Returns false if there is no animation with that name.
+    pub fn play(&mut self, name: &str) -> bool {
+        match self.animations.iter().position(|a| a.name == name) {
+            Some(index) => {
+                if index != self.current || self.animations[index].is_finished {
+                    self.current = index;
+                    self.animations[index].restart();
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn update(&mut self, dt: f32) -> Vec<String> {
+        match self.animations.get_mut(self.current) {
+            Some(animation) => animation.update(dt),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn sheet_frame(&self) -> usize {
+        match self.current_animation() {
+            Some(animation) => animation.sheet_frame(),
+            None => 0,
+        }
+    }
+
+    pub fn draw(&self, buffer: &mut Buffer, sheet: &SpriteSheet, x: i32, y: i32) {
+        sheet.draw(buffer, self.sheet_frame(), x, y);
+    }
+
+    pub fn draw_mtx(&self, buffer: &mut Buffer, sheet: &SpriteSheet, x: f32, y: f32, rotation: f32, scale: (f32, f32)) {
+        sheet.draw_mtx(buffer, self.sheet_frame(), x, y, rotation, scale);
+    }
+}
+
+impl Default for AnimationPlayer {
+    fn default() -> Self {
+        AnimationPlayer::new()
+    }
+}
@@ -0,0 +1,228 @@
+use crate::buffer::Buffer;
+use crate::color::Color;
+use crate::partitioned_buffer::PartitionedBuffer;
+use crate::pixel_format::PixelFormat;
+use crate::post_process::{gaussian_kernel, lerp_u8};
+
+/// Which neighbours count as touching when growing an outline.
+/// Four makes rounded, diamond-like corners. Eight makes square corners.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Neighbourhood {
+    Four,
+    Eight,
+}
+
+/// An effect built from an image's alpha. Bake it once with Buffer::baked_effects, or draw it every frame with Buffer::pimg_effects.
+#[derive(Debug, Copy, Clone)]
+pub enum SpriteEffect {
+    /// A solid 'thickness' pixel border around every visible pixel, drawn behind the image.
+    Outline { thickness: usize, color: Color, neighbours: Neighbourhood },
+    /// A blurred copy of the silhouette moved by 'offset_x' and 'offset_y', drawn behind the image. 'sigma' of 0.0 gives a hard shadow.
+    DropShadow { offset_x: i32, offset_y: i32, sigma: f32, color: Color },
+    /// A soft halo outside the silhouette. 'intensity' above 1.0 pushes the halo further out before it fades.
+    OuterGlow { sigma: f32, intensity: f32, color: Color },
+    /// A soft tint along the inside edges of the silhouette. The image keeps its own alpha.
+    InnerGlow { sigma: f32, intensity: f32, color: Color },
+}
+
+impl SpriteEffect {
+    /// How many pixels the effect grows the image by on the left, top, right and bottom.
+    pub fn padding(&self) -> (usize, usize, usize, usize) {
+        match *self {
+            SpriteEffect::Outline { thickness, .. } => (thickness, thickness, thickness, thickness),
+            SpriteEffect::DropShadow { offset_x, offset_y, sigma, .. } => {
+                let r = blur_radius(sigma) as i32;
+                (
+                    (r - offset_x).max(0) as usize,
+                    (r - offset_y).max(0) as usize,
+                    (r + offset_x).max(0) as usize,
+                    (r + offset_y).max(0) as usize,
+                )
+            },
+            SpriteEffect::OuterGlow { sigma, .. } => {
+                let r = blur_radius(sigma);
+                (r, r, r, r)
+            },
+            SpriteEffect::InnerGlow { .. } => (0, 0, 0, 0),
+        }
+    }
+}
+
+/// An image with effects baked in. The original image's top left corner sits at 'origin_x', 'origin_y' inside 'image'.
+#[derive(Clone)]
+pub struct BakedSprite {
+    pub image: Buffer,
+    pub origin_x: i32,
+    pub origin_y: i32,
+}
+
+impl BakedSprite {
+    /// Draws the baked image so the original image's top left corner lands on x, y.
+    pub fn draw(&self, buffer: &mut Buffer, x: i32, y: i32) {
+        buffer.pimg(&self.image, x - self.origin_x, y - self.origin_y);
+    }
+}
+
+impl Buffer {
+    /// Returns a copy of this image with 'effects' applied in order, grown to fit them.
+    /// Later effects see the result of earlier ones, so an Outline followed by a DropShadow shadows the outline too.
+    pub fn baked_effects(&self, effects: &[SpriteEffect]) -> BakedSprite {
+        let mut baked = BakedSprite { image: self.converted(PixelFormat::Rgba8), origin_x: 0, origin_y: 0 };
+        for effect in effects {
+            let (left, top, _, _) = effect.padding();
+            baked.image = apply_effect(&baked.image, effect);
+            baked.origin_x += left as i32;
+            baked.origin_y += top as i32;
+        }
+        baked
+    }
+
+    /// Draws an image with 'effects' applied, keeping the image's top left corner on x, y.
+    /// Bakes every call, so prefer Buffer::baked_effects for images that don't change.
+    pub fn pimg_effects(&mut self, image: &Buffer, x: i32, y: i32, effects: &[SpriteEffect]) {
+        image.baked_effects(effects).draw(self, x, y);
+    }
+}
+
+impl PartitionedBuffer {
+    /// See Buffer::pimg_effects.
+    pub fn pimg_effects(&mut self, image: &Buffer, x: i32, y: i32, effects: &[SpriteEffect]) {
+        let baked = image.baked_effects(effects);
+        self.pimg(&baked.image, x - baked.origin_x, y - baked.origin_y);
+    }
+}
+
+fn apply_effect(image: &Buffer, effect: &SpriteEffect) -> Buffer {
+    let (left, top, right, bottom) = effect.padding();
+    let width = image.width + left + right;
+    let height = image.height + top + bottom;
+
+    // Copy the image into the middle of the grown canvas
+    let mut source = Buffer::new(width, height);
+    for y in 0..image.height {
+        let from = y * image.width * 4;
+        let to = ((y + top) * width + left) * 4;
+        source.color[to..to + image.width * 4].copy_from_slice(&image.color[from..from + image.width * 4]);
+    }
+
+    let alpha: Vec<f32> = source.color.chunks_exact(4).map(|c| c[3] as f32 / 255.0).collect();
+    let mut output = Buffer::new(width, height);
+
+    match *effect {
+        SpriteEffect::Outline { thickness, color, neighbours } => {
+            let mut mask: Vec<bool> = alpha.iter().map(|a| *a > 0.0).collect();
+            for _ in 0..thickness {
+                mask = dilate(&mask, width, height, neighbours);
+            }
+            let layer: Vec<f32> = mask.iter().map(|m| if *m { 1.0 } else { 0.0 }).collect();
+            composite_behind(&source, &mut output, &layer, color);
+        },
+        SpriteEffect::DropShadow { offset_x, offset_y, sigma, color } => {
+            let mut layer = vec![0.0; width * height];
+            for y in 0..height as i32 {
+                for x in 0..width as i32 {
+                    let (sx, sy) = (x - offset_x, y - offset_y);
+                    if sx < 0 || sy < 0 || sx >= width as i32 || sy >= height as i32 { continue; }
+                    layer[(y * width as i32 + x) as usize] = alpha[(sy * width as i32 + sx) as usize];
+                }
+            }
+            blur_plane(&mut layer, width, height, sigma);
+            composite_behind(&source, &mut output, &layer, color);
+        },
+        SpriteEffect::OuterGlow { sigma, intensity, color } => {
+            let mut layer = alpha.clone();
+            blur_plane(&mut layer, width, height, sigma);
+            for a in layer.iter_mut() { *a = (*a * intensity).min(1.0); }
+            composite_behind(&source, &mut output, &layer, color);
+        },
+        SpriteEffect::InnerGlow { sigma, intensity, color } => {
+            // Blurring the alpha pulls it down near the edges, the drop is how close to an edge a pixel is
+            let mut blurred = alpha.clone();
+            blur_plane(&mut blurred, width, height, sigma);
+            for (i, (out, src)) in output.color.chunks_exact_mut(4).zip(source.color.chunks_exact(4)).enumerate() {
+                let strength = ((1.0 - blurred[i]) * intensity).clamp(0.0, 1.0) * (color.a as f32 / 255.0);
+                out[0] = lerp_u8(src[0], color.r, strength);
+                out[1] = lerp_u8(src[1], color.g, strength);
+                out[2] = lerp_u8(src[2], color.b, strength);
+                out[3] = src[3];
+            }
+        },
+    }
+
+    output
+}
+
+// Grows a mask by one pixel
+fn dilate(mask: &[bool], width: usize, height: usize, neighbours: Neighbourhood) -> Vec<bool> {
+    let offsets: &[(i32, i32)] = match neighbours {
+        Neighbourhood::Four => &[(-1, 0), (1, 0), (0, -1), (0, 1)],
+        Neighbourhood::Eight => &[(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (1, -1), (-1, 1), (1, 1)],
+    };
+
+    let mut grown = mask.to_vec();
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            if mask[(y * width as i32 + x) as usize] { continue; }
+            grown[(y * width as i32 + x) as usize] = offsets.iter().any(|(ox, oy)| {
+                let (nx, ny) = (x + ox, y + oy);
+                nx >= 0 && ny >= 0 && nx < width as i32 && ny < height as i32 && mask[(ny * width as i32 + nx) as usize]
+            });
+        }
+    }
+    grown
+}
+
+// Gaussian blur on a single channel. Outside the plane counts as empty so silhouettes fade out at the edges.
+fn blur_plane(plane: &mut [f32], width: usize, height: usize, sigma: f32) {
+    if sigma <= 0.0 || width == 0 || height == 0 { return; }
+
+    let kernel = gaussian_kernel(sigma);
+    let total: f32 = kernel.iter().sum();
+    let radius = (kernel.len() / 2) as i32;
+
+    let mut horizontal = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width as i32 {
+            let mut sum = 0.0;
+            for (i, weight) in kernel.iter().enumerate() {
+                let sx = x + i as i32 - radius;
+                if sx < 0 || sx >= width as i32 { continue; }
+                sum += plane[y * width + sx as usize] * weight;
+            }
+            horizontal[y * width + x as usize] = sum / total;
+        }
+    }
+
+    for y in 0..height as i32 {
+        for x in 0..width {
+            let mut sum = 0.0;
+            for (i, weight) in kernel.iter().enumerate() {
+                let sy = y + i as i32 - radius;
+                if sy < 0 || sy >= height as i32 { continue; }
+                sum += horizontal[sy as usize * width + x] * weight;
+            }
+            plane[y as usize * width + x] = sum / total;
+        }
+    }
+}
+
+// Puts 'source' over a layer of 'color' whose coverage per pixel is 'layer'
+fn composite_behind(source: &Buffer, output: &mut Buffer, layer: &[f32], color: Color) {
+    let color_a = color.a as f32 / 255.0;
+    for (i, (out, src)) in output.color.chunks_exact_mut(4).zip(source.color.chunks_exact(4)).enumerate() {
+        let sa = src[3] as f32 / 255.0;
+        let da = layer[i].clamp(0.0, 1.0) * color_a;
+        let fa = sa + da * (1.0 - sa);
+        if fa <= 0.0 { continue; }
+
+        let mix = |s: u8, d: u8| ((s as f32 * sa + d as f32 * da * (1.0 - sa)) / fa).round().clamp(0.0, 255.0) as u8;
+        out[0] = mix(src[0], color.r);
+        out[1] = mix(src[1], color.g);
+        out[2] = mix(src[2], color.b);
+        out[3] = (fa * 255.0).round() as u8;
+    }
+}
+
+fn blur_radius(sigma: f32) -> usize {
+    if sigma <= 0.0 { 0 } else { (sigma * 3.0).ceil() as usize }
+}
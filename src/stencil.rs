@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use crate::buffer::Buffer;
+
+/// How a pixel's stencil value is compared against StencilState::reference.
+/// The pixel passes when 'reference <comparison> stencil' holds, both masked by StencilState::read_mask.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StencilTest {
+    Always,
+    Never,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+/// What happens to a pixel's stencil value after the test.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    /// Adds one, stopping at 255.
+    Increment,
+    /// Adds one, going back to 0 after 255.
+    IncrementWrap,
+    /// Takes one away, stopping at 0.
+    Decrement,
+    /// Takes one away, going back to 255 after 0.
+    DecrementWrap,
+    Invert,
+}
+
+/// Stencil test and operations used by every primitive drawn into a Buffer.
+/// The default always passes and never changes the stencil, so it costs nothing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StencilState {
+    pub test: StencilTest,
+    pub reference: u8,
+
+    /// Bits of the reference and the stencil value that the test looks at.
+    pub read_mask: u8,
+    /// Bits of the stencil value the operations are allowed to change.
+    pub write_mask: u8,
+
+    /// Operation for pixels that fail the test. Their color is never written.
+    pub fail: StencilOp,
+    /// Operation for pixels that pass the test.
+    pub pass: StencilOp,
+
+    /// When false, pixels that pass only update the stencil and leave the color alone.
+    pub write_color: bool,
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        StencilState {
+            test: StencilTest::Always,
+            reference: 0,
+            read_mask: 0xFF,
+            write_mask: 0xFF,
+            fail: StencilOp::Keep,
+            pass: StencilOp::Keep,
+            write_color: true,
+        }
+    }
+}
+
+impl StencilState {
+    /// Writes 'reference' into the stencil wherever something is drawn. With 'write_color' false the
+    /// shape is only marked, ready to be tested against with StencilState::test.
+    pub fn mark(reference: u8, write_color: bool) -> StencilState {
+        StencilState { reference, pass: StencilOp::Replace, write_color, ..StencilState::default() }
+    }
+
+    /// Only draws where 'reference <test> stencil' holds, leaving the stencil as it is.
+    pub fn test(test: StencilTest, reference: u8) -> StencilState {
+        StencilState { test, reference, ..StencilState::default() }
+    }
+
+    /// True when the state can stop a pixel being drawn or change the stencil.
+    pub fn is_enabled(&self) -> bool {
+        self.test != StencilTest::Always || self.fail != StencilOp::Keep || self.pass != StencilOp::Keep || !self.write_color
+    }
+
+    /// Runs the test for a stencil value.
+    pub fn passes(&self, stencil: u8) -> bool {
+        let reference = self.reference & self.read_mask;
+        let stencil = stencil & self.read_mask;
+
+        match self.test {
+            StencilTest::Always => true,
+            StencilTest::Never => false,
+            StencilTest::Equal => reference == stencil,
+            StencilTest::NotEqual => reference != stencil,
+            StencilTest::Less => reference < stencil,
+            StencilTest::LessEqual => reference <= stencil,
+            StencilTest::Greater => reference > stencil,
+            StencilTest::GreaterEqual => reference >= stencil,
+        }
+    }
+
+    /// Returns the stencil value after running 'op' on it, keeping any bits outside 'write_mask'.
+    pub fn apply(&self, op: StencilOp, stencil: u8) -> u8 {
+        let value = match op {
+            StencilOp::Keep => stencil,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => self.reference,
+            StencilOp::Increment => stencil.saturating_add(1),
+            StencilOp::IncrementWrap => stencil.wrapping_add(1),
+            StencilOp::Decrement => stencil.saturating_sub(1),
+            StencilOp::DecrementWrap => stencil.wrapping_sub(1),
+            StencilOp::Invert => !stencil,
+        };
+
+        (value & self.write_mask) | (stencil & !self.write_mask)
+    }
+}
+
+/// Only lets pixels be drawn where an image's alpha is above 'threshold', like a cut-out held over the screen.
+/// The image's top left corner sits at 'x', 'y' in screen space and anything outside it counts as empty.
+/// With 'invert' the cut-out is flipped, drawing only where the alpha is at or below 'threshold'.
+#[derive(Clone)]
+pub struct BufferMask {
+    pub image: Arc<Buffer>,
+    pub x: i32,
+    pub y: i32,
+    pub threshold: u8,
+    pub invert: bool,
+}
+
+impl BufferMask {
+    pub fn new(image: Buffer, x: i32, y: i32) -> BufferMask {
+        BufferMask { image: Arc::new(image), x, y, threshold: 0, invert: false }
+    }
+
+    /// True if a pixel at this screen position can be drawn.
+    pub fn allows(&self, x: i32, y: i32) -> bool {
+        let (mx, my) = (x - self.x, y - self.y);
+        let inside = mx >= 0 && my >= 0 && mx < self.image.width as i32 && my < self.image.height as i32;
+        let set = inside && self.image.format.read(&self.image.color, self.image.width, mx, my).a > self.threshold;
+
+        set != self.invert
+    }
+}
@@ -0,0 +1,912 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use roxmltree::Node;
+use serde_json::Value;
+
+use crate::buffer::Buffer;
+use crate::color::Color;
+use crate::tilemap::*;
+
+/// Custom properties set on a map, layer, tile or object in Tiled, by name.
+pub type TiledProperties = HashMap<String, TiledProperty>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TiledProperty {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Color(Color),
+    /// Path as written in Tiled, relative to the file it came from.
+    File(String),
+    /// Id of an object in the same map. 0 means no object.
+    Object(u32),
+    Class(TiledProperties),
+}
+
+impl TiledProperty {
+    pub fn as_bool(&self) -> Option<bool> {
+        match self { TiledProperty::Bool(value) => Some(*value), _ => None }
+    }
+
+    /// Ints and floats both convert, so a property doesn't break when its type is changed in the editor.
+    pub fn as_int(&self) -> Option<i64> {
+        match self { TiledProperty::Int(value) => Some(*value), TiledProperty::Float(value) => Some(*value as i64), _ => None }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self { TiledProperty::Float(value) => Some(*value), TiledProperty::Int(value) => Some(*value as f64), _ => None }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self { TiledProperty::String(value) | TiledProperty::File(value) => Some(value), _ => None }
+    }
+
+    pub fn as_color(&self) -> Option<Color> {
+        match self { TiledProperty::Color(value) => Some(*value), _ => None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TiledObjectShape {
+    Rectangle,
+    Ellipse,
+    Point,
+    /// Points are relative to the object's position.
+    Polygon(Vec<(f32, f32)>),
+    /// Points are relative to the object's position.
+    Polyline(Vec<(f32, f32)>),
+    Text(String),
+}
+
+/// Anything placed on an object layer: spawn points, triggers, collision shapes or tile objects.
+/// Positions are in pixels. Tile objects are positioned by their bottom-left corner, everything else by the top-left.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TiledObject {
+    pub id: u32,
+    pub name: String,
+    pub class: String,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub rotation: f32,
+    pub tile: Option<Tile>,
+    pub shape: TiledObjectShape,
+    pub is_visible: bool,
+    pub properties: TiledProperties,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TiledObjectLayer {
+    pub name: String,
+    pub objects: Vec<TiledObject>,
+    pub is_visible: bool,
+    pub offset_x: i32,
+    pub offset_y: i32,
+}
+
+/// A map made in the Tiled editor. Loads orthogonal maps saved as .tmx or .tmj, with inline or external tilesets.
+///
+/// Tile layers become a Tilemap, with group layers flattened into it. Object layers and custom properties are kept
+/// alongside it. Image layers are skipped.
+#[derive(Clone)]
+pub struct TiledMap {
+    pub tilemap: Tilemap,
+    pub object_layers: Vec<TiledObjectLayer>,
+    pub background_color: Option<Color>,
+
+    pub properties: TiledProperties,
+    /// Properties of tile and object layers, by layer name.
+    pub layer_properties: HashMap<String, TiledProperties>,
+    /// Properties of individual tiles, by global tile id.
+    pub tile_properties: HashMap<u32, TiledProperties>,
+}
+
+// A tileset as written in the file, before its images are loaded
+struct TilesetSource {
+    name: String,
+    image: Option<PathBuf>,
+    tile_width: usize,
+    tile_height: usize,
+    margin: usize,
+    spacing: usize,
+    columns: usize,
+    tile_count: usize,
+    tiles: Vec<TileSource>,
+}
+
+struct TileSource {
+    id: u32,
+    image: Option<PathBuf>,
+    animation: TileAnimation,
+    properties: TiledProperties,
+}
+
+// Settings of a layer after the groups it's inside of have been applied
+#[derive(Clone)]
+struct LayerInfo {
+    name: String,
+    is_visible: bool,
+    offset_x: f32,
+    offset_y: f32,
+    parallax_x: f32,
+    parallax_y: f32,
+}
+
+impl LayerInfo {
+    fn root() -> LayerInfo {
+        LayerInfo { name: String::new(), is_visible: true, offset_x: 0.0, offset_y: 0.0, parallax_x: 1.0, parallax_y: 1.0 }
+    }
+
+    fn inside(&self, name: &str, is_visible: bool, offset_x: f32, offset_y: f32, parallax_x: f32, parallax_y: f32) -> LayerInfo {
+        LayerInfo {
+            name: name.to_string(),
+            is_visible: self.is_visible && is_visible,
+            offset_x: self.offset_x + offset_x,
+            offset_y: self.offset_y + offset_y,
+            parallax_x: self.parallax_x * parallax_x,
+            parallax_y: self.parallax_y * parallax_y,
+        }
+    }
+}
+
+// Limits on what a map file can ask for, well past anything Tiled makes but small enough to allocate
+const MAX_LAYER_TILES: usize = 1 << 24;
+const MAX_TILESET_PIXELS: usize = 8192 * 8192;
+
+// Part of a tile layer. Finite maps have one chunk covering the whole layer.
+struct TileChunk {
+    x: i32,
+    y: i32,
+    width: usize,
+    height: usize,
+    tiles: Vec<Tile>,
+}
+
+impl TiledMap {
+    /// Loads a .tmx map, or a .tmj / .json map for any other extension. Tilesets and images are found relative to the map.
+    pub fn new_from_file(path_to: &str) -> Result<TiledMap, String> {
+        let text = match std::fs::read_to_string(path_to) {
+            Ok(text) => text,
+            Err(reason) => { return Err(format!("ERROR - TILED: Could not read map {} | {}", path_to, reason)); }
+        };
+
+        let path = Path::new(path_to);
+        let base_dir = path.parent().unwrap_or(Path::new("")).to_string_lossy().to_string();
+
+        if TiledMap::is_xml(path) {
+            TiledMap::new_from_tmx(&text, &base_dir)
+        } else {
+            TiledMap::new_from_json(&text, &base_dir)
+        }
+    }
+
+    /// Parses a map in Tiled's XML format. 'base_dir' is the folder relative paths in the map start from.
+    pub fn new_from_tmx(text: &str, base_dir: &str) -> Result<TiledMap, String> {
+        let document = match roxmltree::Document::parse(text) {
+            Ok(document) => document,
+            Err(reason) => { return Err(format!("ERROR - TILED: Map is not valid XML | {}", reason)); }
+        };
+
+        let root = document.root_element();
+        if !root.has_tag_name("map") {
+            return Err(format!("ERROR - TILED: Expected a map element but found {}", root.tag_name().name()));
+        }
+        TiledMap::check_orientation(root.attribute("orientation").unwrap_or("orthogonal"))?;
+
+        let base_dir = Path::new(base_dir);
+        let mut map = TiledMap::new(
+            xml_attr(root, "width", 0),
+            xml_attr(root, "height", 0),
+            xml_attr(root, "tilewidth", 0),
+            xml_attr(root, "tileheight", 0),
+        );
+        map.background_color = root.attribute("backgroundcolor").and_then(parse_color);
+        map.properties = xml_properties(root);
+
+        for node in root.children().filter(|n| n.has_tag_name("tileset")) {
+            let first_id: u32 = xml_attr(node, "firstgid", 1);
+            let source = match node.attribute("source") {
+                Some(source) => TiledMap::load_external_tileset(&base_dir.join(source))?,
+                None => xml_tileset(node, base_dir),
+            };
+            map.add_tileset(source, first_id)?;
+        }
+
+        map.add_xml_layers(root, &LayerInfo::root())?;
+        Ok(map)
+    }
+
+    /// Parses a map in Tiled's JSON format. 'base_dir' is the folder relative paths in the map start from.
+    pub fn new_from_json(text: &str, base_dir: &str) -> Result<TiledMap, String> {
+        let root: Value = match serde_json::from_str(text) {
+            Ok(root) => root,
+            Err(reason) => { return Err(format!("ERROR - TILED: Map is not valid JSON | {}", reason)); }
+        };
+
+        if root["type"].as_str().unwrap_or("map") != "map" {
+            return Err(format!("ERROR - TILED: Expected a map but found {}", root["type"]));
+        }
+        TiledMap::check_orientation(root["orientation"].as_str().unwrap_or("orthogonal"))?;
+
+        let base_dir = Path::new(base_dir);
+        let mut map = TiledMap::new(
+            json_usize(&root, "width", 0),
+            json_usize(&root, "height", 0),
+            json_usize(&root, "tilewidth", 0),
+            json_usize(&root, "tileheight", 0),
+        );
+        map.background_color = root["backgroundcolor"].as_str().and_then(parse_color);
+        map.properties = json_properties(&root);
+
+        for node in root["tilesets"].as_array().into_iter().flatten() {
+            let first_id = json_usize(node, "firstgid", 1) as u32;
+            let source = match node["source"].as_str() {
+                Some(source) => TiledMap::load_external_tileset(&base_dir.join(source))?,
+                None => json_tileset(node, base_dir),
+            };
+            map.add_tileset(source, first_id)?;
+        }
+
+        map.add_json_layers(&root, &LayerInfo::root())?;
+        Ok(map)
+    }
+
+    pub fn object_layer(&self, name: &str) -> Option<&TiledObjectLayer> {
+        self.object_layers.iter().find(|l| l.name == name)
+    }
+
+    /// Every object with a class (or 'type' in older versions of Tiled) across all object layers.
+    pub fn objects_of_class(&self, class: &str) -> Vec<&TiledObject> {
+        self.object_layers.iter()
+            .flat_map(|l| l.objects.iter())
+            .filter(|o| o.class == class)
+            .collect()
+    }
+
+    /// Looks up a custom property on a placed tile. Flip flags on the tile are ignored.
+    pub fn tile_property(&self, tile: Tile, name: &str) -> Option<&TiledProperty> {
+        self.tile_properties.get(&tile.id()).and_then(|p| p.get(name))
+    }
+
+    /// Draws the tile objects of every visible object layer. Objects are drawn at the size of their tile, ignoring
+    /// any scaling or rotation done in the editor.
+    pub fn draw_objects(&self, buffer: &mut Buffer, camera_x: i32, camera_y: i32) {
+        for layer in self.object_layers.iter().filter(|l| l.is_visible) {
+            for object in layer.objects.iter().filter(|o| o.is_visible) {
+                let tile = match object.tile {
+                    Some(tile) => tile,
+                    None => { continue; }
+                };
+
+                let tileset = match self.tilemap.tileset_for(tile.id()) {
+                    Some(tileset) => tileset,
+                    None => { continue; }
+                };
+
+                let local_id = tileset.animated_tile(tile.id() - tileset.first_id, self.tilemap.time);
+                let rect = tileset.tile_rect(local_id);
+
+                let px = object.x as i32 + layer.offset_x - camera_x;
+                let py = object.y as i32 - rect.3 + layer.offset_y - camera_y;
+                buffer.pimgrect_flip(&tileset.image, px, py, rect, tile.flip());
+            }
+        }
+    }
+
+    fn new(width: usize, height: usize, tile_width: usize, tile_height: usize) -> TiledMap {
+        TiledMap {
+            tilemap: Tilemap::new(width, height, tile_width, tile_height),
+            object_layers: Vec::new(),
+            background_color: None,
+            properties: HashMap::new(),
+            layer_properties: HashMap::new(),
+            tile_properties: HashMap::new(),
+        }
+    }
+
+    fn is_xml(path: &Path) -> bool {
+        matches!(path.extension().and_then(|e| e.to_str()), Some("tmx") | Some("tsx") | Some("xml"))
+    }
+
+    fn check_orientation(orientation: &str) -> Result<(), String> {
+        if orientation == "orthogonal" { return Ok(()); }
+        Err(format!("ERROR - TILED: {} maps are not supported, only orthogonal", orientation))
+    }
+
+    // .tsx or .tsj, with image paths relative to the tileset file rather than the map
+    fn load_external_tileset(path: &Path) -> Result<TilesetSource, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(reason) => { return Err(format!("ERROR - TILED: Could not read tileset {} | {}", path.display(), reason)); }
+        };
+
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+
+        if TiledMap::is_xml(path) {
+            match roxmltree::Document::parse(&text) {
+                Ok(document) => Ok(xml_tileset(document.root_element(), base_dir)),
+                Err(reason) => Err(format!("ERROR - TILED: Tileset {} is not valid XML | {}", path.display(), reason)),
+            }
+        } else {
+            match serde_json::from_str::<Value>(&text) {
+                Ok(root) => Ok(json_tileset(&root, base_dir)),
+                Err(reason) => Err(format!("ERROR - TILED: Tileset {} is not valid JSON | {}", path.display(), reason)),
+            }
+        }
+    }
+
+    fn add_tileset(&mut self, source: TilesetSource, first_id: u32) -> Result<(), String> {
+        let mut tileset = match &source.image {
+            Some(path) => {
+                let image = Buffer::new_from_image(&path.to_string_lossy())?;
+                let mut tileset = Tileset::new_with_spacing(&source.name, image, source.tile_width, source.tile_height, source.margin, source.spacing);
+
+                // Trust Tiled's counts over what fits, the image may have been resized since the map was saved
+                if source.columns > 0 { tileset.columns = source.columns; }
+                if source.tile_count > 0 { tileset.tile_count = source.tile_count; }
+                tileset
+            },
+            None => TiledMap::collection_tileset(&source)?,
+        };
+
+        if u32::try_from(tileset.tile_count).ok().and_then(|count| first_id.checked_add(count)).is_none() {
+            return Err(format!("ERROR - TILED: Tileset {} has more tile ids than fit after firstgid {}", source.name, first_id));
+        }
+
+        tileset.first_id = first_id;
+        for tile in source.tiles {
+            if !tile.animation.frames.is_empty() {
+                tileset.add_animation(tile.id, tile.animation);
+            }
+            if !tile.properties.is_empty() {
+                let id = match first_id.checked_add(tile.id) {
+                    Some(id) => id,
+                    None => { return Err(format!("ERROR - TILED: Tile {} in tileset {} is past the largest tile id", tile.id, source.name)); }
+                };
+                self.tile_properties.insert(id, tile.properties);
+            }
+        }
+
+        self.tilemap.tilesets.push(tileset);
+        Ok(())
+    }
+
+    // Tilesets made of separate images get packed into a grid, each image sitting in the bottom-left of its cell
+    fn collection_tileset(source: &TilesetSource) -> Result<Tileset, String> {
+        let mut images: Vec<(u32, Buffer)> = Vec::new();
+        for tile in &source.tiles {
+            if let Some(path) = &tile.image {
+                images.push((tile.id, Buffer::new_from_image(&path.to_string_lossy())?));
+            }
+        }
+
+        let cell_width = images.iter().map(|(_, i)| i.width).max().unwrap_or(0).max(source.tile_width).max(1);
+        let cell_height = images.iter().map(|(_, i)| i.height).max().unwrap_or(0).max(source.tile_height).max(1);
+        let tile_count = images.iter().map(|(id, _)| *id as usize + 1).max().unwrap_or(0);
+        let columns = (tile_count as f32).sqrt().ceil().max(1.0) as usize;
+        let rows = tile_count.div_ceil(columns).max(1);
+
+        // Cells are placed by tile id, so a few images with large ids would still need a huge image
+        let pixels = columns.checked_mul(cell_width).zip(rows.checked_mul(cell_height)).and_then(|(w, h)| w.checked_mul(h));
+        if pixels.is_none_or(|pixels| pixels > MAX_TILESET_PIXELS) {
+            return Err(format!("ERROR - TILED: Tileset {} needs a {} x {} grid of {} x {} tiles, which is too large",
+                source.name, columns, rows, cell_width, cell_height));
+        }
+
+        let mut image = Buffer::new(columns * cell_width, rows * cell_height);
+        for (id, tile_image) in &images {
+            let (cx, cy) = (*id as usize % columns, *id as usize / columns);
+            let x = cx * cell_width;
+            let y = (cy + 1) * cell_height - tile_image.height;
+            image.blit(tile_image, x as i32, y as i32);
+        }
+
+        let mut tileset = Tileset::new(&source.name, image, cell_width, cell_height);
+        tileset.tile_count = tile_count;
+        Ok(tileset)
+    }
+
+    fn add_tile_layer(&mut self, info: &LayerInfo, chunks: Vec<TileChunk>) -> Result<(), String> {
+        let too_large = || format!("ERROR - TILED: Layer {} is larger than {} tiles", info.name, MAX_LAYER_TILES);
+        if chunks.iter().any(|c| c.width > MAX_LAYER_TILES || c.height > MAX_LAYER_TILES) {
+            return Err(too_large());
+        }
+
+        // Chunks can sit anywhere an i32 reaches, so the extent is measured in i64
+        let min_x = chunks.iter().map(|c| c.x).min().unwrap_or(0);
+        let min_y = chunks.iter().map(|c| c.y).min().unwrap_or(0);
+        let max_x = chunks.iter().map(|c| c.x as i64 + c.width as i64).max().unwrap_or(0);
+        let max_y = chunks.iter().map(|c| c.y as i64 + c.height as i64).max().unwrap_or(0);
+
+        let (width, height) = ((max_x - min_x as i64) as usize, (max_y - min_y as i64) as usize);
+        if width.checked_mul(height).is_none_or(|tiles| tiles > MAX_LAYER_TILES) {
+            return Err(too_large());
+        }
+
+        let offset_x = min_x.checked_mul(self.tilemap.tile_width as i32);
+        let offset_y = min_y.checked_mul(self.tilemap.tile_height as i32);
+        let (offset_x, offset_y) = match (offset_x, offset_y) {
+            (Some(x), Some(y)) => (x, y),
+            _ => { return Err(format!("ERROR - TILED: Layer {} is too far from the origin", info.name)); }
+        };
+
+        let mut layer = TilemapLayer::new(&info.name, width, height);
+        for chunk in chunks {
+            for (i, tile) in chunk.tiles.iter().enumerate().take(chunk.width * chunk.height) {
+                let x = chunk.x - min_x + (i % chunk.width) as i32;
+                let y = chunk.y - min_y + (i / chunk.width) as i32;
+                layer.set(x, y, *tile);
+            }
+        }
+
+        // Infinite maps can have chunks at negative positions, so the layer is shifted to where its first tile is
+        layer.is_visible = info.is_visible;
+        layer.offset_x = (info.offset_x.round() as i32).saturating_add(offset_x);
+        layer.offset_y = (info.offset_y.round() as i32).saturating_add(offset_y);
+        layer.parallax_x = info.parallax_x;
+        layer.parallax_y = info.parallax_y;
+
+        self.tilemap.layers.push(layer);
+        Ok(())
+    }
+
+    fn add_xml_layers(&mut self, parent: Node, group: &LayerInfo) -> Result<(), String> {
+        for node in parent.children().filter(|n| n.is_element()) {
+            let name = node.attribute("name").unwrap_or("");
+            let info = group.inside(
+                name,
+                xml_attr::<u32>(node, "visible", 1) != 0,
+                xml_attr(node, "offsetx", 0.0),
+                xml_attr(node, "offsety", 0.0),
+                xml_attr(node, "parallaxx", 1.0),
+                xml_attr(node, "parallaxy", 1.0),
+            );
+
+            match node.tag_name().name() {
+                "layer" => {
+                    let chunks = xml_tile_chunks(node, xml_attr(node, "width", 0), xml_attr(node, "height", 0))?;
+                    self.add_tile_layer(&info, chunks)?;
+                    self.layer_properties.insert(name.to_string(), xml_properties(node));
+                },
+                "objectgroup" => {
+                    let objects = node.children().filter(|n| n.has_tag_name("object")).map(xml_object).collect();
+                    self.add_object_layer(&info, objects);
+                    self.layer_properties.insert(name.to_string(), xml_properties(node));
+                },
+                "group" => { self.add_xml_layers(node, &info)?; },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn add_json_layers(&mut self, parent: &Value, group: &LayerInfo) -> Result<(), String> {
+        for node in parent["layers"].as_array().into_iter().flatten() {
+            let name = node["name"].as_str().unwrap_or("");
+            let info = group.inside(
+                name,
+                node["visible"].as_bool().unwrap_or(true),
+                json_f32(node, "offsetx", 0.0),
+                json_f32(node, "offsety", 0.0),
+                json_f32(node, "parallaxx", 1.0),
+                json_f32(node, "parallaxy", 1.0),
+            );
+
+            match node["type"].as_str().unwrap_or("") {
+                "tilelayer" => {
+                    let chunks = json_tile_chunks(node)?;
+                    self.add_tile_layer(&info, chunks)?;
+                    self.layer_properties.insert(name.to_string(), json_properties(node));
+                },
+                "objectgroup" => {
+                    let objects = node["objects"].as_array().into_iter().flatten().map(json_object).collect();
+                    self.add_object_layer(&info, objects);
+                    self.layer_properties.insert(name.to_string(), json_properties(node));
+                },
+                "group" => { self.add_json_layers(node, &info)?; },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn add_object_layer(&mut self, info: &LayerInfo, objects: Vec<TiledObject>) {
+        self.object_layers.push(TiledObjectLayer {
+            name: info.name.clone(),
+            objects,
+            is_visible: info.is_visible,
+            offset_x: info.offset_x.round() as i32,
+            offset_y: info.offset_y.round() as i32,
+        });
+    }
+}
+
+// Decodes the 'data' of a tile layer or chunk. Tile ids are stored as little endian u32s once decoded from base64.
+fn decode_tiles(data: &str, encoding: &str, compression: &str) -> Result<Vec<Tile>, String> {
+    match encoding {
+        "csv" => {
+            let mut tiles: Vec<Tile> = Vec::new();
+            for value in data.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()) {
+                match value.parse::<u32>() {
+                    Ok(id) => tiles.push(Tile(id)),
+                    Err(_) => { return Err(format!("ERROR - TILED: Tile layer has an invalid tile id {}", value)); }
+                }
+            }
+            Ok(tiles)
+        },
+        "base64" => {
+            let bytes = match base64::engine::general_purpose::STANDARD.decode(data.trim()) {
+                Ok(bytes) => bytes,
+                Err(reason) => { return Err(format!("ERROR - TILED: Tile layer data is not valid base64 | {}", reason)); }
+            };
+
+            let mut decompressed: Vec<u8> = Vec::new();
+            let result = match compression {
+                "" => { decompressed = bytes; Ok(0) },
+                "zlib" => flate2::read::ZlibDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed),
+                "gzip" => flate2::read::GzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed),
+                _ => { return Err(format!("ERROR - TILED: {} compressed tile layers are not supported", compression)); }
+            };
+
+            if let Err(reason) = result {
+                return Err(format!("ERROR - TILED: Could not decompress tile layer | {}", reason));
+            }
+
+            Ok(decompressed.chunks_exact(4).map(|b| Tile(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))).collect())
+        },
+        _ => Err(format!("ERROR - TILED: Unknown tile layer encoding {}", encoding)),
+    }
+}
+
+// Accepts both "#RRGGBB" and "#AARRGGBB"
+fn parse_color(text: &str) -> Option<Color> {
+    let hex = text.trim().trim_start_matches('#');
+    let value = u32::from_str_radix(hex, 16).ok()?;
+
+    match hex.len() {
+        6 => Some(Color::new((value >> 16) as u8, (value >> 8) as u8, value as u8, 255)),
+        8 => Some(Color::new((value >> 16) as u8, (value >> 8) as u8, value as u8, (value >> 24) as u8)),
+        _ => None,
+    }
+}
+
+// Polygon points in the XML format look like "0,0 16,0 16,16"
+fn parse_points(text: &str) -> Vec<(f32, f32)> {
+    text.split_whitespace()
+        .filter_map(|p| {
+            let (x, y) = p.split_once(',')?;
+            Some((x.parse().ok()?, y.parse().ok()?))
+        })
+        .collect()
+}
+
+fn property_from_text(kind: &str, value: &str) -> TiledProperty {
+    match kind {
+        "bool" => TiledProperty::Bool(value == "true"),
+        "int" => TiledProperty::Int(value.parse().unwrap_or(0)),
+        "float" => TiledProperty::Float(value.parse().unwrap_or(0.0)),
+        "color" => TiledProperty::Color(parse_color(value).unwrap_or(Color::new(0, 0, 0, 0))),
+        "file" => TiledProperty::File(value.to_string()),
+        "object" => TiledProperty::Object(value.parse().unwrap_or(0)),
+        _ => TiledProperty::String(value.to_string()),
+    }
+}
+
+// XML
+
+fn xml_attr<T: std::str::FromStr>(node: Node, name: &str, default: T) -> T {
+    node.attribute(name).and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn xml_child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn xml_properties(node: Node) -> TiledProperties {
+    let mut properties: TiledProperties = HashMap::new();
+
+    if let Some(list) = xml_child(node, "properties") {
+        for property in list.children().filter(|n| n.has_tag_name("property")) {
+            let name = property.attribute("name").unwrap_or("").to_string();
+            let kind = property.attribute("type").unwrap_or("string");
+
+            let value = match kind {
+                "class" => TiledProperty::Class(xml_properties(property)),
+                // Multi-line strings are stored as text instead of an attribute
+                _ => property_from_text(kind, property.attribute("value").or(property.text()).unwrap_or("")),
+            };
+            properties.insert(name, value);
+        }
+    }
+
+    properties
+}
+
+fn xml_tileset(node: Node, base_dir: &Path) -> TilesetSource {
+    let tiles = node.children().filter(|n| n.has_tag_name("tile")).map(|tile| {
+        let mut animation = TileAnimation::new();
+        if let Some(frames) = xml_child(tile, "animation") {
+            for frame in frames.children().filter(|n| n.has_tag_name("frame")) {
+                animation.add_frame(xml_attr(frame, "tileid", 0), xml_attr::<f32>(frame, "duration", 0.0) / 1000.0);
+            }
+        }
+
+        TileSource {
+            id: xml_attr(tile, "id", 0),
+            image: xml_child(tile, "image").and_then(|i| i.attribute("source")).map(|s| base_dir.join(s)),
+            animation,
+            properties: xml_properties(tile),
+        }
+    }).collect();
+
+    TilesetSource {
+        name: node.attribute("name").unwrap_or("").to_string(),
+        image: xml_child(node, "image").and_then(|i| i.attribute("source")).map(|s| base_dir.join(s)),
+        tile_width: xml_attr(node, "tilewidth", 0),
+        tile_height: xml_attr(node, "tileheight", 0),
+        margin: xml_attr(node, "margin", 0),
+        spacing: xml_attr(node, "spacing", 0),
+        columns: xml_attr(node, "columns", 0),
+        tile_count: xml_attr(node, "tilecount", 0),
+        tiles,
+    }
+}
+
+fn xml_tile_chunks(layer: Node, width: usize, height: usize) -> Result<Vec<TileChunk>, String> {
+    let data = match xml_child(layer, "data") {
+        Some(data) => data,
+        None => { return Ok(Vec::new()); }
+    };
+
+    let encoding = data.attribute("encoding").unwrap_or("");
+    let compression = data.attribute("compression").unwrap_or("");
+
+    // Without an encoding every tile is its own element
+    let read = |node: Node| -> Result<Vec<Tile>, String> {
+        if encoding.is_empty() {
+            Ok(node.children().filter(|n| n.has_tag_name("tile")).map(|t| Tile(xml_attr(t, "gid", 0))).collect())
+        } else {
+            decode_tiles(node.text().unwrap_or(""), encoding, compression)
+        }
+    };
+
+    let chunks: Vec<Node> = data.children().filter(|n| n.has_tag_name("chunk")).collect();
+    if chunks.is_empty() {
+        return Ok(vec![TileChunk { x: 0, y: 0, width, height, tiles: read(data)? }]);
+    }
+
+    let mut result: Vec<TileChunk> = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        result.push(TileChunk {
+            x: xml_attr(chunk, "x", 0),
+            y: xml_attr(chunk, "y", 0),
+            width: xml_attr(chunk, "width", 0),
+            height: xml_attr(chunk, "height", 0),
+            tiles: read(chunk)?,
+        });
+    }
+    Ok(result)
+}
+
+fn xml_object(node: Node) -> TiledObject {
+    let shape = if xml_child(node, "ellipse").is_some() {
+        TiledObjectShape::Ellipse
+    } else if xml_child(node, "point").is_some() {
+        TiledObjectShape::Point
+    } else if let Some(polygon) = xml_child(node, "polygon") {
+        TiledObjectShape::Polygon(parse_points(polygon.attribute("points").unwrap_or("")))
+    } else if let Some(polyline) = xml_child(node, "polyline") {
+        TiledObjectShape::Polyline(parse_points(polyline.attribute("points").unwrap_or("")))
+    } else if let Some(text) = xml_child(node, "text") {
+        TiledObjectShape::Text(text.text().unwrap_or("").to_string())
+    } else {
+        TiledObjectShape::Rectangle
+    };
+
+    TiledObject {
+        id: xml_attr(node, "id", 0),
+        name: node.attribute("name").unwrap_or("").to_string(),
+        class: node.attribute("class").or(node.attribute("type")).unwrap_or("").to_string(),
+        x: xml_attr(node, "x", 0.0),
+        y: xml_attr(node, "y", 0.0),
+        width: xml_attr(node, "width", 0.0),
+        height: xml_attr(node, "height", 0.0),
+        rotation: xml_attr(node, "rotation", 0.0),
+        tile: node.attribute("gid").and_then(|g| g.parse().ok()).map(Tile),
+        shape,
+        is_visible: xml_attr::<u32>(node, "visible", 1) != 0,
+        properties: xml_properties(node),
+    }
+}
+
+// JSON
+
+fn json_usize(node: &Value, name: &str, default: usize) -> usize {
+    node[name].as_u64().map(|v| v as usize).unwrap_or(default)
+}
+
+fn json_f32(node: &Value, name: &str, default: f32) -> f32 {
+    node[name].as_f64().map(|v| v as f32).unwrap_or(default)
+}
+
+fn json_properties(node: &Value) -> TiledProperties {
+    let mut properties: TiledProperties = HashMap::new();
+
+    for property in node["properties"].as_array().into_iter().flatten() {
+        let name = property["name"].as_str().unwrap_or("").to_string();
+        let kind = property["type"].as_str().unwrap_or("string");
+        let value = &property["value"];
+
+        let value = match kind {
+            "bool" => TiledProperty::Bool(value.as_bool().unwrap_or(false)),
+            "int" => TiledProperty::Int(value.as_i64().unwrap_or(0)),
+            "float" => TiledProperty::Float(value.as_f64().unwrap_or(0.0)),
+            "object" => TiledProperty::Object(value.as_u64().unwrap_or(0) as u32),
+            "class" => TiledProperty::Class(json_class_members(value)),
+            _ => property_from_text(kind, value.as_str().unwrap_or("")),
+        };
+        properties.insert(name, value);
+    }
+
+    properties
+}
+
+// Class members are written without their types, so they're guessed from the JSON value
+fn json_class_members(value: &Value) -> TiledProperties {
+    let mut properties: TiledProperties = HashMap::new();
+
+    for (name, member) in value.as_object().into_iter().flatten() {
+        let member = match member {
+            Value::Bool(b) => TiledProperty::Bool(*b),
+            Value::Number(n) if n.is_i64() || n.is_u64() => TiledProperty::Int(n.as_i64().unwrap_or(0)),
+            Value::Number(n) => TiledProperty::Float(n.as_f64().unwrap_or(0.0)),
+            Value::String(s) => TiledProperty::String(s.clone()),
+            Value::Object(_) => TiledProperty::Class(json_class_members(member)),
+            _ => { continue; }
+        };
+        properties.insert(name.clone(), member);
+    }
+
+    properties
+}
+
+fn json_tileset(node: &Value, base_dir: &Path) -> TilesetSource {
+    let tiles = node["tiles"].as_array().into_iter().flatten().map(|tile| {
+        let mut animation = TileAnimation::new();
+        for frame in tile["animation"].as_array().into_iter().flatten() {
+            animation.add_frame(json_usize(frame, "tileid", 0) as u32, json_f32(frame, "duration", 0.0) / 1000.0);
+        }
+
+        TileSource {
+            id: json_usize(tile, "id", 0) as u32,
+            image: tile["image"].as_str().map(|s| base_dir.join(s)),
+            animation,
+            properties: json_properties(tile),
+        }
+    }).collect();
+
+    TilesetSource {
+        name: node["name"].as_str().unwrap_or("").to_string(),
+        image: node["image"].as_str().map(|s| base_dir.join(s)),
+        tile_width: json_usize(node, "tilewidth", 0),
+        tile_height: json_usize(node, "tileheight", 0),
+        margin: json_usize(node, "margin", 0),
+        spacing: json_usize(node, "spacing", 0),
+        columns: json_usize(node, "columns", 0),
+        tile_count: json_usize(node, "tilecount", 0),
+        tiles,
+    }
+}
+
+fn json_tile_data(node: &Value, encoding: &str, compression: &str) -> Result<Vec<Tile>, String> {
+    match &node["data"] {
+        Value::Array(ids) => Ok(ids.iter().map(|id| Tile(id.as_u64().unwrap_or(0) as u32)).collect()),
+        Value::String(data) => decode_tiles(data, encoding, compression),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn json_tile_chunks(layer: &Value) -> Result<Vec<TileChunk>, String> {
+    let encoding = layer["encoding"].as_str().unwrap_or("csv");
+    let compression = layer["compression"].as_str().unwrap_or("");
+
+    let chunks = match layer["chunks"].as_array() {
+        Some(chunks) => chunks,
+        None => {
+            let tiles = json_tile_data(layer, encoding, compression)?;
+            return Ok(vec![TileChunk { x: 0, y: 0, width: json_usize(layer, "width", 0), height: json_usize(layer, "height", 0), tiles }]);
+        }
+    };
+
+    let mut result: Vec<TileChunk> = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        result.push(TileChunk {
+            x: chunk["x"].as_i64().unwrap_or(0) as i32,
+            y: chunk["y"].as_i64().unwrap_or(0) as i32,
+            width: json_usize(chunk, "width", 0),
+            height: json_usize(chunk, "height", 0),
+            tiles: json_tile_data(chunk, encoding, compression)?,
+        });
+    }
+    Ok(result)
+}
+
+fn json_object(node: &Value) -> TiledObject {
+    let points = |value: &Value| -> Vec<(f32, f32)> {
+        value.as_array().into_iter().flatten().map(|p| (json_f32(p, "x", 0.0), json_f32(p, "y", 0.0))).collect()
+    };
+
+    let shape = if node["ellipse"].as_bool().unwrap_or(false) {
+        TiledObjectShape::Ellipse
+    } else if node["point"].as_bool().unwrap_or(false) {
+        TiledObjectShape::Point
+    } else if node["polygon"].is_array() {
+        TiledObjectShape::Polygon(points(&node["polygon"]))
+    } else if node["polyline"].is_array() {
+        TiledObjectShape::Polyline(points(&node["polyline"]))
+    } else if node["text"].is_object() {
+        TiledObjectShape::Text(node["text"]["text"].as_str().unwrap_or("").to_string())
+    } else {
+        TiledObjectShape::Rectangle
+    };
+
+    TiledObject {
+        id: json_usize(node, "id", 0) as u32,
+        name: node["name"].as_str().unwrap_or("").to_string(),
+        class: node["class"].as_str().or(node["type"].as_str()).unwrap_or("").to_string(),
+        x: json_f32(node, "x", 0.0),
+        y: json_f32(node, "y", 0.0),
+        width: json_f32(node, "width", 0.0),
+        height: json_f32(node, "height", 0.0),
+        rotation: json_f32(node, "rotation", 0.0),
+        tile: node["gid"].as_u64().map(|g| Tile(g as u32)),
+        shape,
+        is_visible: node["visible"].as_bool().unwrap_or(true),
+        properties: json_properties(node),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_json(tilesets: &str, layers: &str) -> String {
+        format!(r#"{{"type":"map","width":4,"height":4,"tilewidth":8,"tileheight":8,"tilesets":[{}],"layers":[{}]}}"#, tilesets, layers)
+    }
+
+    #[test]
+    fn reads_chunks_at_negative_positions() {
+        let layer = r#"{"type":"tilelayer","name":"ground","chunks":[
+            {"x":-2,"y":-1,"width":2,"height":1,"data":[1,2]},
+            {"x":1,"y":1,"width":1,"height":1,"data":[3]}]}"#;
+        let map = TiledMap::new_from_json(&map_json("", layer), "").unwrap();
+
+        let layer = &map.tilemap.layers[0];
+        assert_eq!((layer.width, layer.height), (4, 3));
+        assert_eq!((layer.offset_x, layer.offset_y), (-16, -8));
+        assert_eq!(layer.get(3, 2).id(), 3);
+    }
+
+    #[test]
+    fn rejects_layers_spanning_the_whole_i32_range() {
+        let layer = r#"{"type":"tilelayer","name":"ground","chunks":[
+            {"x":-2147483648,"y":0,"width":1,"height":1,"data":[1]},
+            {"x":2147483646,"y":0,"width":1,"height":1,"data":[1]}]}"#;
+        assert!(TiledMap::new_from_json(&map_json("", layer), "").is_err());
+
+        let layer = r#"{"type":"tilelayer","name":"ground","width":100000,"height":100000,"data":[]}"#;
+        assert!(TiledMap::new_from_json(&map_json("", layer), "").is_err());
+    }
+
+    #[test]
+    fn rejects_tile_ids_past_u32() {
+        let tileset = r#"{"firstgid":4294967295,"name":"props","tiles":[
+            {"id":10,"properties":[{"name":"solid","type":"bool","value":true}]}]}"#;
+        assert!(TiledMap::new_from_json(&map_json(tileset, ""), "").is_err());
+    }
+}
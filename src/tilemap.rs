@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+
+use crate::buffer::{Buffer, ImageFlip};
+use crate::partitioned_buffer::PartitionedBuffer;
+use crate::sprite::*;
+
+/// A tile id with flip flags packed into the top three bits, the same layout Tiled uses for its global tile ids.
+/// An id of 0 is an empty tile. Rotations are stored as combinations of the flips.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Tile(pub u32);
+
+impl Tile {
+    pub const EMPTY: Tile = Tile(0);
+
+    pub const FLIP_HORIZONTAL: u32 = 0x8000_0000;
+    pub const FLIP_VERTICAL: u32 = 0x4000_0000;
+    pub const FLIP_DIAGONAL: u32 = 0x2000_0000;
+    pub const ID_MASK: u32 = 0x1FFF_FFFF;
+
+    pub fn new(id: u32) -> Tile {
+        Tile(id & Tile::ID_MASK)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.0 & Tile::ID_MASK
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.id() == 0
+    }
+
+    pub fn flip_h(&self) -> bool { self.0 & Tile::FLIP_HORIZONTAL != 0 }
+    pub fn flip_v(&self) -> bool { self.0 & Tile::FLIP_VERTICAL != 0 }
+    pub fn flip_d(&self) -> bool { self.0 & Tile::FLIP_DIAGONAL != 0 }
+
+    /// The tile's flip flags, ready for Buffer::pimgrect_flip.
+    pub fn flip(&self) -> ImageFlip {
+        ImageFlip { horizontal: self.flip_h(), vertical: self.flip_v(), diagonal: self.flip_d() }
+    }
+
+    /// Copy of the tile mirrored on the x axis.
+    pub fn flipped_h(&self) -> Tile { Tile(self.0 ^ Tile::FLIP_HORIZONTAL) }
+
+    /// Copy of the tile mirrored on the y axis.
+    pub fn flipped_v(&self) -> Tile { Tile(self.0 ^ Tile::FLIP_VERTICAL) }
+
+    /// Copy of the tile turned clockwise by 90 degrees, 'quarter_turns' times.
+    pub fn rotated(&self, quarter_turns: i32) -> Tile {
+        let mut tile = *self;
+        for _ in 0..quarter_turns.rem_euclid(4) {
+            // Clockwise turn: transpose, then mirror horizontally. Flips already on the tile swap axis with the transpose.
+            let (h, v, d) = (tile.flip_h(), tile.flip_v(), tile.flip_d());
+            let mut bits = tile.id();
+            if !d { bits |= Tile::FLIP_DIAGONAL; }
+            if !v { bits |= Tile::FLIP_HORIZONTAL; }
+            if h { bits |= Tile::FLIP_VERTICAL; }
+            tile = Tile(bits);
+        }
+        tile
+    }
+}
+
+/// Frames of an animated tile. Tile ids are local to the tileset, durations are in seconds.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TileAnimation {
+    pub frames: Vec<(u32, f32)>,
+}
+
+impl TileAnimation {
+    pub fn new() -> TileAnimation {
+        TileAnimation { frames: Vec::new() }
+    }
+
+    pub fn add_frame(&mut self, local_id: u32, duration: f32) {
+        self.frames.push((local_id, duration));
+    }
+
+    /// The local tile id showing at a point in time. The animation loops forever.
+    pub fn tile_at(&self, time: f32) -> Option<u32> {
+        let total: f32 = self.frames.iter().map(|f| f.1).sum();
+        if self.frames.is_empty() || total <= 0.0 { return self.frames.first().map(|f| f.0); }
+
+        let mut t = time.rem_euclid(total);
+        for (id, duration) in &self.frames {
+            if t < *duration { return Some(*id); }
+            t -= duration;
+        }
+        self.frames.last().map(|f| f.0)
+    }
+}
+
+/// An image cut into a grid of tiles. Tiles are numbered from 0, left-to-right, top-to-bottom.
+/// 'first_id' is the Tile id of local tile 0 inside a Tilemap, so several tilesets can share one map.
+#[derive(Clone)]
+pub struct Tileset {
+    pub name: String,
+    pub image: Buffer,
+    pub tile_width: usize,
+    pub tile_height: usize,
+    pub margin: usize,
+    pub spacing: usize,
+    pub columns: usize,
+    pub tile_count: usize,
+    pub first_id: u32,
+    pub animations: HashMap<u32, TileAnimation>,
+}
+
+impl Tileset {
+    pub fn new(name: &str, image: Buffer, tile_width: usize, tile_height: usize) -> Tileset {
+        Tileset::new_with_spacing(name, image, tile_width, tile_height, 0, 0)
+    }
+
+    pub fn new_with_spacing(name: &str, image: Buffer, tile_width: usize, tile_height: usize, margin: usize, spacing: usize) -> Tileset {
+        let columns = Tileset::fit(image.width, tile_width, margin, spacing);
+        let rows = Tileset::fit(image.height, tile_height, margin, spacing);
+
+        Tileset {
+            name: name.to_string(),
+            image,
+            tile_width,
+            tile_height,
+            margin,
+            spacing,
+            columns,
+            tile_count: columns * rows,
+            first_id: 0,
+            animations: HashMap::new(),
+        }
+    }
+
+    pub fn new_from_image(name: &str, path_to: &str, tile_width: usize, tile_height: usize) -> Result<Tileset, String> {
+        let image = Buffer::new_from_image(path_to)?;
+        Ok(Tileset::new(name, image, tile_width, tile_height))
+    }
+
+    pub fn add_animation(&mut self, local_id: u32, animation: TileAnimation) {
+        self.animations.insert(local_id, animation);
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        id >= self.first_id && ((id - self.first_id) as usize) < self.tile_count
+    }
+
+    /// Rect of a local tile inside the tileset image, as (x, y, w, h).
+    pub fn tile_rect(&self, local_id: u32) -> (i32, i32, i32, i32) {
+        let columns = self.columns.max(1) as u32;
+        let (cx, cy) = ((local_id % columns) as usize, (local_id / columns) as usize);
+
+        (
+            (self.margin + cx * (self.tile_width + self.spacing)) as i32,
+            (self.margin + cy * (self.tile_height + self.spacing)) as i32,
+            self.tile_width as i32,
+            self.tile_height as i32,
+        )
+    }
+
+    /// Swaps an animated tile for the frame showing at 'time'. Other tiles are returned as they are.
+    pub fn animated_tile(&self, local_id: u32, time: f32) -> u32 {
+        match self.animations.get(&local_id).and_then(|a| a.tile_at(time)) {
+            Some(id) => id,
+            None => local_id,
+        }
+    }
+
+    /// Every tile as a frame of a SpriteSheet, so tiles can be drawn as sprites. Frame indices are local tile ids.
+    pub fn sprite_sheet(&self) -> SpriteSheet {
+        let frames: Vec<SpriteFrame> = (0..self.tile_count as u32).map(|id| {
+            let (x, y, w, h) = self.tile_rect(id);
+            SpriteFrame::new(x, y, w, h)
+        }).collect();
+
+        SpriteSheet::new_from_rects(self.image.clone(), frames)
+    }
+
+    /// An animated tile as a looping Animation over the frames of sprite_sheet.
+    pub fn animation(&self, local_id: u32, name: &str) -> Option<Animation> {
+        let tile_animation = self.animations.get(&local_id)?;
+
+        let mut animation = Animation::new(name, AnimationMode::Loop);
+        for (id, duration) in &tile_animation.frames {
+            animation.add_frame(*id as usize, *duration);
+        }
+        Some(animation)
+    }
+
+    fn fit(size: usize, tile: usize, margin: usize, spacing: usize) -> usize {
+        if tile == 0 || size < margin * 2 + tile { return 0; }
+        (size - margin * 2 + spacing) / (tile + spacing)
+    }
+}
+
+/// A grid of tiles. Layer offsets are in pixels, parallax scales how much the layer follows the camera.
+#[derive(Debug, Clone)]
+pub struct TilemapLayer {
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub tiles: Vec<Tile>,
+    pub is_visible: bool,
+    pub offset_x: i32,
+    pub offset_y: i32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+}
+
+impl TilemapLayer {
+    pub fn new(name: &str, width: usize, height: usize) -> TilemapLayer {
+        TilemapLayer {
+            name: name.to_string(),
+            width,
+            height,
+            tiles: vec![Tile::EMPTY; width * height],
+            is_visible: true,
+            offset_x: 0,
+            offset_y: 0,
+            parallax_x: 1.0,
+            parallax_y: 1.0,
+        }
+    }
+
+    /// Gets a tile, or an empty tile if the position is outside the layer.
+    pub fn get(&self, x: i32, y: i32) -> Tile {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 { return Tile::EMPTY; }
+        self.tiles[y as usize * self.width + x as usize]
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, tile: Tile) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 { return; }
+        self.tiles[y as usize * self.width + x as usize] = tile;
+    }
+
+    pub fn fill(&mut self, tile: Tile) {
+        self.tiles.iter_mut().for_each(|t| *t = tile);
+    }
+}
+
+/// Layers of tiles drawn from one or more tilesets, with animated tiles and camera culling.
+#[derive(Clone)]
+pub struct Tilemap {
+    pub width: usize,
+    pub height: usize,
+    pub tile_width: usize,
+    pub tile_height: usize,
+    pub layers: Vec<TilemapLayer>,
+    pub tilesets: Vec<Tileset>,
+
+    /// Seconds, drives animated tiles. Advanced by update.
+    pub time: f32,
+}
+
+impl Tilemap {
+    pub fn new(width: usize, height: usize, tile_width: usize, tile_height: usize) -> Tilemap {
+        Tilemap {
+            width,
+            height,
+            tile_width,
+            tile_height,
+            layers: Vec::new(),
+            tilesets: Vec::new(),
+            time: 0.0,
+        }
+    }
+
+    /// Adds an empty layer the size of the map on top of the others and returns its index.
+    pub fn add_layer(&mut self, name: &str) -> usize {
+        self.layers.push(TilemapLayer::new(name, self.width, self.height));
+        self.layers.len() - 1
+    }
+
+    /// Adds a tileset. A first_id of 0 is replaced with the next free id, so tiles from the first
+    /// tileset start at 1. Returns the first_id used.
+    pub fn add_tileset(&mut self, tileset: Tileset) -> u32 {
+        let mut tileset = tileset;
+        if tileset.first_id == 0 {
+            tileset.first_id = self.tilesets.iter()
+                .map(|t| t.first_id.saturating_add(u32::try_from(t.tile_count).unwrap_or(u32::MAX)))
+                .max().unwrap_or(1).max(1);
+        }
+
+        let first_id = tileset.first_id;
+        self.tilesets.push(tileset);
+        first_id
+    }
+
+    pub fn layer(&self, name: &str) -> Option<&TilemapLayer> {
+        self.layers.iter().find(|l| l.name == name)
+    }
+
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut TilemapLayer> {
+        self.layers.iter_mut().find(|l| l.name == name)
+    }
+
+    pub fn tileset_for(&self, id: u32) -> Option<&Tileset> {
+        self.tilesets.iter().find(|t| t.contains(id))
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt;
+    }
+
+    /// Converts a position in pixels to the tile it's inside of.
+    pub fn world_to_tile(&self, x: f32, y: f32) -> (i32, i32) {
+        (
+            (x / self.tile_width.max(1) as f32).floor() as i32,
+            (y / self.tile_height.max(1) as f32).floor() as i32,
+        )
+    }
+
+    /// Range of tiles in a layer that can be seen by a camera, as (x0, y0, x1, y1) with the ends exclusive.
+    /// The camera position is the world position of the top-left of the view.
+    pub fn visible_tiles(&self, layer: &TilemapLayer, camera_x: i32, camera_y: i32, view_width: usize, view_height: usize) -> (i32, i32, i32, i32) {
+        let origin = self.layer_origin(layer, camera_x, camera_y, 0, 0);
+        self.visible_tiles_from(layer, origin, view_width, view_height)
+    }
+
+    // 'origin' is the top-left of the view in the layer's pixel space, from layer_origin
+    fn visible_tiles_from(&self, layer: &TilemapLayer, origin: (i32, i32), view_width: usize, view_height: usize) -> (i32, i32, i32, i32) {
+        let (tw, th) = (self.tile_width.max(1) as i32, self.tile_height.max(1) as i32);
+        let (left, top) = origin;
+
+        let x0 = i32::clamp(left.div_euclid(tw), 0, layer.width as i32);
+        let y0 = i32::clamp(top.div_euclid(th), 0, layer.height as i32);
+        let x1 = i32::clamp((left + view_width as i32).div_euclid(tw) + 1, 0, layer.width as i32);
+        let y1 = i32::clamp((top + view_height as i32).div_euclid(th) + 1, 0, layer.height as i32);
+
+        (x0, y0, x1, y1)
+    }
+
+    /// Draws every visible layer in order. The camera position is the world position of the top-left of the buffer.
+    pub fn draw(&self, buffer: &mut Buffer, camera_x: i32, camera_y: i32) {
+        for layer in self.layers.iter().filter(|l| l.is_visible) {
+            self.draw_layer_part(buffer, layer, camera_x, camera_y, 0, 0);
+        }
+    }
+
+    pub fn draw_layer(&self, buffer: &mut Buffer, layer: usize, camera_x: i32, camera_y: i32) {
+        if let Some(layer) = self.layers.get(layer) {
+            self.draw_layer_part(buffer, layer, camera_x, camera_y, 0, 0);
+        }
+    }
+
+    /// Draws every visible layer with each partition culling and filling its own section of the screen in parallel.
+    pub fn draw_partitioned(&self, buffer: &mut PartitionedBuffer, camera_x: i32, camera_y: i32) {
+        buffer.draw_parallel(|part| {
+            let (sx, sy) = (part.offset_x as i32, part.offset_y as i32);
+            for layer in self.layers.iter().filter(|l| l.is_visible) {
+                self.draw_layer_part(part, layer, camera_x, camera_y, sx, sy);
+            }
+        });
+    }
+
+    // 'screen_x' and 'screen_y' are where the buffer sits on screen, for drawing into partitions
+    fn draw_layer_part(&self, buffer: &mut Buffer, layer: &TilemapLayer, camera_x: i32, camera_y: i32, screen_x: i32, screen_y: i32) {
+        let (left, top) = self.layer_origin(layer, camera_x, camera_y, screen_x, screen_y);
+        let (x0, y0, x1, y1) = self.visible_tiles_from(layer, (left, top), buffer.width, buffer.height);
+
+        for ty in y0..y1 {
+            for tx in x0..x1 {
+                let tile = layer.tiles[ty as usize * layer.width + tx as usize];
+                if tile.is_empty() { continue; }
+
+                let tileset = match self.tileset_for(tile.id()) {
+                    Some(tileset) => tileset,
+                    None => { continue; }
+                };
+
+                let local_id = tileset.animated_tile(tile.id() - tileset.first_id, self.time);
+                let rect = tileset.tile_rect(local_id);
+
+                // Tiles bigger than the grid are anchored to the bottom-left of their cell, like in Tiled
+                let px = tx * self.tile_width as i32 - left;
+                let py = (ty + 1) * self.tile_height as i32 - rect.3 - top;
+
+                buffer.pimgrect_flip(&tileset.image, px, py, rect, tile.flip());
+            }
+        }
+    }
+
+    // Top-left of the view in the layer's own pixel space
+    fn layer_origin(&self, layer: &TilemapLayer, camera_x: i32, camera_y: i32, screen_x: i32, screen_y: i32) -> (i32, i32) {
+        (
+            (camera_x as f32 * layer.parallax_x) as i32 - layer.offset_x + screen_x,
+            (camera_y as f32 * layer.parallax_y) as i32 - layer.offset_y + screen_y,
+        )
+    }
+}